@@ -0,0 +1,309 @@
+// Derives `Encode` and `Decode` for NPS messages laid out as a sequence of fields.
+//
+// Fields are read in declaration order, each starting where the last one
+// ended. A field without attributes uses its own `Encode`/`Decode`, which
+// covers the header types, `PrefixedString`, `PrefixedStringWithNull`,
+// `PlainString` (last field only), big-endian integers and byte arrays.
+// These attributes change how a field is laid out:
+//
+//   #[nps(header)]      the message header, its length must match the input
+//                       and no bytes may be left over after the last field.
+//                       The length is filled in when encoding.
+//   #[nps(fixed = 32)]  a `String` null padded to exactly that many bytes,
+//                       the width may be any constant expression
+//   #[nps(prefixed)]    a `Vec<u8>` preceded by its u16 length
+//   #[nps(container)]   a nested message wrapped in a `MessageContainer`
+//                       with an id of 0
+//   #[nps(counted)]     a `Vec` of values preceded by their u16 count
+//   #[nps(rest)]        a `Vec<u8>` running to the end of the message
+//
+// Encoding fails with `PacketError::TooLong` when a length or count doesn't
+// fit in its u16, or a fixed-width string is wider than its field.
+//
+// `Encode` also lists each field's name and size, nested fields are named
+// with dots and list entries with their index, e.g. `personas[0].name`.
+//
+// The generated code refers to `crate::packet`, so it only works inside npsmc.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Field, Fields, Ident, Type};
+
+enum Layout {
+    Plain,
+    Header,
+    Fixed(Expr),
+    Prefixed,
+    Container,
+    Counted,
+    Rest,
+}
+
+struct MessageField {
+    name: Ident,
+    ty: Type,
+    layout: Layout,
+}
+
+fn parse_fields(input: &DeriveInput) -> syn::Result<Vec<MessageField>> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "NPS messages must have named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "NPS messages must be structs",
+            ))
+        }
+    };
+    fields.iter().map(parse_field).collect()
+}
+
+fn parse_field(field: &Field) -> syn::Result<MessageField> {
+    let mut layout = Layout::Plain;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("nps"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("header") {
+                layout = Layout::Header;
+            } else if meta.path.is_ident("fixed") {
+                layout = Layout::Fixed(meta.value()?.parse()?);
+            } else if meta.path.is_ident("prefixed") {
+                layout = Layout::Prefixed;
+            } else if meta.path.is_ident("container") {
+                layout = Layout::Container;
+            } else if meta.path.is_ident("counted") {
+                layout = Layout::Counted;
+            } else if meta.path.is_ident("rest") {
+                layout = Layout::Rest;
+            } else {
+                return Err(meta.error("unknown nps attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(MessageField {
+        name: field.ident.clone().unwrap(),
+        ty: field.ty.clone(),
+        layout,
+    })
+}
+
+#[proc_macro_derive(Decode, attributes(nps))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let fields = match parse_fields(&input) {
+        Ok(fields) => fields,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let mut has_header = false;
+    let reads: Vec<TokenStream2> = fields
+        .iter()
+        .map(|field| {
+            let name = &field.name;
+            let ty = &field.ty;
+            match &field.layout {
+                Layout::Plain => quote! {
+                    let #name: #ty = crate::packet::Decode::from_bytes(bytes.get(offset..).unwrap_or(&[]))?;
+                    offset += crate::packet::Encode::size(&#name);
+                },
+                Layout::Header => {
+                    has_header = true;
+                    quote! {
+                        let #name: #ty = crate::packet::Decode::from_bytes(bytes.get(offset..).unwrap_or(&[]))?;
+                        offset += crate::packet::Encode::size(&#name);
+                        let length = crate::packet::header::MessageHeader::length(&#name);
+                        if length != bytes.len() {
+                            return Err(crate::packet::error::PacketError::BadLength {
+                                length,
+                                available: bytes.len(),
+                            });
+                        }
+                    }
+                }
+                Layout::Fixed(width) => quote! {
+                    let #name = crate::packet::read_fixed_string(bytes, offset, #width)?;
+                    offset += #width;
+                },
+                Layout::Prefixed => quote! {
+                    let #name = crate::packet::PrefixedField::from_bytes(bytes.get(offset..).unwrap_or(&[]))?;
+                    offset += crate::packet::Encode::size(&#name);
+                    let #name = #name.data;
+                },
+                Layout::Container => quote! {
+                    let container =
+                        crate::packet::MessageContainer::from_bytes(bytes.get(offset..).unwrap_or(&[]))?;
+                    let #name: #ty = crate::packet::Decode::from_bytes(container.data())?;
+                    offset += 2 + crate::packet::Encode::size(&#name);
+                },
+                Layout::Counted => quote! {
+                    let count = crate::packet::read_u16(bytes, offset)?;
+                    offset += 2;
+                    let mut #name: #ty = Vec::new();
+                    for _ in 0..count {
+                        let item = crate::packet::Decode::from_bytes(bytes.get(offset..).unwrap_or(&[]))?;
+                        offset += crate::packet::Encode::size(&item);
+                        #name.push(item);
+                    }
+                },
+                Layout::Rest => quote! {
+                    let #name = bytes.get(offset..).unwrap_or(&[]).to_vec();
+                    offset += #name.len();
+                },
+            }
+        })
+        .collect();
+
+    // Only a message with a header knows where it should end
+    let trailing = if has_header {
+        quote! {
+            if offset != bytes.len() {
+                return Err(crate::packet::error::PacketError::TrailingBytes {
+                    count: bytes.len() - offset,
+                });
+            }
+        }
+    } else {
+        quote! { let _ = offset; }
+    };
+
+    let ident = &input.ident;
+    let names = fields.iter().map(|field| &field.name);
+    quote! {
+        impl crate::packet::Decode for #ident {
+            fn from_bytes(
+                bytes: &[u8],
+            ) -> Result<#ident, crate::packet::error::PacketError> {
+                use crate::packet::Decode as _;
+                let mut offset = 0;
+                #(#reads)*
+                #trailing
+                Ok(#ident { #(#names),* })
+            }
+        }
+    }
+    .into()
+}
+
+#[proc_macro_derive(Encode, attributes(nps))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let fields = match parse_fields(&input) {
+        Ok(fields) => fields,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let writes = fields.iter().map(|field| {
+        let name = &field.name;
+        match &field.layout {
+            Layout::Plain => quote! {
+                bytes.extend_from_slice(&crate::packet::Encode::to_bytes(&self.#name)?);
+            },
+            Layout::Header => quote! {
+                let header = crate::packet::header::MessageHeader::with_length(
+                    &self.#name,
+                    crate::packet::Encode::size(self),
+                )?;
+                bytes.extend_from_slice(&crate::packet::Encode::to_bytes(&header)?);
+            },
+            Layout::Fixed(width) => quote! {
+                crate::packet::write_fixed_string(&mut bytes, &self.#name, #width)?;
+            },
+            Layout::Prefixed => quote! {
+                bytes.extend_from_slice(&crate::packet::u16_length(self.#name.len())?.to_be_bytes());
+                bytes.extend_from_slice(&self.#name);
+            },
+            Layout::Container => quote! {
+                let container = crate::packet::MessageContainer::new(
+                    0,
+                    crate::packet::Encode::to_bytes(&self.#name)?,
+                );
+                bytes.extend_from_slice(&crate::packet::Encode::to_bytes(&container)?);
+            },
+            Layout::Counted => quote! {
+                bytes.extend_from_slice(&crate::packet::u16_length(self.#name.len())?.to_be_bytes());
+                for item in &self.#name {
+                    bytes.extend_from_slice(&crate::packet::Encode::to_bytes(item)?);
+                }
+            },
+            Layout::Rest => quote! {
+                bytes.extend_from_slice(&self.#name);
+            },
+        }
+    });
+    let sizes = fields.iter().map(|field| {
+        let name = &field.name;
+        match &field.layout {
+            Layout::Plain | Layout::Header => quote! { crate::packet::Encode::size(&self.#name) },
+            Layout::Fixed(width) => quote! { #width },
+            Layout::Prefixed => quote! { 2 + self.#name.len() },
+            Layout::Container => quote! { 2 + crate::packet::Encode::size(&self.#name) },
+            Layout::Counted => quote! {
+                2 + self.#name.iter().map(crate::packet::Encode::size).sum::<usize>()
+            },
+            Layout::Rest => quote! { self.#name.len() },
+        }
+    });
+    let field_lists = fields.iter().map(|field| {
+        let name = &field.name;
+        let label = name.to_string();
+        match &field.layout {
+            Layout::Plain | Layout::Header => quote! {
+                crate::packet::push_fields(&mut fields, #label, &self.#name);
+            },
+            Layout::Fixed(width) => quote! {
+                fields.push((#label.to_string(), #width));
+            },
+            Layout::Prefixed => quote! {
+                fields.push((#label.to_string(), 2 + self.#name.len()));
+            },
+            Layout::Container => quote! {
+                fields.push((format!("{}.id", #label), 2));
+                crate::packet::push_fields(&mut fields, #label, &self.#name);
+            },
+            Layout::Counted => quote! {
+                fields.push((format!("{}.count", #label), 2));
+                for (index, item) in self.#name.iter().enumerate() {
+                    crate::packet::push_fields(&mut fields, &format!("{}[{}]", #label, index), item);
+                }
+            },
+            Layout::Rest => quote! {
+                fields.push((#label.to_string(), self.#name.len()));
+            },
+        }
+    });
+
+    let ident = &input.ident;
+    quote! {
+        impl crate::packet::Encode for #ident {
+            fn to_bytes(&self) -> Result<Vec<u8>, crate::packet::error::PacketError> {
+                let mut bytes = Vec::new();
+                #(#writes)*
+                Ok(bytes)
+            }
+
+            fn size(&self) -> usize {
+                0 #(+ #sizes)*
+            }
+
+            fn fields(&self) -> Vec<(String, usize)> {
+                let mut fields = Vec::new();
+                #(#field_lists)*
+                fields
+            }
+        }
+    }
+    .into()
+}
@@ -0,0 +1,575 @@
+// Desc: JSON API for operators' scripts and web panels
+//
+// Everything the console can see and do, plus account moderation. Every
+// request needs `Authorization: Bearer <admin.token>`, and the API only runs
+// when a token is configured. The token is read per request, so a reload
+// can change it.
+
+use std::sync::Arc;
+use std::time::{Instant, UNIX_EPOCH};
+
+use axum::extract::{Path, Request, State as AxumState};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::dispatch::Server;
+use crate::log::recent_errors;
+use crate::net::stopped;
+use crate::packet::lobby::{SystemMessage, MAX_SYSTEM_MESSAGE_LENGTH};
+use crate::packet::Encode;
+use crate::state::State;
+use crate::store::account::{Account, AccountStore};
+use crate::store::persona::Persona;
+
+#[derive(Clone)]
+struct AdminState {
+    state: Arc<State>,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+struct ListenerJson {
+    name: String,
+    address: String,
+    listening: bool,
+    accepted: u64,
+    failed: u64,
+    connections: usize,
+}
+
+#[derive(Serialize)]
+struct ConnectionJson {
+    id: u64,
+    listener: String,
+    peer: String,
+    customer_id: Option<u32>,
+    persona_id: Option<u32>,
+    connected_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct SessionJson {
+    customer_id: u32,
+    username: Option<String>,
+    persona_id: Option<u32>,
+    connection_id: u64,
+    expires_in_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct AccountJson {
+    customer_id: u32,
+    username: String,
+    is_banned: bool,
+    is_gagged: bool,
+}
+
+#[derive(Serialize)]
+struct PersonaJson {
+    id: u32,
+    customer_id: u32,
+    name: String,
+    shard_id: u32,
+}
+
+#[derive(Serialize)]
+struct ErrorJson {
+    // Seconds since the epoch
+    time: u64,
+    level: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordRequest {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    message: String,
+}
+
+impl From<Account> for AccountJson {
+    fn from(account: Account) -> AccountJson {
+        AccountJson {
+            customer_id: account.customer_id,
+            username: account.username,
+            is_banned: account.is_banned,
+            is_gagged: account.is_gagged,
+        }
+    }
+}
+
+impl From<Persona> for PersonaJson {
+    fn from(persona: Persona) -> PersonaJson {
+        PersonaJson {
+            id: persona.id,
+            customer_id: persona.customer_id,
+            name: persona.name,
+            shard_id: persona.shard_id,
+        }
+    }
+}
+
+pub(crate) fn router(state: Arc<State>) -> Router {
+    let admin = AdminState {
+        state,
+        started: Instant::now(),
+    };
+    Router::new()
+        .route("/health", get(health))
+        .route("/connections", get(connections))
+        .route("/connections/:id/kick", post(kick))
+        .route("/sessions", get(sessions))
+        .route("/accounts", get(accounts))
+        .route("/accounts/:id", get(account))
+        .route("/accounts/:id/ban", post(ban))
+        .route("/accounts/:id/unban", post(unban))
+        .route("/accounts/:id/gag", post(gag))
+        .route("/accounts/:id/ungag", post(ungag))
+        .route("/accounts/:id/password", post(reset_password))
+        .route("/personas", get(personas))
+        .route("/bans", get(bans))
+        .route("/errors", get(errors))
+        .route("/broadcast", post(broadcast))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+// Serve the admin API until the server shuts down
+pub(crate) async fn serve(
+    listener: TcpListener,
+    state: Arc<State>,
+    mut running: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    if let Ok(address) = listener.local_addr() {
+        info!("Admin API listening on {}", address);
+    }
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(async move { stopped(&mut running).await })
+        .await
+}
+
+async fn authorize(
+    AxumState(admin): AxumState<AdminState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = admin.state.config().admin.token.expose().to_string();
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // An empty token means the API was switched off by a reload
+    if token.is_empty()
+        || presented.len() != token.len()
+        || !openssl::memcmp::eq(presented.as_bytes(), token.as_bytes())
+    {
+        warn!("Admin API refused {} {}", request.method(), request.uri());
+        return failure(StatusCode::UNAUTHORIZED, "Missing or wrong admin token");
+    }
+    next.run(request).await
+}
+
+async fn health(AxumState(admin): AxumState<AdminState>) -> Response {
+    let state = &admin.state;
+    let config = state.config();
+    let live = state.connections.list();
+    let listeners: Vec<ListenerJson> = [
+        (Server::Login, config.listeners.login),
+        (Server::Persona, config.listeners.persona),
+        (Server::Lobby, config.listeners.lobby),
+        (Server::Transaction, config.listeners.transaction),
+    ]
+    .into_iter()
+    .map(|(server, address)| {
+        let health = state.connections.listener_health(server);
+        ListenerJson {
+            name: server.to_string(),
+            address: address.to_string(),
+            listening: health.listening,
+            accepted: health.accepted,
+            failed: health.failed,
+            connections: live.iter().filter(|c| c.server == server).count(),
+        }
+    })
+    .collect();
+    Json(json!({
+        "uptime_seconds": admin.started.elapsed().as_secs(),
+        "listeners": listeners,
+        "sessions": state.sessions.list().len(),
+        "capturing": state.capture.is_enabled(),
+    }))
+    .into_response()
+}
+
+async fn connections(AxumState(admin): AxumState<AdminState>) -> Response {
+    let connections: Vec<ConnectionJson> = admin
+        .state
+        .connections
+        .list()
+        .into_iter()
+        .map(|connection| ConnectionJson {
+            id: connection.id,
+            listener: connection.server.to_string(),
+            peer: connection.peer,
+            customer_id: connection.customer_id,
+            persona_id: connection.persona_id,
+            connected_seconds: connection.connected.elapsed().as_secs(),
+        })
+        .collect();
+    Json(connections).into_response()
+}
+
+async fn kick(AxumState(admin): AxumState<AdminState>, Path(id): Path<u64>) -> Response {
+    if !admin.state.connections.kick(id) {
+        return failure(StatusCode::NOT_FOUND, "No such connection");
+    }
+    info!("Admin API kicked connection {}", id);
+    Json(json!({ "kicked": 1 })).into_response()
+}
+
+// `State::with_accounts`, a failed task is a 500
+async fn with_accounts<T: Send + 'static>(
+    admin: &AdminState,
+    call: impl FnOnce(&AccountStore) -> T + Send + 'static,
+) -> Result<T, Response> {
+    admin.state.with_accounts(call).await.map_err(|e| {
+        error!("Account database task failed: {}", e);
+        failure(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+    })
+}
+
+async fn sessions(AxumState(admin): AxumState<AdminState>) -> Response {
+    let now = Instant::now();
+    let sessions = admin.state.sessions.list();
+    let sessions = with_accounts(&admin, move |accounts| {
+        sessions
+            .into_iter()
+            .map(|session| SessionJson {
+                customer_id: session.customer_id,
+                username: accounts
+                    .get(session.customer_id)
+                    .map(|account| account.username),
+                persona_id: session.persona_id,
+                connection_id: session.connection_id,
+                expires_in_seconds: session.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect::<Vec<SessionJson>>()
+    })
+    .await;
+    match sessions {
+        Ok(sessions) => Json(sessions).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn accounts(AxumState(admin): AxumState<AdminState>) -> Response {
+    match with_accounts(&admin, |accounts| accounts.list()).await {
+        Ok(accounts) => {
+            let accounts: Vec<AccountJson> = accounts.into_iter().map(AccountJson::from).collect();
+            Json(accounts).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+// One account with its personas and whether it is connected
+async fn account(AxumState(admin): AxumState<AdminState>, Path(id): Path<u32>) -> Response {
+    let account = match with_accounts(&admin, move |accounts| accounts.get(id)).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return failure(StatusCode::NOT_FOUND, "No such account"),
+        Err(response) => return response,
+    };
+    let state = &admin.state;
+    let personas: Vec<PersonaJson> = state
+        .personas
+        .for_customer(id)
+        .into_iter()
+        .map(PersonaJson::from)
+        .collect();
+    let connections: Vec<u64> = state
+        .connections
+        .list()
+        .iter()
+        .filter(|connection| connection.customer_id == Some(id))
+        .map(|connection| connection.id)
+        .collect();
+    Json(json!({
+        "account": AccountJson::from(account),
+        "personas": personas,
+        "connections": connections,
+    }))
+    .into_response()
+}
+
+// A banned customer is also thrown off straight away, and their login
+// session goes so it can't be picked up from another connection
+async fn ban(AxumState(admin): AxumState<AdminState>, Path(id): Path<u32>) -> Response {
+    let result = match with_accounts(&admin, move |accounts| accounts.set_banned(id, true)).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let state = &admin.state;
+    match result {
+        Ok(true) => {
+            state.sessions.remove(id);
+            let kicked = state.connections.kick_customer(id);
+            info!(
+                "Admin API banned customer {}, kicking {} connection(s)",
+                id, kicked
+            );
+            Json(json!({ "kicked": kicked })).into_response()
+        }
+        Ok(false) => failure(StatusCode::NOT_FOUND, "No such account"),
+        Err(_) => failure(StatusCode::INTERNAL_SERVER_ERROR, "Database error"),
+    }
+}
+
+async fn unban(AxumState(admin): AxumState<AdminState>, Path(id): Path<u32>) -> Response {
+    let result = with_accounts(&admin, move |accounts| accounts.set_banned(id, false)).await;
+    moderated(result, "unbanned", id)
+}
+
+// The client enforces gags, so they apply from the customer's next login
+async fn gag(AxumState(admin): AxumState<AdminState>, Path(id): Path<u32>) -> Response {
+    let result = with_accounts(&admin, move |accounts| accounts.set_gagged(id, true)).await;
+    moderated(result, "gagged", id)
+}
+
+async fn ungag(AxumState(admin): AxumState<AdminState>, Path(id): Path<u32>) -> Response {
+    let result = with_accounts(&admin, move |accounts| accounts.set_gagged(id, false)).await;
+    moderated(result, "ungagged", id)
+}
+
+async fn reset_password(
+    AxumState(admin): AxumState<AdminState>,
+    Path(id): Path<u32>,
+    Json(request): Json<PasswordRequest>,
+) -> Response {
+    if request.password.is_empty() {
+        return failure(StatusCode::BAD_REQUEST, "The password must not be empty");
+    }
+    // Password hashing blocks as well as the database
+    let result = with_accounts(&admin, move |accounts| {
+        accounts.set_password(id, &request.password)
+    })
+    .await;
+    moderated(result, "reset the password of", id)
+}
+
+fn moderated<E>(
+    result: Result<Result<bool, E>, Response>,
+    action: &str,
+    customer_id: u32,
+) -> Response {
+    match result {
+        Ok(Ok(true)) => {
+            info!("Admin API {} customer {}", action, customer_id);
+            Json(json!({})).into_response()
+        }
+        Ok(Ok(false)) => failure(StatusCode::NOT_FOUND, "No such account"),
+        Ok(Err(_)) => failure(StatusCode::INTERNAL_SERVER_ERROR, "Database error"),
+        Err(response) => response,
+    }
+}
+
+async fn personas(AxumState(admin): AxumState<AdminState>) -> Response {
+    let personas: Vec<PersonaJson> = admin
+        .state
+        .personas
+        .list()
+        .into_iter()
+        .map(PersonaJson::from)
+        .collect();
+    Json(personas).into_response()
+}
+
+// Accounts that are banned or gagged
+async fn bans(AxumState(admin): AxumState<AdminState>) -> Response {
+    match with_accounts(&admin, |accounts| accounts.list()).await {
+        Ok(accounts) => {
+            let accounts: Vec<AccountJson> = accounts
+                .into_iter()
+                .filter(|account| account.is_banned || account.is_gagged)
+                .map(AccountJson::from)
+                .collect();
+            Json(accounts).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+// The most recent warnings and errors, oldest first
+async fn errors() -> Response {
+    let errors: Vec<ErrorJson> = recent_errors()
+        .into_iter()
+        .map(|error| ErrorJson {
+            time: error
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            level: error.level.to_string(),
+            message: error.message,
+        })
+        .collect();
+    Json(errors).into_response()
+}
+
+async fn broadcast(
+    AxumState(admin): AxumState<AdminState>,
+    Json(request): Json<BroadcastRequest>,
+) -> Response {
+    let message = request.message.trim();
+    if message.is_empty() {
+        return failure(StatusCode::BAD_REQUEST, "The message must not be empty");
+    }
+    if message.len() > MAX_SYSTEM_MESSAGE_LENGTH {
+        return failure(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "The message must be at most {} bytes",
+                MAX_SYSTEM_MESSAGE_LENGTH
+            ),
+        );
+    }
+    let frame = match SystemMessage::new(message).to_bytes() {
+        Ok(frame) => frame,
+        Err(e) => {
+            return failure(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to encode the message: {}", e),
+            )
+        }
+    };
+    let sent = admin.state.connections.broadcast(Server::Lobby, &frame);
+    info!(
+        "Admin API broadcast to {} lobby connection(s): {}",
+        sent, message
+    );
+    Json(json!({ "sent": sent })).into_response()
+}
+
+fn failure(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::config::Secret;
+    use crate::dispatch::Connection;
+    use crate::test_client::test_state;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    async fn start(state: Arc<State>) -> std::net::SocketAddr {
+        let mut config = (*state.config()).clone();
+        config.admin.token = Secret::new(TOKEN);
+        state.set_config(config);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(state)).await });
+        address
+    }
+
+    // A bare HTTP/1.1 request, returning the status and the JSON body
+    async fn request(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: admin\r\nAuthorization: Bearer {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let address = start(test_state(None)).await;
+        let (status, body) = request(address, "GET", "/health", "wrong", "").await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error"], "Missing or wrong admin token");
+
+        let (status, body) = request(address, "GET", "/health", TOKEN, "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["listeners"][0]["name"], "login");
+        assert_eq!(body["listeners"][0]["listening"], false);
+    }
+
+    #[tokio::test]
+    async fn operators_moderate_accounts() {
+        let state = test_state(None);
+        let address = start(state.clone()).await;
+        let account = state.accounts.create("racer", "hunter2").unwrap();
+        let id = account.customer_id;
+        let mut connection = Connection::new(3, Server::Lobby, "peer".to_string(), state.clone());
+        connection.customer_id = Some(id);
+        let mut control = state.connections.register(&connection);
+        state.sessions.insert(id, 1, "127.0.0.1:50000", vec![0; 32]);
+
+        let (status, body) =
+            request(address, "POST", &format!("/accounts/{}/ban", id), TOKEN, "").await;
+        assert_eq!((status, body["kicked"].as_u64()), (200, Some(1)));
+        assert_eq!(
+            control.try_recv(),
+            Ok(crate::store::connection::Control::Kick)
+        );
+        assert!(state.sessions.get(id).is_none());
+        let (_, body) = request(address, "GET", "/bans", TOKEN, "").await;
+        assert_eq!(body[0]["username"], "racer");
+        assert_eq!(body[0]["is_banned"], true);
+
+        let (status, _) = request(
+            address,
+            "POST",
+            &format!("/accounts/{}/password", id),
+            TOKEN,
+            r#"{"password": "hunter3"}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(state.accounts.authenticate("racer", "hunter3").is_some());
+
+        let (status, body) =
+            request(address, "POST", "/broadcast", TOKEN, r#"{"message": "hi"}"#).await;
+        assert_eq!((status, body["sent"].as_u64()), (200, Some(1)));
+        // Too long for one frame
+        let long = json!({ "message": "x".repeat(MAX_SYSTEM_MESSAGE_LENGTH + 1) }).to_string();
+        let (status, _) = request(address, "POST", "/broadcast", TOKEN, &long).await;
+        assert_eq!(status, 400);
+        let (status, _) = request(address, "POST", "/accounts/1/gag", TOKEN, "").await;
+        assert_eq!(status, 404);
+    }
+}
@@ -0,0 +1,318 @@
+// Desc: Traffic capture
+//
+// Every frame a client sends or receives can be appended to a capture file,
+// so sessions can be inspected or replayed later. Frames are recorded in the
+// clear, after decryption on the way in and before encryption on the way out.
+// Transaction frames are recorded as the MCOTS body, without the
+// `MCOTS_HEADER_SIZE` (11) byte MCOTS header.
+//
+// The file starts with an 8 byte header:
+//
+//   4 bytes  magic "NPSC"
+//   u16      format version, currently 1
+//   u16      reserved, 0
+//
+// followed by one record per frame:
+//
+//   u64      timestamp, microseconds since the unix epoch
+//   u64      connection id
+//   u8       server: 0 login, 1 persona, 2 lobby, 3 transaction
+//   u8       direction: 0 client to server, 1 server to client in reply,
+//            2 server to client unprompted, e.g. a kick or operator message
+//   u16      peer address length, then the address as UTF-8, e.g. "127.0.0.1:50000"
+//   u32      frame length, then the frame including its header
+//
+// All integers are big endian, like the protocol itself.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dispatch::{Connection, Server};
+use crate::packet::error::PacketError;
+use crate::packet::{read_bytes, read_u16, read_u32, Decode};
+
+pub(crate) const CAPTURE_MAGIC: &[u8; 4] = b"NPSC";
+pub(crate) const CAPTURE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    // Client to server
+    Inbound,
+    // Server to client, in reply to the last client frame
+    Outbound,
+    // Server to client, sent without the client asking. Replay skips these.
+    Unsolicited,
+}
+
+impl Direction {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+            Direction::Unsolicited => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Direction, PacketError> {
+        match code {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            2 => Ok(Direction::Unsolicited),
+            _ => Err(PacketError::InvalidEncoding),
+        }
+    }
+}
+
+pub(crate) fn server_code(server: Server) -> u8 {
+    match server {
+        Server::Login => 0,
+        Server::Persona => 1,
+        Server::Lobby => 2,
+        Server::Transaction => 3,
+    }
+}
+
+fn server_from_code(code: u8) -> Result<Server, PacketError> {
+    match code {
+        0 => Ok(Server::Login),
+        1 => Ok(Server::Persona),
+        2 => Ok(Server::Lobby),
+        3 => Ok(Server::Transaction),
+        _ => Err(PacketError::InvalidEncoding),
+    }
+}
+
+// One frame read back from a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CaptureRecord {
+    pub(crate) timestamp: u64,
+    pub(crate) connection_id: u64,
+    pub(crate) server: Server,
+    pub(crate) direction: Direction,
+    pub(crate) peer: String,
+    pub(crate) frame: Vec<u8>,
+}
+
+// Parse a whole capture file, in the order the frames were recorded
+pub(crate) fn read_capture(bytes: &[u8]) -> Result<Vec<CaptureRecord>, PacketError> {
+    if read_bytes(bytes, 0, 8)? != file_header() {
+        return Err(PacketError::InvalidEncoding);
+    }
+
+    let mut records = Vec::new();
+    let mut offset = 8;
+    while offset < bytes.len() {
+        let timestamp = u64::from_bytes(read_bytes(bytes, offset, 8)?)?;
+        let connection_id = u64::from_bytes(read_bytes(bytes, offset + 8, 8)?)?;
+        let server = server_from_code(read_bytes(bytes, offset + 16, 1)?[0])?;
+        let direction = Direction::from_code(read_bytes(bytes, offset + 17, 1)?[0])?;
+        let peer_length = read_u16(bytes, offset + 18)? as usize;
+        offset += 20;
+        let peer = match String::from_utf8(read_bytes(bytes, offset, peer_length)?.to_vec()) {
+            Ok(peer) => peer,
+            Err(_) => return Err(PacketError::InvalidEncoding),
+        };
+        offset += peer_length;
+        let frame_length = read_u32(bytes, offset)? as usize;
+        let frame = read_bytes(bytes, offset + 4, frame_length)?.to_vec();
+        offset += 4 + frame_length;
+
+        records.push(CaptureRecord {
+            timestamp,
+            connection_id,
+            server,
+            direction,
+            peer,
+            frame,
+        });
+    }
+    Ok(records)
+}
+
+fn file_header() -> Vec<u8> {
+    let mut bytes = CAPTURE_MAGIC.to_vec();
+    bytes.extend_from_slice(&CAPTURE_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes
+}
+
+// Records are handed to a writer thread, so a slow disk never holds up a
+// connection. The thread flushes whenever it runs out of records to write.
+struct Writer {
+    records: Sender<Vec<u8>>,
+    thread: JoinHandle<()>,
+}
+
+// A capture file that can be switched on and off while the server runs
+pub(crate) struct Capture {
+    path: String,
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Capture {
+    // Starts switched off
+    pub(crate) fn new(path: &str) -> Capture {
+        Capture {
+            path: path.to_string(),
+            writer: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.writer.lock().unwrap().is_some()
+    }
+
+    // Appends to an existing capture, as long as it really is one
+    pub(crate) fn enable(&self) -> Result<(), ()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_some() {
+            return Ok(());
+        }
+
+        let mut opened = match OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+        {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!("Failed to open capture file {}: {}", self.path, e);
+                return Err(());
+            }
+        };
+        let mut existing = Vec::new();
+        if let Err(e) = (&opened).take(8).read_to_end(&mut existing) {
+            error!("Failed to read capture file {}: {}", self.path, e);
+            return Err(());
+        }
+        if existing.is_empty() {
+            if let Err(e) = opened.write_all(&file_header()) {
+                error!("Failed to write capture file {}: {}", self.path, e);
+                return Err(());
+            }
+        } else if existing != file_header() {
+            error!("{} is not a capture file, refusing to append", self.path);
+            return Err(());
+        }
+
+        let (records, queued) = mpsc::channel();
+        let path = self.path.clone();
+        let thread = std::thread::spawn(move || write_records(&path, opened, queued));
+        info!("Capturing traffic to {}", self.path);
+        *writer = Some(Writer { records, thread });
+        Ok(())
+    }
+
+    // Waits for every record already queued to reach the file
+    pub(crate) fn disable(&self) {
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(Writer { records, thread }) = writer {
+            drop(records);
+            if thread.join().is_err() {
+                error!("Capture writer for {} panicked", self.path);
+            }
+            info!("Stopped capturing traffic to {}", self.path);
+        }
+    }
+
+    // Returns whether capture is now on
+    pub(crate) fn toggle(&self) -> bool {
+        if self.is_enabled() {
+            self.disable();
+            false
+        } else {
+            self.enable().is_ok()
+        }
+    }
+
+    pub(crate) fn record(&self, connection: &Connection, direction: Direction, frame: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        let records = match writer.as_ref() {
+            Some(writer) => &writer.records,
+            None => return,
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_micros() as u64)
+            .unwrap_or(0);
+        let mut record = Vec::with_capacity(24 + connection.peer.len() + frame.len());
+        record.extend_from_slice(&timestamp.to_be_bytes());
+        record.extend_from_slice(&connection.id.to_be_bytes());
+        record.push(server_code(connection.server));
+        record.push(direction.code());
+        record.extend_from_slice(&(connection.peer.len() as u16).to_be_bytes());
+        record.extend_from_slice(connection.peer.as_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        record.extend_from_slice(frame);
+
+        // The writer only hangs up after a failed write, which it has logged
+        if records.send(record).is_err() {
+            *writer = None;
+        }
+    }
+}
+
+fn write_records(path: &str, file: File, queued: Receiver<Vec<u8>>) {
+    let mut file = BufWriter::new(file);
+    while let Ok(record) = queued.recv() {
+        let mut written = file.write_all(&record);
+        while written.is_ok() {
+            match queued.try_recv() {
+                Ok(record) => written = file.write_all(&record),
+                Err(_) => break,
+            }
+        }
+        // Flush once the queue is empty, so a crash loses as little as possible
+        if let Err(e) = written.and_then(|_| file.flush()) {
+            error!(
+                "Failed to write capture file {}, stopping capture: {}",
+                path, e
+            );
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::test_state;
+
+    #[test]
+    fn records_frames_while_enabled() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let state = test_state(Some(path));
+        let connection = Connection::new(7, Server::Lobby, "1.2.3.4:5".to_string(), state.clone());
+
+        // Nothing is written while capture is off
+        state
+            .capture
+            .record(&connection, Direction::Inbound, &[0xff]);
+        assert!(state.capture.toggle());
+        state
+            .capture
+            .record(&connection, Direction::Outbound, &[0x01, 0x20, 0x00, 0x04]);
+        assert!(!state.capture.toggle());
+
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(&bytes[..8], b"NPSC\x00\x01\x00\x00");
+        let record = &bytes[8..];
+        assert_eq!(&record[8..16], 7u64.to_be_bytes());
+        assert_eq!(&record[16..18], [2, 1]);
+        assert_eq!(&record[18..20], [0, 9]);
+        assert_eq!(&record[20..29], b"1.2.3.4:5");
+        assert_eq!(&record[29..33], [0, 0, 0, 4]);
+        assert_eq!(&record[33..], [0x01, 0x20, 0x00, 0x04]);
+    }
+}
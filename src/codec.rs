@@ -0,0 +1,326 @@
+// Desc: NPS and MCOTS framing
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::crypto::Ciphers;
+use crate::dispatch::Server;
+use crate::packet::mcots::{McotsHeader, MCOTS_HEADER_SIZE};
+use crate::packet::Decode;
+
+// Nothing the client sends legitimately comes close to this
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+// The length always sits in the first 4 bytes, whichever header a frame has
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+// Which header the frames on a connection start with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderKind {
+    // id and length
+    Plain,
+    // id, length, version, reserved and checksum
+    Versioned,
+}
+
+impl HeaderKind {
+    pub(crate) fn for_server(server: Server) -> HeaderKind {
+        match server {
+            Server::Login => HeaderKind::Versioned,
+            _ => HeaderKind::Plain,
+        }
+    }
+
+    pub(crate) fn size(self) -> usize {
+        match self {
+            HeaderKind::Plain => 4,
+            HeaderKind::Versioned => 12,
+        }
+    }
+}
+
+// Splits a byte stream into whole NPS frames, header included.
+// Once a session key is known frame bodies are encrypted, headers always
+// travel in the clear so the framing still works.
+pub(crate) struct NpsCodec {
+    header: HeaderKind,
+    max_frame_size: usize,
+    ciphers: Option<Ciphers>,
+}
+
+impl NpsCodec {
+    pub(crate) fn new(header: HeaderKind) -> NpsCodec {
+        NpsCodec {
+            header,
+            max_frame_size: MAX_FRAME_SIZE,
+            ciphers: None,
+        }
+    }
+
+    pub(crate) fn enable(&mut self, session_key: &[u8]) -> Result<(), ()> {
+        self.ciphers = Some(Ciphers::from_session_key(session_key)?);
+        Ok(())
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+impl Decoder for NpsCodec {
+    type Item = Vec<u8>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, std::io::Error> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        // The length includes the header itself
+        let length = u16::from_be_bytes([src[2], src[3]]) as usize;
+        if length < self.header.size() {
+            return Err(invalid_data(format!(
+                "frame length {} is shorter than its {} byte header",
+                length,
+                self.header.size()
+            )));
+        }
+        if length > self.max_frame_size {
+            return Err(invalid_data(format!(
+                "frame length {} is over the {} byte limit",
+                length, self.max_frame_size
+            )));
+        }
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(length).to_vec();
+        debug!("Loading packet: {}", hex::encode(&frame));
+        if let Some(ciphers) = self.ciphers.as_mut() {
+            let body = ciphers
+                .decrypt_data(&frame[LENGTH_PREFIX_SIZE..])
+                .map_err(|_| invalid_data("failed to decrypt frame".to_string()))?;
+            frame.truncate(LENGTH_PREFIX_SIZE);
+            frame.extend_from_slice(&body);
+            debug!("Decrypted packet: {}", hex::encode(&frame));
+        }
+        Ok(Some(frame))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, std::io::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.has_remaining() => {
+                // The client hung up part way through a frame, nothing to salvage
+                debug!("Dropping {} bytes of a partial frame", src.len());
+                src.clear();
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Vec<u8>> for NpsCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Vec<u8>, dst: &mut BytesMut) -> Result<(), std::io::Error> {
+        // The client would refuse it, and the length wouldn't fit in the header anyway
+        if frame.len() > self.max_frame_size {
+            return Err(invalid_data(format!(
+                "frame length {} is over the {} byte limit",
+                frame.len(),
+                self.max_frame_size
+            )));
+        }
+        match self.ciphers.as_mut() {
+            Some(ciphers) if frame.len() > LENGTH_PREFIX_SIZE => {
+                let body = ciphers
+                    .encrypt_data(&frame[LENGTH_PREFIX_SIZE..])
+                    .map_err(|_| invalid_data("failed to encrypt frame".to_string()))?;
+                dst.reserve(frame.len());
+                dst.put_slice(&frame[..LENGTH_PREFIX_SIZE]);
+                dst.put_slice(&body);
+            }
+            _ => dst.put_slice(&frame),
+        }
+        Ok(())
+    }
+}
+
+// Splits a transaction server byte stream into MCOTS headers and bodies.
+// Whether a body is encrypted depends on its header flags, so bodies come
+// out as sent and outgoing frames go in already built.
+pub(crate) struct McotsCodec;
+
+impl Decoder for McotsCodec {
+    type Item = (McotsHeader, Vec<u8>);
+    type Error = std::io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<(McotsHeader, Vec<u8>)>, std::io::Error> {
+        if src.len() < MCOTS_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let header = McotsHeader::from_bytes(&src[..MCOTS_HEADER_SIZE]).map_err(|e| {
+            invalid_data(format!(
+                "bad MCOTS header: {}: {}",
+                e,
+                hex::encode(&src[..MCOTS_HEADER_SIZE])
+            ))
+        })?;
+        // The length includes the header itself
+        let length = header.length as usize;
+        if length < MCOTS_HEADER_SIZE {
+            return Err(invalid_data(format!(
+                "frame length {} is shorter than its {} byte header",
+                length, MCOTS_HEADER_SIZE
+            )));
+        }
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(length);
+        let body = frame[MCOTS_HEADER_SIZE..].to_vec();
+        debug!("Loading header: {:?}", header);
+        debug!("Loading packet: {}", hex::encode(&body));
+        Ok(Some((header, body)))
+    }
+
+    fn decode_eof(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<(McotsHeader, Vec<u8>)>, std::io::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.has_remaining() => {
+                // The client hung up part way through a frame, nothing to salvage
+                debug!("Dropping {} bytes of a partial frame", src.len());
+                src.clear();
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Vec<u8>> for McotsCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Vec<u8>, dst: &mut BytesMut) -> Result<(), std::io::Error> {
+        dst.put_slice(&frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let mut codec = NpsCodec::new(HeaderKind::Plain);
+        let mut buffer = BytesMut::from(&[0x01, 0x00][..]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&[0x00, 0x06, 0xaa]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&[0xbb]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(vec![0x01, 0x00, 0x00, 0x06, 0xaa, 0xbb])
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn splits_coalesced_frames() {
+        let mut codec = NpsCodec::new(HeaderKind::Plain);
+        let mut buffer =
+            BytesMut::from(&[0x01, 0x00, 0x00, 0x05, 0xaa, 0x02, 0x00, 0x00, 0x04, 0x03][..]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(vec![0x01, 0x00, 0x00, 0x05, 0xaa])
+        );
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(vec![0x02, 0x00, 0x00, 0x04])
+        );
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        assert_eq!(&buffer[..], [0x03]);
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        // Shorter than the header it claims to carry
+        let mut codec = NpsCodec::new(HeaderKind::Plain);
+        assert!(codec
+            .decode(&mut BytesMut::from(&[0x01, 0x00, 0x00, 0x02][..]))
+            .is_err());
+
+        let mut codec = NpsCodec::new(HeaderKind::Versioned);
+        assert!(codec
+            .decode(&mut BytesMut::from(&[0x05, 0x01, 0x00, 0x08][..]))
+            .is_err());
+
+        // Longer than we are willing to buffer
+        let mut codec = NpsCodec::new(HeaderKind::Plain);
+        let length = (MAX_FRAME_SIZE + 1) as u16;
+        let mut buffer = BytesMut::from(&[0x01, 0x00][..]);
+        buffer.extend_from_slice(&length.to_be_bytes());
+        assert!(codec.decode(&mut buffer).is_err());
+
+        // Nor do we send anything that long
+        let mut buffer = BytesMut::new();
+        assert!(codec
+            .encode(vec![0; MAX_FRAME_SIZE + 1], &mut buffer)
+            .is_err());
+        assert!(buffer.is_empty());
+        assert!(codec.encode(vec![0; MAX_FRAME_SIZE], &mut buffer).is_ok());
+    }
+
+    #[tokio::test]
+    async fn encrypts_bodies_only() {
+        let session_key = [0x11; 32];
+        let (server_side, mut client_side) = tokio::io::duplex(64);
+        let mut framed = Framed::new(server_side, NpsCodec::new(HeaderKind::Plain));
+        framed.codec_mut().enable(&session_key).unwrap();
+        assert!(framed.codec().is_encrypted());
+
+        framed
+            .send(vec![0x01, 0x20, 0x00, 0x08, 1, 2, 3, 4])
+            .await
+            .unwrap();
+        let mut received = [0; 8];
+        client_side.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..4], &[0x01, 0x20, 0x00, 0x08]);
+
+        let mut client = Ciphers::from_session_key(&session_key).unwrap();
+        assert_eq!(client.decrypt_data(&received[4..]).unwrap(), [1, 2, 3, 4]);
+
+        // And the other way, split across two writes
+        let mut packet = vec![0x01, 0x00, 0x00, 0x07];
+        packet.extend(client.encrypt_data(&[9, 8, 7]).unwrap());
+        client_side.write_all(&packet[..5]).await.unwrap();
+        client_side.write_all(&packet[5..]).await.unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            [0x01, 0x00, 0x00, 0x07, 9, 8, 7]
+        );
+    }
+}
@@ -0,0 +1,694 @@
+// Desc: Server configuration
+//
+// Settings come from a TOML file (npsmc.toml by default, see
+// npsmc.example.toml), then environment variables, then command line flags,
+// each overriding the last. Secrets have no flag, anyone who can list
+// processes can read a command line. Everything is checked once at startup so a bad
+// setting stops the server before it binds anything. The console's `reload`
+// goes through the same steps, and a bad file leaves the running config alone.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "npsmc.toml";
+
+#[derive(Debug, Parser)]
+#[command(name = "npsmc", about = "Rusty Motors server", version)]
+pub(crate) struct Cli {
+    /// Configuration file, it's fine for the default one not to exist
+    #[arg(long, env = "NPSMC_CONFIG", value_name = "PATH")]
+    pub(crate) config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub(crate) overrides: Overrides,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Check the handlers still answer a captured session the same way
+    ///
+    /// Runs against an empty in-memory database seeded with the logins in
+    /// the capture, the account database is never touched.
+    Replay {
+        /// Capture file to replay
+        capture: PathBuf,
+    },
+}
+
+// Settings that can be changed without editing the file
+#[derive(Debug, Clone, Default, Args)]
+pub(crate) struct Overrides {
+    /// Login server address
+    #[arg(long, env = "LOGIN_ADDR", value_name = "ADDR")]
+    pub(crate) login: Option<SocketAddr>,
+    /// Persona server address
+    #[arg(long, env = "PERSONA_ADDR", value_name = "ADDR")]
+    pub(crate) persona: Option<SocketAddr>,
+    /// Lobby server address
+    #[arg(long, env = "LOBBY_ADDR", value_name = "ADDR")]
+    pub(crate) lobby: Option<SocketAddr>,
+    /// Transaction (MCOTS) server address
+    #[arg(long, env = "TRANSACTION_ADDR", value_name = "ADDR")]
+    pub(crate) transaction: Option<SocketAddr>,
+    /// AuthLogin and shard list address
+    #[arg(long, env = "WEB_ADDR", value_name = "ADDR")]
+    pub(crate) web: Option<SocketAddr>,
+    /// PEM private key used to decrypt session keys
+    #[arg(long, env = "PRIVATE_KEY_PATH", value_name = "PATH")]
+    pub(crate) private_key: Option<PathBuf>,
+    // Passphrase for an encrypted private key
+    #[arg(skip = env_secret("PRIVATE_KEY_PASSPHRASE"))]
+    pub(crate) private_key_passphrase: Option<Secret>,
+    /// Account database
+    #[arg(long, env = "DATABASE_PATH", value_name = "PATH")]
+    pub(crate) database: Option<String>,
+    /// Terminal log level
+    #[arg(long, env = "LOG_LEVEL", value_name = "LEVEL")]
+    pub(crate) log_level: Option<String>,
+    /// Capture file
+    #[arg(long, env = "CAPTURE_PATH", value_name = "PATH")]
+    pub(crate) capture_path: Option<PathBuf>,
+    /// Capture traffic from the start
+    #[arg(long, env = "CAPTURE")]
+    pub(crate) capture: bool,
+    /// Seconds to let connections finish when shutting down
+    #[arg(long, env = "SHUTDOWN_GRACE", value_name = "SECONDS")]
+    pub(crate) shutdown_grace: Option<u64>,
+    /// Run without the operator console, controlled by signals only
+    #[arg(long, env = "HEADLESS")]
+    pub(crate) headless: bool,
+    /// Show the full screen dashboard instead of the console
+    #[arg(long, env = "DASHBOARD")]
+    pub(crate) dashboard: bool,
+    /// Admin API address
+    #[arg(long, env = "ADMIN_ADDR", value_name = "ADDR")]
+    pub(crate) admin: Option<SocketAddr>,
+    // Bearer token for the admin API, the API is off without one
+    #[arg(skip = env_secret("ADMIN_TOKEN"))]
+    pub(crate) admin_token: Option<Secret>,
+    /// Address clients should use to reach every shard
+    #[arg(long, env = "SHARD_HOST", value_name = "HOST")]
+    pub(crate) shard_host: Option<String>,
+    /// Create an account the first time an unknown username logs in
+    #[arg(long, env = "AUTO_REGISTER")]
+    pub(crate) auto_register: bool,
+}
+
+fn env_secret(name: &str) -> Option<Secret> {
+    std::env::var(name).ok().map(Secret::new)
+}
+
+// A setting that must never end up in a log line, `{:?}` doesn't show it
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub(crate) fn new(value: impl Into<String>) -> Secret {
+        Secret(value.into())
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    // No operator console, for running under a service manager
+    pub(crate) headless: bool,
+    // The full screen dashboard in place of the console
+    pub(crate) dashboard: bool,
+    pub(crate) database: String,
+    // Unknown usernames get an account on their first AuthLogin
+    pub(crate) auto_register: bool,
+    pub(crate) listeners: Listeners,
+    pub(crate) keys: Keys,
+    pub(crate) log: LogConfig,
+    pub(crate) capture: CaptureConfig,
+    pub(crate) shutdown: ShutdownConfig,
+    pub(crate) console: ConsoleConfig,
+    pub(crate) admin: AdminConfig,
+    pub(crate) shards: Vec<ShardConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Listeners {
+    pub(crate) login: SocketAddr,
+    pub(crate) persona: SocketAddr,
+    pub(crate) lobby: SocketAddr,
+    pub(crate) transaction: SocketAddr,
+    pub(crate) web: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Keys {
+    pub(crate) private_key: PathBuf,
+    // Only needed when the private key is encrypted
+    pub(crate) passphrase: Option<Secret>,
+    // Handed to the client by the web server
+    pub(crate) public_key: PathBuf,
+    pub(crate) certificate: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    // What reaches the terminal
+    pub(crate) level: String,
+    pub(crate) file: PathBuf,
+    pub(crate) file_level: String,
+    pub(crate) debug_file: PathBuf,
+    pub(crate) debug_level: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CaptureConfig {
+    pub(crate) path: PathBuf,
+    // Otherwise wait for the console
+    pub(crate) enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ShutdownConfig {
+    // Seconds connections get to finish what they are doing once told to go
+    pub(crate) grace_period: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ConsoleConfig {
+    // Commands typed at the console, kept between runs. Empty keeps none.
+    pub(crate) history: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdminConfig {
+    pub(crate) listen: SocketAddr,
+    // Sent as `Authorization: Bearer <token>`. Empty turns the API off.
+    pub(crate) token: Secret,
+}
+
+impl AdminConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        !self.token.expose().is_empty()
+    }
+}
+
+impl ShutdownConfig {
+    pub(crate) fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+// A game world listed by the web server. Every shard points at our own
+// login and lobby listeners.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ShardConfig {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: String,
+    #[serde(default = "default_shard_host")]
+    pub(crate) host: String,
+}
+
+fn default_shard_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            headless: false,
+            dashboard: false,
+            database: "npsmc.db".to_string(),
+            auto_register: false,
+            listeners: Listeners::default(),
+            keys: Keys::default(),
+            log: LogConfig::default(),
+            capture: CaptureConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            console: ConsoleConfig::default(),
+            admin: AdminConfig::default(),
+            shards: vec![ShardConfig {
+                id: 44,
+                name: "Rusty Motors".to_string(),
+                description: "Rusty Motors".to_string(),
+                host: default_shard_host(),
+            }],
+        }
+    }
+}
+
+impl Default for Listeners {
+    fn default() -> Listeners {
+        Listeners {
+            login: SocketAddr::from(([0, 0, 0, 0], 8226)),
+            persona: SocketAddr::from(([0, 0, 0, 0], 8228)),
+            lobby: SocketAddr::from(([0, 0, 0, 0], 7003)),
+            transaction: SocketAddr::from(([0, 0, 0, 0], 43300)),
+            web: SocketAddr::from(([0, 0, 0, 0], 3000)),
+        }
+    }
+}
+
+impl Default for Keys {
+    fn default() -> Keys {
+        Keys {
+            private_key: PathBuf::from("data/private_key.pem"),
+            passphrase: None,
+            public_key: PathBuf::from("data/pub.key"),
+            certificate: PathBuf::from("data/mcouniverse.crt"),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            file: PathBuf::from("server.log"),
+            file_level: "info".to_string(),
+            debug_file: PathBuf::from("debug.log"),
+            debug_level: "debug".to_string(),
+        }
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> CaptureConfig {
+        CaptureConfig {
+            path: PathBuf::from("npsmc.npscap"),
+            enabled: false,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig { grace_period: 10 }
+    }
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> ConsoleConfig {
+        ConsoleConfig {
+            history: PathBuf::from(".npsmc_history"),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> AdminConfig {
+        AdminConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3001)),
+            token: Secret::default(),
+        }
+    }
+}
+
+// Anything shorter is too easy to guess
+const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ConfigError {
+    Read { path: PathBuf, error: String },
+    Parse { path: PathBuf, error: String },
+    // Every problem found, so they can all be fixed in one go
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "Failed to read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "Failed to parse {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level).map_err(|_| {
+        format!(
+            "unknown log level {:?}, expected off, error, warn, info, debug or trace",
+            level
+        )
+    })
+}
+
+// Two listeners can't share a port unless they are on different addresses
+fn clashes(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+impl Config {
+    // Read the file, apply the overrides and check the result
+    pub(crate) fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_path_buf(),
+            error: e.to_string(),
+        })?;
+        Config::parse(&text).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| e.to_string().trim_end().to_string())
+    }
+
+    pub(crate) fn apply(&mut self, overrides: &Overrides) {
+        let listeners = &mut self.listeners;
+        for (value, field) in [
+            (overrides.login, &mut listeners.login),
+            (overrides.persona, &mut listeners.persona),
+            (overrides.lobby, &mut listeners.lobby),
+            (overrides.transaction, &mut listeners.transaction),
+            (overrides.web, &mut listeners.web),
+        ] {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        if let Some(private_key) = &overrides.private_key {
+            self.keys.private_key = private_key.clone();
+        }
+        if let Some(passphrase) = &overrides.private_key_passphrase {
+            self.keys.passphrase = Some(passphrase.clone());
+        }
+        if let Some(database) = &overrides.database {
+            self.database = database.clone();
+        }
+        if let Some(level) = &overrides.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(path) = &overrides.capture_path {
+            self.capture.path = path.clone();
+        }
+        if overrides.capture {
+            self.capture.enabled = true;
+        }
+        if overrides.headless {
+            self.headless = true;
+        }
+        if overrides.dashboard {
+            self.dashboard = true;
+        }
+        if let Some(grace_period) = overrides.shutdown_grace {
+            self.shutdown.grace_period = grace_period;
+        }
+        if let Some(address) = overrides.admin {
+            self.admin.listen = address;
+        }
+        if let Some(token) = &overrides.admin_token {
+            self.admin.token = token.clone();
+        }
+        if let Some(host) = &overrides.shard_host {
+            for shard in &mut self.shards {
+                shard.host = host.clone();
+            }
+        }
+        if overrides.auto_register {
+            self.auto_register = true;
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let mut listeners = vec![
+            ("listeners.login", self.listeners.login),
+            ("listeners.persona", self.listeners.persona),
+            ("listeners.lobby", self.listeners.lobby),
+            ("listeners.transaction", self.listeners.transaction),
+            ("listeners.web", self.listeners.web),
+        ];
+        if self.admin.enabled() {
+            listeners.push(("admin.listen", self.admin.listen));
+        }
+        for (i, (name, address)) in listeners.iter().enumerate() {
+            for (other, other_address) in &listeners[..i] {
+                if clashes(address, other_address) {
+                    problems.push(format!(
+                        "{} and {} both use port {}",
+                        other,
+                        name,
+                        address.port()
+                    ));
+                }
+            }
+        }
+
+        for (name, path) in [
+            ("keys.private_key", &self.keys.private_key),
+            ("keys.public_key", &self.keys.public_key),
+            ("keys.certificate", &self.keys.certificate),
+        ] {
+            if !path.is_file() {
+                problems.push(format!("{} {} is not a file", name, path.display()));
+            }
+        }
+
+        for (name, level) in [
+            ("log.level", &self.log.level),
+            ("log.file_level", &self.log.file_level),
+            ("log.debug_level", &self.log.debug_level),
+        ] {
+            if let Err(e) = parse_level(level) {
+                problems.push(format!("{}: {}", name, e));
+            }
+        }
+
+        if self.admin.enabled() && self.admin.token.expose().len() < MIN_ADMIN_TOKEN_LENGTH {
+            problems.push(format!(
+                "admin.token must be at least {} characters",
+                MIN_ADMIN_TOKEN_LENGTH
+            ));
+        }
+
+        if self.database.is_empty() {
+            problems.push("database must not be empty".to_string());
+        }
+
+        if self.shards.is_empty() {
+            problems.push("at least one [[shards]] entry is needed".to_string());
+        }
+        let mut ids = HashSet::new();
+        for shard in &self.shards {
+            if !ids.insert(shard.id) {
+                problems.push(format!("shard id {} is used more than once", shard.id));
+            }
+            if shard.name.is_empty() {
+                problems.push(format!("shard {} needs a name", shard.id));
+            }
+            // The client writes these into an ini file, a newline would break it
+            if shard.name.contains(['\n', '[', ']']) || shard.description.contains('\n') {
+                problems.push(format!(
+                    "shard {} name or description has characters the client can't read",
+                    shard.id
+                ));
+            }
+            if shard.host.is_empty() {
+                problems.push(format!("shard {} needs a host", shard.id));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    // Settings that differ from `self` but only take effect on a restart.
+    // Keys, log level, shards, the grace period, auto registration and a new
+    // admin token are picked up by a reload.
+    pub(crate) fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let mut settings = Vec::new();
+        if self.headless != new.headless || self.dashboard != new.dashboard {
+            settings.push("headless and dashboard");
+        }
+        if self.database != new.database {
+            settings.push("database");
+        }
+        if self.listeners != new.listeners {
+            settings.push("listeners");
+        }
+        if self.log.file != new.log.file
+            || self.log.file_level != new.log.file_level
+            || self.log.debug_file != new.log.debug_file
+            || self.log.debug_level != new.log.debug_level
+        {
+            settings.push("log files");
+        }
+        if self.capture != new.capture {
+            settings.push("capture");
+        }
+        if self.console != new.console {
+            settings.push("console");
+        }
+        if self.admin.listen != new.admin.listen || self.admin.enabled() != new.admin.enabled() {
+            settings.push("admin");
+        }
+        settings
+    }
+
+    // Personas are created on the first shard listed
+    pub(crate) fn home_shard(&self) -> u32 {
+        self.shards.first().map(|shard| shard.id).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_matches_the_defaults() {
+        let example = Config::from_file(Path::new("npsmc.example.toml")).unwrap();
+        assert_eq!(example, Config::default());
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn file_and_flags_override_defaults() {
+        let mut config = Config::parse(
+            r#"
+            database = "/var/lib/npsmc/accounts.db"
+
+            [listeners]
+            login = "127.0.0.1:9226"
+
+            [[shards]]
+            id = 1
+            name = "East"
+
+            [[shards]]
+            id = 2
+            name = "West"
+            host = "10.0.0.2"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listeners.login, "127.0.0.1:9226".parse().unwrap());
+        assert_eq!(config.listeners.lobby, Listeners::default().lobby);
+        assert_eq!(config.log, LogConfig::default());
+        assert_eq!(config.shards[0].host, "127.0.0.1");
+        assert_eq!(config.home_shard(), 1);
+
+        let cli = Cli::try_parse_from([
+            "npsmc",
+            "--lobby",
+            "127.0.0.1:9003",
+            "--log-level",
+            "debug",
+            "--shard-host",
+            "example.com",
+            "--auto-register",
+        ])
+        .unwrap();
+        config.apply(&cli.overrides);
+        assert_eq!(config.listeners.login, "127.0.0.1:9226".parse().unwrap());
+        assert_eq!(config.listeners.lobby, "127.0.0.1:9003".parse().unwrap());
+        assert_eq!(config.log.level, "debug");
+        assert!(config.auto_register);
+        assert!(config
+            .shards
+            .iter()
+            .all(|shard| shard.host == "example.com"));
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn secrets_stay_out_of_flags_and_logs() {
+        assert!(Cli::try_parse_from(["npsmc", "--admin-token", "0123456789abcdef"]).is_err());
+        assert!(Cli::try_parse_from(["npsmc", "--private-key-passphrase", "hunter2"]).is_err());
+
+        let mut config = Config::default();
+        config.admin.token = Secret::new("0123456789abcdef");
+        config.keys.passphrase = Some(Secret::new("hunter2"));
+        let printed = format!("{:?}", config);
+        assert!(!printed.contains("0123456789abcdef"));
+        assert!(!printed.contains("hunter2"));
+    }
+
+    #[test]
+    fn problems_are_reported_together() {
+        assert!(Config::parse("[listeners]\nlogin = \"8226\"\n").is_err());
+        assert!(Config::parse("databse = \"typo.db\"\n").is_err());
+
+        let mut config = Config::default();
+        config.listeners.lobby = config.listeners.login;
+        config.keys.private_key = PathBuf::from("data/missing.pem");
+        config.log.level = "loud".to_string();
+        config.shards.push(config.shards[0].clone());
+        config.admin.listen = config.listeners.web;
+        config.admin.token = Secret::new("secret");
+        let problems = match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("Expected problems, got {:?}", other),
+        };
+        assert_eq!(
+            problems,
+            [
+                "listeners.login and listeners.lobby both use port 8226",
+                "listeners.web and admin.listen both use port 3000",
+                "keys.private_key data/missing.pem is not a file",
+                "log.level: unknown log level \"loud\", expected off, error, warn, info, debug or trace",
+                "admin.token must be at least 16 characters",
+                "shard id 44 is used more than once",
+            ]
+        );
+    }
+}
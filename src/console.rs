@@ -0,0 +1,651 @@
+// Desc: Operator console on the server's own terminal
+//
+// Only used when the server runs in the foreground. Commands are read a line
+// at a time, with history and tab completion, on a thread of their own since
+// reading the terminal blocks. Everything they do goes through the live state,
+// so the listeners see it straight away.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log_crate::LevelFilter;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor};
+use tokio::sync::watch;
+
+use crate::config::{parse_level, Config, Overrides};
+use crate::dispatch::Server;
+use crate::log::{set_terminal_level, terminal_level};
+use crate::packet::lobby::{SystemMessage, MAX_SYSTEM_MESSAGE_LENGTH};
+use crate::packet::Encode;
+use crate::state::State;
+
+// Name, arguments and what it does, in the order `help` lists them
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("help", "", "Print this help"),
+    (
+        "connections",
+        "[listener]",
+        "List open connections, optionally only one listener's",
+    ),
+    (
+        "session",
+        "<customer id>",
+        "Show a customer's session, persona and connections",
+    ),
+    (
+        "kick",
+        "<connection id>",
+        "Log a connection off and hang up",
+    ),
+    (
+        "broadcast",
+        "<message>",
+        "Send a message to everyone in the lobby",
+    ),
+    ("log", "[level]", "Show or change the terminal log level"),
+    ("capture", "[on|off]", "Start or stop capturing traffic"),
+    ("reload", "", "Read the config file again"),
+    ("quit", "", "Shut the server down"),
+];
+
+const LISTENERS: &[&str] = &["login", "persona", "lobby", "transaction"];
+const LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+fn listener(name: &str) -> Option<Server> {
+    match name {
+        "login" => Some(Server::Login),
+        "persona" => Some(Server::Persona),
+        "lobby" => Some(Server::Lobby),
+        "transaction" => Some(Server::Transaction),
+        _ => None,
+    }
+}
+
+// How long ago, to the second
+fn since(instant: Instant) -> Duration {
+    Duration::from_secs(instant.elapsed().as_secs())
+}
+
+fn optional(id: Option<u32>) -> String {
+    match id {
+        Some(id) => id.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Output(String),
+    Quit,
+}
+
+pub(crate) struct Console {
+    state: Arc<State>,
+    shutdown: Arc<watch::Sender<bool>>,
+    // Where the running config came from, `reload` reads it the same way
+    config_path: Option<PathBuf>,
+    overrides: Overrides,
+}
+
+impl Console {
+    pub(crate) fn new(
+        state: Arc<State>,
+        shutdown: Arc<watch::Sender<bool>>,
+        config_path: Option<PathBuf>,
+        overrides: Overrides,
+    ) -> Console {
+        Console {
+            state,
+            shutdown,
+            config_path,
+            overrides,
+        }
+    }
+
+    fn run(self) {
+        let mut editor = match Editor::<Helper, DefaultHistory>::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                error!("Failed to start the console, use signals instead: {}", e);
+                return;
+            }
+        };
+        editor.set_helper(Some(Helper {
+            state: self.state.clone(),
+        }));
+        let history = self.state.config().console.history.clone();
+        if !history.as_os_str().is_empty() {
+            // Not there on the first run
+            let _ = editor.load_history(&history);
+        }
+
+        println!("Type help for a list of commands");
+        loop {
+            let line = match editor.readline("npsmc> ") {
+                Ok(line) => line,
+                // Raw mode swallows Ctrl-C before it becomes SIGINT
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                    self.quit();
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to read the console, use signals instead: {}", e);
+                    break;
+                }
+            };
+            // A signal may have asked us to stop while we were waiting
+            if !*self.shutdown.borrow() {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let _ = editor.add_history_entry(line.as_str());
+            if !history.as_os_str().is_empty() {
+                if let Err(e) = editor.save_history(&history) {
+                    warn!(
+                        "Failed to save console history {}: {}",
+                        history.display(),
+                        e
+                    );
+                }
+            }
+
+            match self.execute(&line) {
+                Reply::Output(output) => {
+                    if !output.is_empty() {
+                        println!("{}", output);
+                    }
+                }
+                Reply::Quit => {
+                    self.quit();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn quit(&self) {
+        println!("Quitting");
+        self.shutdown.send_replace(false);
+    }
+
+    fn execute(&self, line: &str) -> Reply {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let output = match command {
+            "help" | "?" => help(),
+            "connections" => self.connections(argument),
+            "session" => self.session(argument),
+            "kick" => self.kick(argument),
+            "broadcast" => self.broadcast(argument),
+            "log" => log_level(argument),
+            "capture" => self.capture(argument),
+            "reload" => self.reload(),
+            "quit" | "exit" | "x" => return Reply::Quit,
+            _ => format!("Unknown command {}, try help", command),
+        };
+        Reply::Output(output)
+    }
+
+    fn connections(&self, argument: &str) -> String {
+        let server = match argument {
+            "" => None,
+            name => match listener(name) {
+                Some(server) => Some(server),
+                None => return format!("No listener called {}", name),
+            },
+        };
+        let connections: Vec<_> = self
+            .state
+            .connections
+            .list()
+            .into_iter()
+            .filter(|connection| server.is_none_or(|server| connection.server == server))
+            .collect();
+        if connections.is_empty() {
+            return "No connections".to_string();
+        }
+
+        let mut lines = vec![format!(
+            "{:>6}  {:<11}  {:<21}  {:>8}  {:>8}  {:>8}",
+            "id", "listener", "peer", "customer", "persona", "open for"
+        )];
+        for connection in connections {
+            lines.push(format!(
+                "{:>6}  {:<11}  {:<21}  {:>8}  {:>8}  {:>8}",
+                connection.id,
+                connection.server.to_string(),
+                connection.peer,
+                optional(connection.customer_id),
+                optional(connection.persona_id),
+                format!("{:?}", since(connection.connected)),
+            ));
+        }
+        lines.join("\n")
+    }
+
+    fn session(&self, argument: &str) -> String {
+        let customer_id: u32 = match argument.parse() {
+            Ok(customer_id) => customer_id,
+            Err(_) => return "Usage: session <customer id>".to_string(),
+        };
+
+        let mut lines = Vec::new();
+        match self.state.accounts.get(customer_id) {
+            Some(account) => lines.push(format!(
+                "Customer {} is {}{}{}",
+                customer_id,
+                account.username,
+                if account.is_banned { ", banned" } else { "" },
+                if account.is_gagged { ", gagged" } else { "" },
+            )),
+            None => lines.push(format!("Customer {} has no account", customer_id)),
+        }
+        match self.state.sessions.get(customer_id) {
+            Some(session) => {
+                let persona = match session.persona_id {
+                    Some(persona_id) => match self.state.personas.get(customer_id, persona_id) {
+                        Some(persona) => format!("persona {} ({})", persona_id, persona.name),
+                        None => format!("persona {}", persona_id),
+                    },
+                    None => "no persona selected".to_string(),
+                };
+                lines.push(format!(
+                    "Logged in on connection {}, {}, expires in {:?}",
+                    session.connection_id,
+                    persona,
+                    Duration::from_secs(
+                        session
+                            .expires
+                            .saturating_duration_since(Instant::now())
+                            .as_secs()
+                    ),
+                ));
+            }
+            None => lines.push("No session".to_string()),
+        }
+        for connection in self.state.connections.list() {
+            if connection.customer_id == Some(customer_id) {
+                lines.push(format!(
+                    "Connection {} to {} from {}, open for {:?}",
+                    connection.id,
+                    connection.server,
+                    connection.peer,
+                    since(connection.connected)
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn kick(&self, argument: &str) -> String {
+        let id: u64 = match argument.parse() {
+            Ok(id) => id,
+            Err(_) => return "Usage: kick <connection id>".to_string(),
+        };
+        if self.state.connections.kick(id) {
+            format!("Kicking connection {}", id)
+        } else {
+            format!("No connection {}", id)
+        }
+    }
+
+    fn broadcast(&self, message: &str) -> String {
+        if message.is_empty() {
+            return "Usage: broadcast <message>".to_string();
+        }
+        if message.len() > MAX_SYSTEM_MESSAGE_LENGTH {
+            return format!(
+                "The message is {} bytes, the most one frame holds is {}",
+                message.len(),
+                MAX_SYSTEM_MESSAGE_LENGTH
+            );
+        }
+        let frame = match SystemMessage::new(message).to_bytes() {
+            Ok(frame) => frame,
+            Err(e) => return format!("Failed to encode the message: {}", e),
+        };
+        let sent = self.state.connections.broadcast(Server::Lobby, &frame);
+        info!("Broadcast to {} lobby connection(s): {}", sent, message);
+        format!("Sent to {} lobby connection(s)", sent)
+    }
+
+    fn capture(&self, argument: &str) -> String {
+        let capture = &self.state.capture;
+        let enabled = match argument {
+            "" => capture.toggle(),
+            "on" => capture.enable().is_ok(),
+            "off" => {
+                capture.disable();
+                false
+            }
+            _ => return "Usage: capture [on|off]".to_string(),
+        };
+        if enabled {
+            format!("Capturing traffic to {}", capture.path())
+        } else {
+            "Not capturing traffic".to_string()
+        }
+    }
+
+    // Only swaps the config in once everything in it has been checked
+    fn reload(&self) -> String {
+        let mut config = match Config::load(self.config_path.as_deref(), &self.overrides) {
+            Ok(config) => config,
+            Err(e) => return format!("Keeping the running config\n{}", e),
+        };
+        let running = self.state.config();
+
+        if config.keys != running.keys && self.state.keys.reload(&config.keys).is_err() {
+            return "Keeping the running config, the new private key didn't load".to_string();
+        }
+        // Leave a level set with `log` alone unless the file changed it
+        if config.log.level != running.log.level {
+            if let Ok(level) = parse_level(&config.log.level) {
+                set_terminal_level(level);
+            }
+        }
+
+        let mut output = "Reloaded the config".to_string();
+        let restart = running.restart_needed(&config);
+        if !restart.is_empty() {
+            output.push_str(&format!(
+                ", changes to {} take effect on a restart",
+                restart.join(", ")
+            ));
+        }
+        info!("{}", output);
+        // The sockets stay where they were bound, so keep handing those out
+        config.listeners = running.listeners.clone();
+        config.admin.listen = running.admin.listen;
+        self.state.set_config(config);
+        output
+    }
+}
+
+fn help() -> String {
+    let mut lines = vec!["Commands:".to_string()];
+    for (name, arguments, description) in COMMANDS {
+        lines.push(format!(
+            "  {:<26} {}",
+            format!("{} {}", name, arguments),
+            description
+        ));
+    }
+    lines.join("\n")
+}
+
+fn log_level(argument: &str) -> String {
+    if argument.is_empty() {
+        return format!("Terminal log level is {}", terminal_level());
+    }
+    match parse_level(argument) {
+        Ok(level) => {
+            set_terminal_level(level);
+            if level == LevelFilter::Off {
+                "Terminal logging off".to_string()
+            } else {
+                format!("Terminal log level is now {}", level)
+            }
+        }
+        Err(e) => e,
+    }
+}
+
+// Completes command names, then whatever the command takes
+struct Helper {
+    state: Arc<State>,
+}
+
+impl Helper {
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let (start, word) = match line.rsplit_once(char::is_whitespace) {
+            Some((_, word)) => (line.len() - word.len(), word),
+            None => (0, line),
+        };
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let options: Vec<String> = match words.as_slice() {
+            [] => COMMANDS
+                .iter()
+                .map(|(name, _, _)| name.to_string())
+                .collect(),
+            ["connections"] => LISTENERS.iter().map(|name| name.to_string()).collect(),
+            ["log"] => LEVELS.iter().map(|name| name.to_string()).collect(),
+            ["capture"] => vec!["on".to_string(), "off".to_string()],
+            ["kick"] => self
+                .state
+                .connections
+                .list()
+                .iter()
+                .map(|connection| connection.id.to_string())
+                .collect(),
+            ["session"] => {
+                let mut customers: Vec<u32> = self
+                    .state
+                    .connections
+                    .list()
+                    .iter()
+                    .filter_map(|connection| connection.customer_id)
+                    .collect();
+                customers.sort_unstable();
+                customers.dedup();
+                customers.iter().map(u32::to_string).collect()
+            }
+            _ => Vec::new(),
+        };
+        let matches = options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .collect();
+        (start, matches)
+    }
+}
+
+impl Completer for Helper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(&line[..pos]))
+    }
+}
+
+impl Hinter for Helper {
+    type Hint = String;
+}
+
+impl Highlighter for Helper {}
+
+impl Validator for Helper {}
+
+impl rustyline::Helper for Helper {}
+
+// Puts the terminal back how we found it. Reading a line switches it to raw
+// mode, and a signal can stop the server while a line is being read.
+pub(crate) struct Terminal {
+    #[cfg(unix)]
+    saved: Option<nix::sys::termios::Termios>,
+}
+
+impl Terminal {
+    fn save() -> Terminal {
+        Terminal {
+            #[cfg(unix)]
+            saved: nix::sys::termios::tcgetattr(std::io::stdin()).ok(),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(saved) = &self.saved {
+            use nix::sys::termios::{tcsetattr, SetArg};
+            let _ = tcsetattr(std::io::stdin(), SetArg::TCSANOW, saved);
+        }
+    }
+}
+
+// The console thread is left behind at shutdown, it may be waiting for a
+// line. Keep the returned guard until then so the terminal is put right.
+pub(crate) fn start(console: Console) -> Terminal {
+    let terminal = Terminal::save();
+    let spawned = std::thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || console.run());
+    if let Err(e) = spawned {
+        error!("Failed to start the console, use signals instead: {}", e);
+    }
+    terminal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Listeners;
+    use crate::dispatch::Connection;
+    use crate::store::connection::Control;
+    use crate::test_client::test_state;
+
+    fn console() -> (Console, watch::Receiver<bool>) {
+        let (shutdown, running) = watch::channel(true);
+        let console = Console::new(
+            test_state(None),
+            Arc::new(shutdown),
+            None,
+            Overrides::default(),
+        );
+        (console, running)
+    }
+
+    fn output(reply: Reply) -> String {
+        match reply {
+            Reply::Output(output) => output,
+            Reply::Quit => panic!("console quit"),
+        }
+    }
+
+    #[test]
+    fn commands_reach_live_connections() {
+        let (console, running) = console();
+        let state = console.state.clone();
+        let mut lobby = Connection::new(7, Server::Lobby, "1.2.3.4:5".to_string(), state.clone());
+        let mut control = state.connections.register(&lobby);
+        lobby.customer_id = Some(42);
+        state.connections.update(&lobby);
+
+        let listing = output(console.execute("connections lobby"));
+        assert!(listing.contains("1.2.3.4:5"), "{}", listing);
+        assert!(listing.contains("42"), "{}", listing);
+        assert_eq!(
+            output(console.execute("connections login")),
+            "No connections"
+        );
+        assert!(output(console.execute("session 42")).contains("Connection 7 to lobby"));
+
+        assert_eq!(
+            output(console.execute("broadcast  track closes soon ")),
+            "Sent to 1 lobby connection(s)"
+        );
+        assert_eq!(
+            control.try_recv(),
+            Ok(Control::Send(
+                SystemMessage::new("track closes soon").to_bytes().unwrap()
+            ))
+        );
+        assert_eq!(output(console.execute("kick 7")), "Kicking connection 7");
+        assert_eq!(control.try_recv(), Ok(Control::Kick));
+        assert_eq!(output(console.execute("kick 8")), "No connection 8");
+
+        assert!(output(console.execute("frobnicate")).starts_with("Unknown command"));
+        assert_eq!(console.execute("quit"), Reply::Quit);
+        // Quitting is up to the loop, execute only reports it
+        assert!(*running.borrow());
+    }
+
+    #[test]
+    fn completes_commands_and_arguments() {
+        let helper = Helper {
+            state: test_state(None),
+        };
+        assert_eq!(
+            helper.candidates("co"),
+            (0, vec!["connections".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("connections lo"),
+            (12, vec!["login".to_string(), "lobby".to_string()])
+        );
+        assert_eq!(helper.candidates("log d"), (4, vec!["debug".to_string()]));
+        assert_eq!(helper.candidates("kick "), (5, Vec::new()));
+        // Spaces outside ASCII are more than one byte long
+        assert_eq!(
+            helper.candidates("log\u{3000}d"),
+            (6, vec!["debug".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("connections\u{a0}lo"),
+            (13, vec!["login".to_string(), "lobby".to_string()])
+        );
+    }
+
+    #[test]
+    fn sessions_complete_once_each() {
+        let state = test_state(None);
+        for (id, customer_id) in [(1, 42), (2, 7), (3, 42)] {
+            let mut connection =
+                Connection::new(id, Server::Lobby, "1.2.3.4:5".to_string(), state.clone());
+            state.connections.register(&connection);
+            connection.customer_id = Some(customer_id);
+            state.connections.update(&connection);
+        }
+        let helper = Helper { state };
+        assert_eq!(
+            helper.candidates("session "),
+            (8, vec!["7".to_string(), "42".to_string()])
+        );
+    }
+
+    #[test]
+    fn reload_keeps_the_bound_listeners() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "[listeners]\nlogin = \"0.0.0.0:9226\"\n\n[[shards]]\nid = 44\nname = \"Renamed\"\n",
+        )
+        .unwrap();
+        let (shutdown, _running) = watch::channel(true);
+        let console = Console::new(
+            test_state(None),
+            Arc::new(shutdown),
+            Some(file.path().to_path_buf()),
+            Overrides::default(),
+        );
+
+        let output = output(console.execute("reload"));
+        assert!(
+            output.contains("listeners take effect on a restart"),
+            "{}",
+            output
+        );
+        let config = console.state.config();
+        assert_eq!(config.listeners, Listeners::default());
+        assert_eq!(config.shards[0].name, "Renamed");
+    }
+}
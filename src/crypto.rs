@@ -0,0 +1,255 @@
+// Desc: Session encryption
+
+use std::sync::OnceLock;
+
+use openssl::provider::Provider;
+use openssl::symm::{Cipher, Crypter, Mode};
+
+// DES keys are 8 bytes, taken from the front of the session key
+const DES_KEY_LENGTH: usize = 8;
+const DES_BLOCK_SIZE: usize = 8;
+
+// RC4 and DES live in OpenSSL's legacy provider, which has to be loaded by hand
+fn load_legacy_provider() -> Result<(), ()> {
+    static LEGACY: OnceLock<Option<Provider>> = OnceLock::new();
+    let provider = LEGACY.get_or_init(|| match Provider::try_load(None, "legacy", true) {
+        Ok(provider) => Some(provider),
+        Err(e) => {
+            error!("Failed to load the OpenSSL legacy provider: {}", e);
+            None
+        }
+    });
+    match provider {
+        Some(_) => Ok(()),
+        None => Err(()),
+    }
+}
+
+// RC4 encrypts and decrypts the same way, but keeps state between calls
+pub(crate) struct Rc4 {
+    crypter: Crypter,
+}
+
+impl Rc4 {
+    pub(crate) fn new(key: &[u8]) -> Result<Rc4, ()> {
+        load_legacy_provider()?;
+        match Crypter::new(Cipher::rc4(), Mode::Encrypt, key, None) {
+            Ok(crypter) => Ok(Rc4 { crypter }),
+            Err(e) => {
+                error!("Failed to create RC4 cipher: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    pub(crate) fn apply(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let mut output = vec![0; data.len() + Cipher::rc4().block_size()];
+        match self.crypter.update(data, &mut output) {
+            Ok(count) => {
+                output.truncate(count);
+                Ok(output)
+            }
+            Err(e) => {
+                error!("RC4 failed: {}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+// DES in CBC mode with a zero IV, chaining across calls
+pub(crate) struct DesCbc {
+    crypter: Crypter,
+}
+
+impl DesCbc {
+    pub(crate) fn new(key: &[u8], mode: Mode) -> Result<DesCbc, ()> {
+        load_legacy_provider()?;
+        if key.len() < DES_KEY_LENGTH {
+            error!("DES key too short: {} bytes", key.len());
+            return Err(());
+        }
+        let iv = [0; DES_BLOCK_SIZE];
+        let mut crypter =
+            match Crypter::new(Cipher::des_cbc(), mode, &key[..DES_KEY_LENGTH], Some(&iv)) {
+                Ok(crypter) => crypter,
+                Err(e) => {
+                    error!("Failed to create DES cipher: {}", e);
+                    return Err(());
+                }
+            };
+        crypter.pad(false);
+        Ok(DesCbc { crypter })
+    }
+
+    pub(crate) fn apply(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        if !data.len().is_multiple_of(DES_BLOCK_SIZE) {
+            error!("DES data is not a whole number of blocks: {}", data.len());
+            return Err(());
+        }
+        let mut output = vec![0; data.len() + DES_BLOCK_SIZE];
+        match self.crypter.update(data, &mut output) {
+            Ok(count) => {
+                output.truncate(count);
+                Ok(output)
+            }
+            Err(e) => {
+                error!("DES failed: {}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+// One set of cipher contexts per connection, one context per direction.
+// Message bodies use RC4 keyed with the whole session key, command blocks
+// use DES-CBC keyed with its first 8 bytes.
+pub(crate) struct Ciphers {
+    data_in: Rc4,
+    data_out: Rc4,
+    command_in: DesCbc,
+    command_out: DesCbc,
+}
+
+impl Ciphers {
+    pub(crate) fn from_session_key(session_key: &[u8]) -> Result<Ciphers, ()> {
+        Ok(Ciphers {
+            data_in: Rc4::new(session_key)?,
+            data_out: Rc4::new(session_key)?,
+            command_in: DesCbc::new(session_key, Mode::Decrypt)?,
+            command_out: DesCbc::new(session_key, Mode::Encrypt)?,
+        })
+    }
+
+    pub(crate) fn decrypt_data(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.data_in.apply(data)
+    }
+
+    pub(crate) fn encrypt_data(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.data_out.apply(data)
+    }
+
+    pub(crate) fn decrypt_command(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.command_in.apply(data)
+    }
+
+    pub(crate) fn encrypt_command(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.command_out.apply(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Keys;
+    use crate::keys::KeyManager;
+    use crate::packet::lobby::SystemMessage;
+    use crate::packet::mcots::MC_GET_OWNED_VEHICLES;
+    use crate::packet::Encode;
+    use crate::parser::user_login::decrypt_session_key;
+
+    // A session key field shaped like a LoginRequest's, encrypted with
+    // data/pub.key. It was made for this test, not taken from a real client.
+    const LOGIN_SESSION_KEY: &str =
+        "811F49CF1A33B7C65B5928F029EE1F95643E5C12168D303D91FF923EC2950156\
+        821C784634C18CEE003F53C418F975E088ACB9614DDB2430163A331B41AC65A5\
+        908BF58FD6C72BCE33D25D37BB62451FB9873D6ACBDE83EAE7DEAEEE1502E5F7\
+        740CDED9D1FA29A992B871748C77C393A4A09F790A1609DF88BF8A1D78983FB0";
+
+    #[test]
+    fn rc4_known_answer() {
+        // Published RC4 test vectors
+        let mut rc4 = Rc4::new(b"Key").unwrap();
+        assert_eq!(
+            hex::encode(rc4.apply(b"Plaintext").unwrap()),
+            "bbf316e8d940af0ad3"
+        );
+
+        let mut rc4 = Rc4::new(b"Secret").unwrap();
+        assert_eq!(
+            hex::encode(rc4.apply(b"Attack at dawn").unwrap()),
+            "45a01f645fc35b383552544b9bf5"
+        );
+    }
+
+    #[test]
+    fn rc4_keeps_state_between_calls() {
+        let mut whole = Rc4::new(b"Key").unwrap();
+        let mut split = Rc4::new(b"Key").unwrap();
+        let mut output = split.apply(b"Plain").unwrap();
+        output.extend(split.apply(b"text").unwrap());
+        assert_eq!(output, whole.apply(b"Plaintext").unwrap());
+    }
+
+    #[test]
+    fn des_known_answer() {
+        // The worked example from the DES standard, a single block with a zero IV
+        let key = hex::decode("133457799bbcdff1").unwrap();
+        let plaintext = hex::decode("0123456789abcdef").unwrap();
+        let mut des = DesCbc::new(&key, Mode::Encrypt).unwrap();
+        assert_eq!(
+            hex::encode(des.apply(&plaintext).unwrap()),
+            "85e813540f0ab405"
+        );
+
+        let mut des = DesCbc::new(&key, Mode::Decrypt).unwrap();
+        assert_eq!(
+            des.apply(&hex::decode("85e813540f0ab405").unwrap())
+                .unwrap(),
+            plaintext
+        );
+    }
+
+    #[test]
+    fn des_rejects_partial_blocks() {
+        let mut des = DesCbc::new(&[0; 8], Mode::Encrypt).unwrap();
+        assert!(des.apply(&[0; 7]).is_err());
+    }
+
+    #[test]
+    fn ciphers_round_trip_between_peers() {
+        let session_key = [0x5a; 32];
+        let mut server = Ciphers::from_session_key(&session_key).unwrap();
+        let mut client = Ciphers::from_session_key(&session_key).unwrap();
+
+        let data = server.encrypt_data(b"lobby list please").unwrap();
+        assert_ne!(data, b"lobby list please");
+        assert_eq!(client.decrypt_data(&data).unwrap(), b"lobby list please");
+
+        let command = client.encrypt_command(b"commands").unwrap();
+        assert_eq!(server.decrypt_command(&command).unwrap(), b"commands");
+    }
+
+    #[test]
+    fn login_session_key_outputs_stay_the_same() {
+        let keys = KeyManager::load(&Keys::default()).unwrap();
+        let session_key = decrypt_session_key(&keys.private_key(), LOGIN_SESSION_KEY).unwrap();
+        assert_eq!(
+            hex::encode(&session_key),
+            "9f5f0ada3c48aaadd5d1a6c78d0c36ddd634596a26d7148eeb4f27a3fd488770"
+        );
+
+        // Recorded from this code, so they only catch changes to what we
+        // send. Nothing here has been checked against a real client, swap in
+        // bytes from a client capture once there is one.
+        let mut server = Ciphers::from_session_key(&session_key).unwrap();
+        let message = SystemMessage::new("Welcome to the track")
+            .to_bytes()
+            .unwrap();
+        let data = server.encrypt_data(&message[4..]).unwrap();
+        assert_eq!(
+            hex::encode(&data),
+            "be3fd1fa37775eb9ec41200a2cc70bec9be593f353ea"
+        );
+
+        // An MCOTS owned vehicles request for persona 1, padded to a block
+        let mut request = MC_GET_OWNED_VEHICLES.to_le_bytes().to_vec();
+        request.extend_from_slice(&[1, 0, 0, 0, 0, 0]);
+        let command = server.encrypt_command(&request).unwrap();
+        assert_eq!(hex::encode(&command), "c9dd770ebd85ad06");
+
+        let mut client = Ciphers::from_session_key(&session_key).unwrap();
+        assert_eq!(client.decrypt_data(&data).unwrap(), &message[4..]);
+        assert_eq!(client.decrypt_command(&command).unwrap(), request);
+    }
+}
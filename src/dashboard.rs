@@ -0,0 +1,596 @@
+// Desc: Full screen dashboard on the server's own terminal
+//
+// An alternative to the console, picked with --dashboard. Shows each
+// listener's connections, the packets going past, live sessions and the log,
+// redrawn a few times a second from the live state. The packet feed and the
+// log tail are only kept while the dashboard is up, and the log goes back to
+// the terminal once it closes.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+use tokio::sync::watch;
+
+use crate::capture::Direction;
+use crate::dispatch::Server;
+use crate::inspect::{describe, message_name};
+use crate::log::{clock, divert_to_tail, tail};
+use crate::state::State;
+use crate::store::connection::LiveConnection;
+use crate::store::feed::FeedEntry;
+
+// How often the screen is redrawn when nothing is pressed
+const REFRESH: Duration = Duration::from_millis(250);
+
+// Rows for each listener pane, borders included
+const LISTENER_HEIGHT: usize = 6;
+
+// How far Page Up and Page Down move the selection
+const PAGE: usize = 10;
+
+const LISTENERS: [Server; 4] = [
+    Server::Login,
+    Server::Persona,
+    Server::Lobby,
+    Server::Transaction,
+];
+
+struct SessionRow {
+    customer_id: u32,
+    username: String,
+    persona: String,
+    expires_in: Duration,
+}
+
+// Everything one frame shows, read from the state in one go
+struct Snapshot {
+    connections: Vec<LiveConnection>,
+    sessions: Vec<SessionRow>,
+    packets: Vec<FeedEntry>,
+    log: Vec<String>,
+    capturing: bool,
+}
+
+impl Snapshot {
+    fn read(state: &State) -> Snapshot {
+        let now = Instant::now();
+        let sessions = state
+            .sessions
+            .list()
+            .into_iter()
+            .map(|session| SessionRow {
+                customer_id: session.customer_id,
+                username: match state.accounts.get(session.customer_id) {
+                    Some(account) => account.username,
+                    None => "-".to_string(),
+                },
+                persona: match session.persona_id {
+                    Some(persona_id) => match state.personas.get(session.customer_id, persona_id) {
+                        Some(persona) => persona.name,
+                        None => persona_id.to_string(),
+                    },
+                    None => "-".to_string(),
+                },
+                expires_in: session.expires.saturating_duration_since(now),
+            })
+            .collect();
+        Snapshot {
+            connections: state.connections.list(),
+            sessions,
+            packets: state.feed.entries(),
+            log: tail(),
+            capturing: state.capture.is_enabled(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    None,
+    ToggleCapture,
+    Quit,
+}
+
+// Where the keyboard has got to
+#[derive(Debug, Default)]
+struct View {
+    // The sequence number of the selected packet, none follows the newest
+    selected: Option<u64>,
+    // Show the selected packet's fields in place of the feed
+    inspecting: bool,
+}
+
+impl View {
+    // Index of the selected packet, if it is still in the feed
+    fn selected_index(&self, packets: &[FeedEntry]) -> Option<usize> {
+        let selected = self.selected?;
+        packets
+            .iter()
+            .position(|packet| packet.sequence == selected)
+    }
+
+    fn select(&mut self, packets: &[FeedEntry], index: Option<usize>) {
+        self.selected = index.and_then(|index| packets.get(index).map(|packet| packet.sequence));
+        if self.selected.is_none() {
+            self.inspecting = false;
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent, packets: &[FeedEntry]) -> Action {
+        // Raw mode swallows Ctrl-C before it becomes SIGINT
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit;
+        }
+        let newest = packets.len().checked_sub(1);
+        let current = self.selected_index(packets);
+        match key.code {
+            KeyCode::Char('q') => return Action::Quit,
+            KeyCode::Char('c') => return Action::ToggleCapture,
+            KeyCode::Up | KeyCode::Char('k') => match current {
+                Some(index) => self.select(packets, Some(index.saturating_sub(1))),
+                None => self.select(packets, newest),
+            },
+            KeyCode::Down | KeyCode::Char('j') => match current {
+                // Going past the newest packet goes back to following the feed
+                Some(index) if Some(index) != newest => self.select(packets, Some(index + 1)),
+                _ => self.select(packets, None),
+            },
+            KeyCode::PageUp => {
+                let index = current.or(newest).map(|index| index.saturating_sub(PAGE));
+                self.select(packets, index);
+            }
+            KeyCode::PageDown => {
+                if let Some(index) = current {
+                    match newest {
+                        Some(newest) if index + PAGE < newest => {
+                            self.select(packets, Some(index + PAGE))
+                        }
+                        _ => self.select(packets, None),
+                    }
+                }
+            }
+            KeyCode::Home => self.select(packets, newest.map(|_| 0)),
+            KeyCode::End => self.select(packets, None),
+            KeyCode::Enter => {
+                if current.is_none() {
+                    self.select(packets, newest);
+                }
+                self.inspecting = self.selected.is_some();
+            }
+            KeyCode::Esc | KeyCode::Backspace => {
+                if self.inspecting {
+                    self.inspecting = false;
+                } else {
+                    self.select(packets, None);
+                }
+            }
+            _ => {}
+        }
+        Action::None
+    }
+}
+
+// A frame drawn in memory, written to the terminal in one go
+struct Screen {
+    width: usize,
+    rows: Vec<Vec<char>>,
+    // Row, column and width of text shown in reverse video
+    highlights: Vec<(usize, usize, usize)>,
+}
+
+impl Screen {
+    fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            width,
+            rows: vec![vec![' '; width]; height],
+            highlights: Vec::new(),
+        }
+    }
+
+    // Anything past `width` columns is cut off
+    fn text(&mut self, x: usize, y: usize, width: usize, text: &str) {
+        let Some(row) = self.rows.get_mut(y) else {
+            return;
+        };
+        for (i, c) in text.chars().take(width).enumerate() {
+            if let Some(cell) = row.get_mut(x + i) {
+                *cell = if c.is_control() { ' ' } else { c };
+            }
+        }
+    }
+
+    fn highlight(&mut self, x: usize, y: usize, width: usize) {
+        self.highlights.push((y, x, width));
+    }
+
+    // A box with its title in the top border
+    fn frame(&mut self, x: usize, y: usize, width: usize, height: usize, title: &str) {
+        if width < 2 || height < 2 {
+            return;
+        }
+        let inner = width - 2;
+        let title = format!(" {} ", title);
+        let top: String = title
+            .chars()
+            .chain(std::iter::repeat('─'))
+            .take(inner)
+            .collect();
+        self.text(x, y, width, &format!("┌{}┐", top));
+        for row in y + 1..y + height - 1 {
+            self.text(x, row, 1, "│");
+            self.text(x + width - 1, row, 1, "│");
+        }
+        let bottom = "─".repeat(inner);
+        self.text(x, y + height - 1, width, &format!("└{}┘", bottom));
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.rows.iter().map(|row| row.iter().collect()).collect()
+    }
+}
+
+fn direction(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Inbound => "in",
+        Direction::Outbound => "out",
+        // Sent by the server without being asked
+        Direction::Unsolicited => "srv",
+    }
+}
+
+fn packet_line(packet: &FeedEntry) -> String {
+    format!(
+        "{}  {:>5}  {:<11}  {:<3}  {:<6}  {:>5}",
+        clock(packet.time),
+        packet.connection_id,
+        packet.server.to_string(),
+        direction(packet.direction),
+        message_name(packet.server, &packet.frame),
+        packet.frame.len()
+    )
+}
+
+fn render(snapshot: &Snapshot, view: &View, width: usize, height: usize) -> Screen {
+    let mut screen = Screen::new(width, height);
+    if width < 60 || height < 20 {
+        screen.text(0, 0, width, "Terminal too small for the dashboard");
+        return screen;
+    }
+
+    let header = format!(
+        " Rusty Motors   capture {}   q quit  c capture  ↑↓ select  enter inspect  esc back",
+        if snapshot.capturing { "on" } else { "off" }
+    );
+    screen.text(0, 0, width, &header);
+    screen.highlight(0, 0, width);
+
+    // One pane per listener
+    let pane_width = width / LISTENERS.len();
+    for (i, server) in LISTENERS.iter().enumerate() {
+        let x = i * pane_width;
+        let w = if i == LISTENERS.len() - 1 {
+            width - x
+        } else {
+            pane_width
+        };
+        let connections: Vec<&LiveConnection> = snapshot
+            .connections
+            .iter()
+            .filter(|connection| connection.server == *server)
+            .collect();
+        screen.frame(
+            x,
+            1,
+            w,
+            LISTENER_HEIGHT,
+            &format!("{} {}", server, connections.len()),
+        );
+        let rows = LISTENER_HEIGHT - 2;
+        for (row, connection) in connections.iter().take(rows).enumerate() {
+            let line = if row == rows - 1 && connections.len() > rows {
+                format!("+{} more", connections.len() - row)
+            } else {
+                let who = match connection.customer_id {
+                    Some(customer_id) => format!(" c{}", customer_id),
+                    None => String::new(),
+                };
+                format!("#{} {}{}", connection.id, connection.peer, who)
+            };
+            screen.text(x + 1, 2 + row, w - 2, &line);
+        }
+    }
+
+    let rest = height - 1 - LISTENER_HEIGHT;
+    let log_height = (rest / 3).max(4);
+    let middle_height = rest - log_height;
+    let middle = 1 + LISTENER_HEIGHT;
+    let selected = view.selected_index(&snapshot.packets);
+
+    match selected.filter(|_| view.inspecting) {
+        Some(index) => {
+            let packet = &snapshot.packets[index];
+            let title = format!("packet {}", packet_line(packet));
+            screen.frame(0, middle, width, middle_height, &title);
+            let lines = describe(packet.server, packet.direction, &packet.frame);
+            for (row, line) in lines.iter().take(middle_height - 2).enumerate() {
+                screen.text(1, middle + 1 + row, width - 2, line);
+            }
+        }
+        None => {
+            // Packets on the left, sessions on the right
+            let feed_width = width * 3 / 5;
+            screen.frame(
+                0,
+                middle,
+                feed_width,
+                middle_height,
+                &format!("packets {}", snapshot.packets.len()),
+            );
+            screen.text(
+                1,
+                middle + 1,
+                feed_width - 2,
+                "time       conn  listener     dir  id        len",
+            );
+            let rows = middle_height - 3;
+            let end = selected.unwrap_or(snapshot.packets.len().saturating_sub(1));
+            let start = (end + 1).saturating_sub(rows);
+            for (row, packet) in snapshot.packets.iter().skip(start).take(rows).enumerate() {
+                let y = middle + 2 + row;
+                screen.text(1, y, feed_width - 2, &packet_line(packet));
+                if Some(start + row) == selected {
+                    screen.highlight(1, y, feed_width - 2);
+                }
+            }
+
+            let sessions_width = width - feed_width;
+            screen.frame(
+                feed_width,
+                middle,
+                sessions_width,
+                middle_height,
+                &format!("sessions {}", snapshot.sessions.len()),
+            );
+            screen.text(
+                feed_width + 1,
+                middle + 1,
+                sessions_width - 2,
+                "customer  username      persona       expires",
+            );
+            for (row, session) in snapshot.sessions.iter().take(rows).enumerate() {
+                let line = format!(
+                    "{:>8}  {:<12}  {:<12}  {:>6}s",
+                    session.customer_id,
+                    session.username,
+                    session.persona,
+                    session.expires_in.as_secs()
+                );
+                screen.text(feed_width + 1, middle + 2 + row, sessions_width - 2, &line);
+            }
+        }
+    }
+
+    let log = middle + middle_height;
+    screen.frame(0, log, width, log_height, "log");
+    let rows = log_height - 2;
+    let start = snapshot.log.len().saturating_sub(rows);
+    for (row, line) in snapshot.log[start..].iter().enumerate() {
+        screen.text(1, log + 1 + row, width - 2, line);
+    }
+    screen
+}
+
+fn draw(out: &mut impl Write, screen: &Screen) -> std::io::Result<()> {
+    for (y, line) in screen.lines().iter().enumerate() {
+        queue!(out, MoveTo(0, y as u16))?;
+        let chars: Vec<char> = line.chars().collect();
+        let mut x = 0;
+        let mut highlights: Vec<_> = screen
+            .highlights
+            .iter()
+            .filter(|(row, _, _)| *row == y)
+            .collect();
+        highlights.sort_by_key(|(_, start, _)| *start);
+        for (_, start, width) in highlights {
+            let end = (start + width).min(screen.width);
+            let start = (*start).max(x).min(end);
+            queue!(
+                out,
+                Print(chars[x..start].iter().collect::<String>()),
+                SetAttribute(Attribute::Reverse),
+                Print(chars[start..end].iter().collect::<String>()),
+                SetAttribute(Attribute::NoReverse)
+            )?;
+            x = end;
+        }
+        queue!(out, Print(chars[x..].iter().collect::<String>()))?;
+    }
+    out.flush()
+}
+
+// Takes over the terminal, and gives it back however the dashboard ends
+struct FullScreen;
+
+impl FullScreen {
+    fn enter() -> std::io::Result<FullScreen> {
+        enable_raw_mode()?;
+        let full_screen = FullScreen;
+        execute!(std::io::stdout(), EnterAlternateScreen, Hide)?;
+        divert_to_tail(true);
+        Ok(full_screen)
+    }
+}
+
+impl Drop for FullScreen {
+    fn drop(&mut self) {
+        divert_to_tail(false);
+        let _ = execute!(std::io::stdout(), Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+fn run(state: Arc<State>, shutdown: Arc<watch::Sender<bool>>) {
+    let full_screen = match FullScreen::enter() {
+        Ok(full_screen) => full_screen,
+        Err(e) => {
+            error!("Failed to start the dashboard, use signals instead: {}", e);
+            return;
+        }
+    };
+    state.feed.set_enabled(true);
+
+    let mut view = View::default();
+    let mut out = std::io::stdout();
+    // A signal may ask us to stop at any time
+    while *shutdown.borrow() {
+        let snapshot = Snapshot::read(&state);
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+        let screen = render(&snapshot, &view, width as usize, height as usize);
+        if let Err(e) = draw(&mut out, &screen) {
+            drop(full_screen);
+            error!("Failed to draw the dashboard: {}", e);
+            state.feed.set_enabled(false);
+            return;
+        }
+
+        match crossterm::event::poll(REFRESH) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                drop(full_screen);
+                error!("Failed to poll the terminal: {}", e);
+                state.feed.set_enabled(false);
+                return;
+            }
+        }
+        let key = match crossterm::event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+            Ok(_) => continue,
+            Err(e) => {
+                drop(full_screen);
+                error!("Failed to read the terminal: {}", e);
+                state.feed.set_enabled(false);
+                return;
+            }
+        };
+        match view.key(key, &snapshot.packets) {
+            Action::None => {}
+            Action::ToggleCapture => {
+                state.capture.toggle();
+            }
+            Action::Quit => {
+                shutdown.send_replace(false);
+                break;
+            }
+        }
+    }
+
+    drop(full_screen);
+    state.feed.set_enabled(false);
+}
+
+// The dashboard notices shutdown within a refresh, join the handle to have
+// the terminal back before printing anything else
+pub(crate) fn start(
+    state: Arc<State>,
+    shutdown: Arc<watch::Sender<bool>>,
+) -> Option<JoinHandle<()>> {
+    let spawned = std::thread::Builder::new()
+        .name("dashboard".to_string())
+        .spawn(move || run(state, shutdown));
+    match spawned {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            error!("Failed to start the dashboard, use signals instead: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn packet(sequence: u64) -> FeedEntry {
+        FeedEntry {
+            sequence,
+            time: SystemTime::UNIX_EPOCH,
+            connection_id: 3,
+            server: Server::Persona,
+            direction: Direction::Outbound,
+            frame: vec![0x06, 0x11, 0x00, 0x08, 0x00, 0x00, 0x00, 0x07],
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn keys_move_through_the_feed() {
+        let packets: Vec<FeedEntry> = (1..=3).map(packet).collect();
+        let mut view = View::default();
+
+        assert_eq!(view.key(key(KeyCode::Up), &packets), Action::None);
+        assert_eq!(view.selected, Some(3));
+        view.key(key(KeyCode::Up), &packets);
+        view.key(key(KeyCode::Up), &packets);
+        view.key(key(KeyCode::Up), &packets);
+        assert_eq!(view.selected, Some(1));
+        view.key(key(KeyCode::Enter), &packets);
+        assert!(view.inspecting);
+        view.key(key(KeyCode::Esc), &packets);
+        assert!(!view.inspecting);
+        assert_eq!(view.selected, Some(1));
+        view.key(key(KeyCode::End), &packets);
+        assert_eq!(view.selected, None);
+
+        assert_eq!(view.key(key(KeyCode::Char('q')), &packets), Action::Quit);
+        let interrupt = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(view.key(interrupt, &packets), Action::Quit);
+    }
+
+    #[test]
+    fn renders_every_pane() {
+        let snapshot = Snapshot {
+            connections: vec![LiveConnection {
+                id: 9,
+                server: Server::Lobby,
+                peer: "10.0.0.2:4000".to_string(),
+                customer_id: Some(42),
+                persona_id: Some(7),
+                connected: Instant::now(),
+            }],
+            sessions: vec![SessionRow {
+                customer_id: 42,
+                username: "racer".to_string(),
+                persona: "Speedy".to_string(),
+                expires_in: Duration::from_secs(60),
+            }],
+            packets: vec![packet(1)],
+            log: vec!["00:00:00 [INFO] hello".to_string()],
+            capturing: false,
+        };
+        let mut view = View::default();
+        let text = render(&snapshot, &view, 120, 30).lines().join("\n");
+        assert!(text.contains("┌ lobby 1 "), "{}", text);
+        assert!(text.contains("#9 10.0.0.2:4000 c42"), "{}", text);
+        assert!(text.contains("0x0611"), "{}", text);
+        assert!(text.contains("racer         Speedy"), "{}", text);
+        assert!(text.contains("[INFO] hello"), "{}", text);
+
+        view.key(key(KeyCode::Enter), &snapshot.packets);
+        let text = render(&snapshot, &view, 120, 30).lines().join("\n");
+        assert!(text.contains("persona_id: 7"), "{}", text);
+    }
+}
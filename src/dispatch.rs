@@ -0,0 +1,208 @@
+// Desc: Packet dispatch
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::log::hexdump;
+use crate::packet::error::ErrorResponse;
+use crate::packet::mcots::McotsStatus;
+use crate::packet::Encode;
+use crate::state::State;
+use crate::store::account::AccountStore;
+use crate::store::session::Session;
+
+// The listeners a packet can arrive on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Server {
+    Login,
+    Persona,
+    Lobby,
+    Transaction,
+}
+
+impl std::fmt::Display for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Server::Login => "login",
+            Server::Persona => "persona",
+            Server::Lobby => "lobby",
+            Server::Transaction => "transaction",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Per-connection state handed to every handler
+pub(crate) struct Connection {
+    pub(crate) id: u64,
+    pub(crate) server: Server,
+    pub(crate) peer: String,
+    pub(crate) state: Arc<State>,
+    pub(crate) customer_id: Option<u32>,
+    pub(crate) persona_id: Option<u32>,
+    // Set once the connection has switched to encrypted traffic
+    pub(crate) session_key: Option<Vec<u8>>,
+}
+
+impl Connection {
+    pub(crate) fn new(id: u64, server: Server, peer: String, state: Arc<State>) -> Connection {
+        Connection {
+            id,
+            server,
+            peer,
+            state,
+            customer_id: None,
+            persona_id: None,
+            session_key: None,
+        }
+    }
+
+    // The login session a request may act for. It has to be live, started from
+    // the same host, and for the customer this connection already speaks for.
+    // Checking doesn't bind the connection to the customer, handlers with
+    // checks of their own set `customer_id` once those pass too.
+    pub(crate) fn session(&self, customer_id: u32) -> Option<Session> {
+        if self.customer_id.is_some_and(|id| id != customer_id) {
+            error!(
+                "Connection {} is customer {:?}, refusing a request for customer {}",
+                self.id, self.customer_id, customer_id
+            );
+            return None;
+        }
+        let session = match self.state.sessions.get(customer_id) {
+            Some(session) => session,
+            None => {
+                error!(
+                    "No live session for customer {}, did they skip login?",
+                    customer_id
+                );
+                return None;
+            }
+        };
+        if host(&session.peer) != host(&self.peer) {
+            error!(
+                "Customer {} logged in from {} but {} is asking",
+                customer_id, session.peer, self.peer
+            );
+            return None;
+        }
+        Some(session)
+    }
+
+    // `session`, then speak for the customer from here on
+    pub(crate) fn bind_session(&mut self, customer_id: u32) -> Option<Session> {
+        let session = self.session(customer_id)?;
+        self.customer_id = Some(customer_id);
+        Some(session)
+    }
+
+    // `State::with_accounts`, failing the handler if the task does
+    pub(crate) async fn with_accounts<T: Send + 'static>(
+        &self,
+        call: impl FnOnce(&AccountStore) -> T + Send + 'static,
+    ) -> Result<T, ()> {
+        self.state
+            .with_accounts(call)
+            .await
+            .map_err(|e| error!("Account database task failed: {}", e))
+    }
+}
+
+// The address without its port
+fn host(peer: &str) -> &str {
+    peer.rsplit_once(':').map_or(peer, |(host, _)| host)
+}
+
+// A handler returns the packets to send back, in order
+pub(crate) type HandlerResult = Result<Vec<Vec<u8>>, ()>;
+pub(crate) type HandlerFuture<'a> = Pin<Box<dyn Future<Output = HandlerResult> + Send + 'a>>;
+
+pub(crate) trait Handler: Send + Sync {
+    fn handle<'a>(&'a self, connection: &'a mut Connection, packet: &'a [u8]) -> HandlerFuture<'a>;
+}
+
+impl<F> Handler for F
+where
+    F: for<'a> Fn(&'a mut Connection, &'a [u8]) -> HandlerFuture<'a> + Send + Sync,
+{
+    fn handle<'a>(&'a self, connection: &'a mut Connection, packet: &'a [u8]) -> HandlerFuture<'a> {
+        self(connection, packet)
+    }
+}
+
+// Maps (server, message id) to the handler for that message
+#[derive(Default)]
+pub(crate) struct Registry {
+    handlers: HashMap<(Server, u16), Box<dyn Handler>>,
+}
+
+impl Registry {
+    pub(crate) fn new() -> Registry {
+        Registry::default()
+    }
+
+    pub(crate) fn register<H>(&mut self, server: Server, id: u16, handler: H)
+    where
+        H: Handler + 'static,
+    {
+        if self
+            .handlers
+            .insert((server, id), Box::new(handler))
+            .is_some()
+        {
+            warn!("Replacing handler for 0x{:04x} on {}", id, server);
+        }
+    }
+
+    pub(crate) async fn dispatch(
+        &self,
+        connection: &mut Connection,
+        id: u16,
+        packet: &[u8],
+    ) -> HandlerResult {
+        match self.handlers.get(&(connection.server, id)) {
+            Some(handler) => handler.handle(connection, packet).await,
+            None => {
+                error!(
+                    "Unknown packet id 0x{:04x} on {} from {}",
+                    id, connection.server, connection.peer
+                );
+                debug!("Packet:\n{}", hexdump(packet));
+                Ok(vec![unknown_message_response(connection.server, id)?])
+            }
+        }
+    }
+}
+
+// The transaction server speaks MCOTS, everything else speaks NPS
+fn unknown_message_response(server: Server, id: u16) -> Result<Vec<u8>, ()> {
+    match server {
+        Server::Transaction => encode(&McotsStatus::failed(id)),
+        _ => encode(&ErrorResponse::generic(id)),
+    }
+}
+
+// Encode a message for the wire, one that doesn't fit its length fields
+// fails the handler or connection sending it
+pub(crate) fn encode(message: &impl Encode) -> Result<Vec<u8>, ()> {
+    message
+        .to_bytes()
+        .map_err(|e| error!("Failed to encode message: {}", e))
+}
+
+// Adapt an `async fn(&mut Connection, &[u8]) -> HandlerResult` into something
+// the registry can store
+macro_rules! handler {
+    ($function:path) => {{
+        fn handler<'a>(
+            connection: &'a mut $crate::dispatch::Connection,
+            packet: &'a [u8],
+        ) -> $crate::dispatch::HandlerFuture<'a> {
+            Box::pin($function(connection, packet))
+        }
+        handler
+    }};
+}
+pub(crate) use handler;
@@ -0,0 +1,294 @@
+// Desc: Decode frames into named fields for people to read
+//
+// Frames are decoded with the same types the handlers use, in both
+// directions. Replay names the field a difference falls in from the field
+// list of the decoded response.
+
+use std::fmt::Debug;
+
+use crate::capture::Direction;
+use crate::dispatch::Server;
+use crate::log::hexdump;
+use crate::packet::error::{
+    ErrorResponse, PacketError, NPS_FORCE_LOGOFF, NPS_GENERIC_FAILURE, NPS_LOGIN_FAILED,
+};
+use crate::packet::lobby::{
+    LobbyLogin, LobbyWelcome, SystemMessage, NPS_LOBBY_LOGIN, NPS_LOBBY_WELCOME, NPS_SYSTEM_MESSAGE,
+};
+use crate::packet::login_request::{LoginRequest, NPS_USER_LOGIN};
+use crate::packet::mcots::{
+    message_id, ClientConnect, McotsStatus, OwnedVehiclesList, OwnedVehiclesRequest, StockCarInfo,
+    StockCarInfoRequest, MC_CLIENT_CONNECT_MSG, MC_FAILED, MC_GET_OWNED_VEHICLES, MC_LOGIN,
+    MC_LOGOUT, MC_OWNED_VEHICLES_LIST, MC_STOCK_CAR_INFO, MC_SUCCESS,
+};
+use crate::packet::persona::{
+    DeletePersona, GetPersonaMaps, PersonaCreated, PersonaMaps, PersonaNameRequest, PersonaStatus,
+    SelectGamePersona, NPS_ACK, NPS_CREATE_PERSONA, NPS_DELETE_PERSONA, NPS_GET_PERSONA_MAPS,
+    NPS_PERSONA_CREATED, NPS_PERSONA_MAPS, NPS_PERSONA_NAME_INVALID, NPS_PERSONA_NAME_TAKEN,
+    NPS_PERSONA_NOT_FOUND, NPS_SELECT_GAME_PERSONA, NPS_TOO_MANY_PERSONAS,
+    NPS_VALIDATE_PERSONA_NAME,
+};
+use crate::packet::user_status::{UserStatus, NPS_USER_STATUS};
+use crate::packet::{Decode, Encode};
+
+// A decoded message we can print and list the fields of
+trait Message: Debug + Encode {
+    fn name(&self) -> &'static str;
+}
+
+impl<T: Debug + Encode> Message for T {
+    fn name(&self) -> &'static str {
+        let path = std::any::type_name::<T>();
+        path.rsplit("::").next().unwrap_or(path)
+    }
+}
+
+fn decode<T: Message + Decode + 'static>(frame: &[u8]) -> Result<Box<dyn Message>, PacketError> {
+    Ok(Box::new(T::from_bytes(frame)?))
+}
+
+// Every NPS response we know how to send, `None` for anything else
+fn decode_response(frame: &[u8]) -> Option<Result<Box<dyn Message>, PacketError>> {
+    let id = u16::from_be_bytes([*frame.first()?, *frame.get(1)?]);
+    Some(match id {
+        NPS_USER_STATUS => decode::<UserStatus>(frame),
+        NPS_GENERIC_FAILURE | NPS_LOGIN_FAILED | NPS_FORCE_LOGOFF => decode::<ErrorResponse>(frame),
+        NPS_PERSONA_MAPS => decode::<PersonaMaps>(frame),
+        NPS_PERSONA_CREATED => decode::<PersonaCreated>(frame),
+        NPS_ACK
+        | NPS_PERSONA_NAME_TAKEN
+        | NPS_PERSONA_NAME_INVALID
+        | NPS_PERSONA_NOT_FOUND
+        | NPS_TOO_MANY_PERSONAS => decode::<PersonaStatus>(frame),
+        NPS_LOBBY_WELCOME => decode::<LobbyWelcome>(frame),
+        NPS_SYSTEM_MESSAGE => decode::<SystemMessage>(frame),
+        _ => return None,
+    })
+}
+
+// Every MCOTS response we know how to send, `None` for anything else
+fn decode_mcots_response(body: &[u8]) -> Option<Result<Box<dyn Message>, PacketError>> {
+    Some(match message_id(body).ok()? {
+        MC_SUCCESS | MC_FAILED | MC_LOGOUT => decode::<McotsStatus>(body),
+        MC_STOCK_CAR_INFO => decode::<StockCarInfo>(body),
+        MC_OWNED_VEHICLES_LIST => decode::<OwnedVehiclesList>(body),
+        _ => return None,
+    })
+}
+
+// Name the field of a response that `offset` falls in
+pub(crate) fn field_at(server: Server, frame: &[u8], offset: usize) -> String {
+    let message = match server {
+        Server::Transaction => decode_mcots_response(frame),
+        _ => decode_response(frame),
+    };
+    let message = match message {
+        Some(Ok(message)) => message,
+        // MCOTS bodies have no header, only the id is common to all of them
+        _ if server == Server::Transaction => {
+            let field = if offset < 2 { "id" } else { "body" };
+            return field.to_string();
+        }
+        // Without a message to go on only the header can be named
+        _ => {
+            let field = match offset {
+                0 | 1 => "header.id",
+                2 | 3 => "header.length",
+                _ => "body",
+            };
+            return field.to_string();
+        }
+    };
+
+    let mut start = 0;
+    for (field, size) in message.fields() {
+        if offset < start + size {
+            return format!("{}.{}", message.name(), field);
+        }
+        start += size;
+    }
+    format!("{}, past the last field", message.name())
+}
+
+fn decoded<T: Debug + ?Sized>(message: Result<Box<T>, PacketError>) -> Vec<String> {
+    match message {
+        Ok(message) => format!("{:#?}", message)
+            .lines()
+            .map(String::from)
+            .collect(),
+        Err(e) => vec![format!("Failed to decode: {}", e)],
+    }
+}
+
+fn boxed<T: Debug + 'static>(
+    message: Result<T, PacketError>,
+) -> Result<Box<dyn Debug>, PacketError> {
+    Ok(Box::new(message?))
+}
+
+fn response_fields(frame: &[u8]) -> Vec<String> {
+    match decode_response(frame) {
+        Some(message) => decoded(message),
+        None => vec!["unknown message".to_string()],
+    }
+}
+
+fn request_fields(frame: &[u8]) -> Vec<String> {
+    let id = u16::from_be_bytes([frame[0], frame[1]]);
+    let message = match id {
+        NPS_USER_LOGIN => boxed(LoginRequest::from_bytes(frame)),
+        NPS_GET_PERSONA_MAPS => boxed(GetPersonaMaps::from_bytes(frame)),
+        NPS_SELECT_GAME_PERSONA => boxed(SelectGamePersona::from_bytes(frame)),
+        NPS_CREATE_PERSONA | NPS_VALIDATE_PERSONA_NAME => {
+            boxed(PersonaNameRequest::from_bytes(frame))
+        }
+        NPS_DELETE_PERSONA => boxed(DeletePersona::from_bytes(frame)),
+        NPS_LOBBY_LOGIN => boxed(LobbyLogin::from_bytes(frame)),
+        _ => return vec!["unknown message".to_string()],
+    };
+    decoded(message)
+}
+
+fn mcots_fields(direction: Direction, body: &[u8]) -> Vec<String> {
+    let id = match message_id(body) {
+        Ok(id) => id,
+        Err(e) => return vec![format!("Failed to decode: {}", e)],
+    };
+    // Unprompted frames are the same messages the server sends in reply
+    let direction = match direction {
+        Direction::Unsolicited => Direction::Outbound,
+        direction => direction,
+    };
+    let message = match (direction, id) {
+        (Direction::Inbound, MC_CLIENT_CONNECT_MSG | MC_LOGIN) => {
+            boxed(ClientConnect::from_bytes(body))
+        }
+        (Direction::Inbound, MC_STOCK_CAR_INFO) => boxed(StockCarInfoRequest::from_bytes(body)),
+        (Direction::Inbound, MC_GET_OWNED_VEHICLES) => {
+            boxed(OwnedVehiclesRequest::from_bytes(body))
+        }
+        (Direction::Outbound, MC_SUCCESS | MC_FAILED | MC_LOGOUT) => {
+            boxed(McotsStatus::from_bytes(body))
+        }
+        (Direction::Outbound, MC_STOCK_CAR_INFO) => boxed(StockCarInfo::from_bytes(body)),
+        (Direction::Outbound, MC_OWNED_VEHICLES_LIST) => boxed(OwnedVehiclesList::from_bytes(body)),
+        _ => return vec![format!("unknown message {}", id)],
+    };
+    decoded(message)
+}
+
+// The message id as it would be written in a handler
+pub(crate) fn message_name(server: Server, frame: &[u8]) -> String {
+    match server {
+        Server::Transaction => match message_id(frame) {
+            Ok(id) => id.to_string(),
+            Err(_) => "?".to_string(),
+        },
+        _ => match frame.get(..2) {
+            Some(id) => format!("0x{:04x}", u16::from_be_bytes([id[0], id[1]])),
+            None => "?".to_string(),
+        },
+    }
+}
+
+// The fields of a frame as recorded in a capture, then the raw bytes
+pub(crate) fn describe(server: Server, direction: Direction, frame: &[u8]) -> Vec<String> {
+    let mut lines = if server == Server::Transaction {
+        mcots_fields(direction, frame)
+    } else if frame.len() < 4 {
+        vec!["Frame too short for a header".to_string()]
+    } else if direction == Direction::Inbound {
+        request_fields(frame)
+    } else {
+        response_fields(frame)
+    };
+    lines.push(String::new());
+    lines.extend(hexdump(frame).lines().map(String::from));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::mcots::StockCarEntry;
+    use crate::store::persona::Persona;
+
+    #[test]
+    fn describes_both_directions() {
+        let created = PersonaCreated::new(7).to_bytes().unwrap();
+        let lines = describe(Server::Persona, Direction::Outbound, &created);
+        assert_eq!(lines[0], "PersonaCreated {");
+        assert!(lines.contains(&"    persona_id: 7,".to_string()));
+
+        let message = SystemMessage::new("hello").to_bytes().unwrap();
+        let lines = describe(Server::Lobby, Direction::Outbound, &message);
+        assert!(lines.contains(&"        string: \"hello\",".to_string()));
+
+        let mut request = vec![0x05, 0x32, 0x00, 0x08];
+        request.extend_from_slice(&42u32.to_be_bytes());
+        let lines = describe(Server::Persona, Direction::Inbound, &request);
+        assert_eq!(lines[0], "GetPersonaMaps {");
+        assert!(lines.contains(&"    customer_id: 42,".to_string()));
+        assert!(lines.last().unwrap().starts_with("00000000  05 32 00 08"));
+
+        let status = McotsStatus::failed(MC_LOGIN).to_bytes().unwrap();
+        let lines = describe(Server::Transaction, Direction::Outbound, &status);
+        assert_eq!(lines[0], "McotsStatus {");
+    }
+
+    #[test]
+    fn fields_are_named_from_the_message() {
+        let maps = PersonaMaps::new(&[Persona {
+            id: 3,
+            customer_id: 1,
+            name: "Racer".to_string(),
+            shard_id: 1,
+        }])
+        .to_bytes()
+        .unwrap();
+        assert_eq!(field_at(Server::Persona, &maps, 1), "PersonaMaps.header.id");
+        assert_eq!(
+            field_at(Server::Persona, &maps, 5),
+            "PersonaMaps.personas.count"
+        );
+        assert_eq!(
+            field_at(Server::Persona, &maps, 9),
+            "PersonaMaps.personas[0].id"
+        );
+        assert_eq!(
+            field_at(Server::Persona, &maps, 20),
+            "PersonaMaps.personas[0].name"
+        );
+        assert_eq!(
+            field_at(Server::Persona, &maps, 46),
+            "PersonaMaps, past the last field"
+        );
+
+        // Frames that don't decode still get their header named
+        assert_eq!(field_at(Server::Persona, &maps[..8], 2), "header.length");
+        assert_eq!(field_at(Server::Persona, &maps[..8], 6), "body");
+        assert_eq!(field_at(Server::Transaction, &maps, 6), "body");
+    }
+
+    #[test]
+    fn mcots_fields_are_named_from_the_message() {
+        let cars = StockCarInfo {
+            cars: vec![StockCarEntry {
+                car_id: 101,
+                brand_id: 1,
+                retail_price: 5000,
+            }],
+        }
+        .to_bytes()
+        .unwrap();
+        assert_eq!(field_at(Server::Transaction, &cars, 0), "StockCarInfo.id");
+        assert_eq!(
+            field_at(Server::Transaction, &cars, 3),
+            "StockCarInfo.cars.count"
+        );
+        assert_eq!(
+            field_at(Server::Transaction, &cars, 13),
+            "StockCarInfo.cars[0].retail_price"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Desc: The login server's RSA private key
+//
+// The key is read once at startup and shared by every connection. Sending the
+// process SIGHUP, or reloading the config from the console, reads it again so
+// a replaced key file takes effect without a restart. A reload that fails
+// keeps the key we already have.
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
+
+use crate::config::{Keys, Secret};
+
+pub(crate) struct KeyManager {
+    key: RwLock<Arc<Rsa<Private>>>,
+}
+
+impl KeyManager {
+    pub(crate) fn load(keys: &Keys) -> Result<KeyManager, ()> {
+        let key = read_key(
+            &keys.private_key,
+            keys.passphrase.as_ref().map(Secret::expose),
+        )?;
+        info!(
+            "Loaded {} bit private key from {}",
+            key.size() * 8,
+            keys.private_key.display()
+        );
+        Ok(KeyManager {
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    // The current key, which stays usable even if a reload swaps it out
+    pub(crate) fn private_key(&self) -> Arc<Rsa<Private>> {
+        self.key.read().unwrap().clone()
+    }
+
+    // `keys` may name a different file from the one we started with
+    pub(crate) fn reload(&self, keys: &Keys) -> Result<(), ()> {
+        let key = read_key(
+            &keys.private_key,
+            keys.passphrase.as_ref().map(Secret::expose),
+        )?;
+        info!("Reloaded private key from {}", keys.private_key.display());
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+fn read_key(path: &Path, passphrase: Option<&str>) -> Result<Rsa<Private>, ()> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(e) => {
+            error!("Failed to read private key {}: {}", path.display(), e);
+            return Err(());
+        }
+    };
+    // Answer OpenSSL's passphrase request ourselves, otherwise it asks on the
+    // terminal. Unencrypted keys never ask.
+    let key = Rsa::private_key_from_pem_callback(&pem, |buffer| {
+        let passphrase = passphrase.unwrap_or("").as_bytes();
+        match buffer.get_mut(..passphrase.len()) {
+            Some(buffer) => {
+                buffer.copy_from_slice(passphrase);
+                Ok(passphrase.len())
+            }
+            None => Ok(0),
+        }
+    });
+    let key = match key {
+        Ok(key) => key,
+        Err(e) => {
+            error!(
+                "Failed to parse private key {}, is the passphrase right?: {}",
+                path.display(),
+                e
+            );
+            return Err(());
+        }
+    };
+    match key.check_key() {
+        Ok(true) => Ok(key),
+        Ok(false) | Err(_) => {
+            error!("Private key {} is not a valid RSA key", path.display());
+            Err(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::symm::Cipher;
+
+    #[test]
+    fn encrypted_keys_need_the_passphrase() {
+        let original = read_key(&Keys::default().private_key, None).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let pem = original
+            .private_key_to_pem_passphrase(Cipher::aes_256_cbc(), b"hunter2")
+            .unwrap();
+        std::fs::write(&path, pem).unwrap();
+
+        let mut keys = Keys {
+            private_key: path.clone(),
+            ..Keys::default()
+        };
+        assert!(KeyManager::load(&keys).is_err());
+        keys.passphrase = Some(Secret::new("wrong"));
+        assert!(KeyManager::load(&keys).is_err());
+        keys.passphrase = Some(Secret::new("hunter2"));
+        let manager = KeyManager::load(&keys).unwrap();
+        assert_eq!(manager.private_key().n().to_vec(), original.n().to_vec());
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_old_key() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        std::fs::copy(Keys::default().private_key, &path).unwrap();
+        let keys = Keys {
+            private_key: path.clone(),
+            ..Keys::default()
+        };
+        let manager = KeyManager::load(&keys).unwrap();
+        let before = manager.private_key();
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(manager.reload(&keys).is_err());
+        assert!(Arc::ptr_eq(&before, &manager.private_key()));
+
+        let replacement = Rsa::generate(1024).unwrap();
+        std::fs::write(&path, replacement.private_key_to_pem().unwrap()).unwrap();
+        assert!(manager.reload(&keys).is_ok());
+        assert_eq!(manager.private_key().n().to_vec(), replacement.n().to_vec());
+    }
+}
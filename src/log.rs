@@ -1,41 +1,205 @@
-use log::LevelFilter;
-use simplelog::{CombinedLogger, TermLogger, Config, TerminalMode, ColorChoice, WriteLogger};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub(crate) fn get_log_level() -> LevelFilter {
-    match std::env::var("LOG_LEVEL") {
-        Ok(level) => match level.as_str() {
-            "trace" => LevelFilter::Trace,
-            "debug" => LevelFilter::Debug,
-            "info" => LevelFilter::Info,
-            "warn" => LevelFilter::Warn,
-            "error" => LevelFilter::Error,
-            _ => LevelFilter::Info,
-        },
-        Err(_) => LevelFilter::Info,
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, ConfigBuilder, SharedLogger, TermLogger, TerminalMode,
+    WriteLogger,
+};
+
+use crate::config::{parse_level, LogConfig};
+
+// The terminal level can be changed from the console, the log files keep theirs
+static TERMINAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static FILE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+// While the dashboard has the terminal, log lines are kept for it instead
+const TAIL_LENGTH: usize = 200;
+static TAIL: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+// Warnings and errors, kept for the admin API whatever the terminal shows
+const ERRORS_LENGTH: usize = 100;
+static ERRORS: Mutex<VecDeque<LoggedError>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Clone)]
+pub(crate) struct LoggedError {
+    pub(crate) time: SystemTime,
+    pub(crate) level: Level,
+    pub(crate) message: String,
+}
+
+// Levels have been validated with the rest of the config by now
+fn level(level: &str) -> LevelFilter {
+    parse_level(level).unwrap_or(LevelFilter::Info)
+}
+
+fn load(level: &AtomicUsize) -> LevelFilter {
+    LevelFilter::iter()
+        .nth(level.load(Ordering::Relaxed))
+        .unwrap_or(LevelFilter::Off)
+}
+
+pub(crate) fn terminal_level() -> LevelFilter {
+    load(&TERMINAL_LEVEL)
+}
+
+pub(crate) fn set_terminal_level(level: LevelFilter) {
+    TERMINAL_LEVEL.store(level as usize, Ordering::Relaxed);
+    // Let through whatever the terminal or a file still wants
+    log_crate::set_max_level(level.max(load(&FILE_LEVEL)));
+}
+
+// Start or stop keeping terminal log lines for the dashboard
+pub(crate) fn divert_to_tail(divert: bool) {
+    *TAIL.lock().unwrap() = if divert { Some(VecDeque::new()) } else { None };
+}
+
+// Oldest first
+pub(crate) fn tail() -> Vec<String> {
+    match TAIL.lock().unwrap().as_ref() {
+        Some(tail) => tail.iter().cloned().collect(),
+        None => Vec::new(),
     }
 }
 
-pub(crate) fn init_logging() {
+// Oldest first
+pub(crate) fn recent_errors() -> Vec<LoggedError> {
+    ERRORS.lock().unwrap().iter().cloned().collect()
+}
+
+fn keep_error(record: &Record) {
+    let mut errors = ERRORS.lock().unwrap();
+    if errors.len() == ERRORS_LENGTH {
+        errors.pop_front();
+    }
+    errors.push_back(LoggedError {
+        time: SystemTime::now(),
+        level: record.level(),
+        message: record.args().to_string(),
+    });
+}
+
+// Time of day in UTC, like the log lines themselves
+pub(crate) fn clock(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub(crate) fn init_logging(config: &LogConfig) -> std::io::Result<()> {
     // Set up logging
-    let level = get_log_level();
-    println!("Log level: {}", level);
+    let terminal_level = level(&config.level);
+    println!("Log level: {}", terminal_level);
+    let file_level = level(&config.file_level);
+    let debug_level = level(&config.debug_level);
+    FILE_LEVEL.store(file_level.max(debug_level) as usize, Ordering::Relaxed);
+    // The console's line editor logs every key pressed at debug
+    let logger_config = ConfigBuilder::new()
+        .add_filter_ignore_str("rustyline")
+        .build();
     CombinedLogger::init(vec![
-        TermLogger::new(
-            level,
-            Config::default(),
+        Box::new(Terminal(TermLogger::new(
+            LevelFilter::Trace,
+            logger_config.clone(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            std::fs::File::create("server.log").unwrap(),
-        ),
-        WriteLogger::new(
-            LevelFilter::Debug,
-            Config::default(),
-            std::fs::File::create("debug.log").unwrap(),
-        ),
+        ))),
+        WriteLogger::new(file_level, logger_config.clone(), create(&config.file)?),
+        WriteLogger::new(debug_level, logger_config, create(&config.debug_file)?),
     ])
-    .unwrap();
-}
\ No newline at end of file
+    .map_err(std::io::Error::other)?;
+    set_terminal_level(terminal_level);
+    Ok(())
+}
+
+// The terminal logger, filtered by whatever level is current
+struct Terminal(Box<TermLogger>);
+
+impl Log for Terminal {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= terminal_level() && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= Level::Warn {
+            keep_error(record);
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(tail) = TAIL.lock().unwrap().as_mut() {
+            if tail.len() == TAIL_LENGTH {
+                tail.pop_front();
+            }
+            tail.push_back(format!(
+                "{} [{}] {}",
+                clock(SystemTime::now()),
+                record.level(),
+                record.args()
+            ));
+            return;
+        }
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+impl SharedLogger for Terminal {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    fn config(&self) -> Option<&Config> {
+        self.0.config()
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+fn create(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    std::fs::File::create(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Failed to create log file {}: {}", path.display(), e),
+        )
+    })
+}
+
+// Format bytes as a classic offset / hex / ascii dump for the logs
+pub(crate) fn hexdump(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        output.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    output
+}
@@ -1,170 +1,335 @@
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use tokio::net::TcpListener;
 use tokio::sync::watch;
 #[macro_use]
 extern crate log as log_crate;
 extern crate simplelog;
 
-use crate::{log::init_logging, net::handle_client};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
 
+use clap::Parser;
+
+use crate::capture::Capture;
+use crate::config::{Cli, Command, Config};
+use crate::console::Console;
+use crate::dispatch::Server;
+use crate::keys::KeyManager;
+use crate::net::{serve, stopped};
+use crate::state::State;
+use crate::store::account::AccountStore;
+use crate::{log::init_logging, parser::handlers};
+
+mod admin;
+mod capture;
+mod codec;
+mod config;
+mod console;
+mod crypto;
+mod dashboard;
+mod dispatch;
+mod inspect;
+mod keys;
 mod log;
 mod net;
 mod packet;
 mod parser;
+mod replay;
+mod state;
+mod store;
+#[cfg(test)]
+mod test_client;
+mod web;
 
-fn print_help() {
-    println!("Help:");
-    println!("? - Print this help");
-    println!("x - Quit");
+async fn bind(address: SocketAddr) -> std::io::Result<TcpListener> {
+    match TcpListener::bind(address).await {
+        Ok(listener) => {
+            debug!("Listening on {}", address);
+            Ok(listener)
+        }
+        Err(e) => Err(std::io::Error::new(
+            e.kind(),
+            format!("Failed to listen on {}: {}", address, e),
+        )),
+    }
 }
 
-enum Keys {
-    None,
-    Quit,
+fn load_keys(config: &Config) -> std::io::Result<KeyManager> {
+    KeyManager::load(&config.keys).map_err(|()| {
+        std::io::Error::other(format!(
+            "Failed to load the private key {}",
+            config.keys.private_key.display()
+        ))
+    })
 }
 
-fn check_for_key() -> Keys {
-    // Set stdin to non-blocking
-    match enable_raw_mode() {
-        Ok(_) => {}
-        Err(_) => {
-            error!("Failed to set stdin to non-blocking");
-        }
-    }
+// `npsmc replay` checks the handlers still answer a captured session the same way
+async fn replay_main(capture: &Path, config: Config) -> std::io::Result<()> {
+    let accounts = match AccountStore::open_in_memory() {
+        Ok(accounts) => accounts,
+        Err(()) => return Err(std::io::Error::other("Failed to open the account database")),
+    };
+    let keys = load_keys(&config)?;
+    let state = Arc::new(State::new(
+        handlers(),
+        accounts,
+        Capture::new(&config.capture.path.to_string_lossy()),
+        config,
+        keys,
+    ));
 
-    // Poll stdin for input
-    if crossterm::event::poll(std::time::Duration::from_millis(100)).unwrap() {
-        match crossterm::event::read().unwrap() {
-            crossterm::event::Event::Key(key) => match key.code {
-                crossterm::event::KeyCode::Char('x') => {
-                    disable_raw_mode().unwrap();
-                    println!("Quitting");
-                    return Keys::Quit;
-                }
-                crossterm::event::KeyCode::Char('?') => {
-                    disable_raw_mode().unwrap();
-                    print_help();
-                }
-                _ => {
-                    disable_raw_mode().unwrap();
-                    // Swallow the key
-                    return Keys::None;
-                }
-            },
-            _ => {}
-        }
-    }
-
-    // Set stdin back to blocking
-    match disable_raw_mode() {
-        Ok(_) => {}
-        Err(_) => {
-            error!("Failed to set stdin back to blocking");
-        }
+    match replay::run(&capture.to_string_lossy(), state).await {
+        Ok(true) => Ok(()),
+        Ok(false) => std::process::exit(1),
+        Err(()) => Err(std::io::Error::other("Replay failed")),
     }
-    return Keys::None;
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let login_port = 8226;
-    let persona_port = 8228;
-    let lobby_port = 7003;
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref(), &cli.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(Command::Replay { capture }) = &cli.command {
+        return replay_main(capture, config).await;
+    }
+
+    // Without a terminal there is nobody to press keys
+    let headless = config.headless || !std::io::stdin().is_terminal();
 
-    init_logging();
+    init_logging(&config.log)?;
 
     println!("Welcome to the Rusty Motors Server");
+    if headless && !config.headless {
+        warn!("stdin is not a terminal, running headless");
+    }
+
+    let listeners = &config.listeners;
+    let login_listener = bind(listeners.login).await?;
+    let persona_listener = bind(listeners.persona).await?;
+    let lobby_listener = bind(listeners.lobby).await?;
+    let transaction_listener = bind(listeners.transaction).await?;
+    let web_listener = bind(listeners.web).await?;
+    // Operators' scripts and web panels, only with a token to check
+    let admin_listener = if config.admin.enabled() {
+        Some(bind(config.admin.listen).await?)
+    } else {
+        None
+    };
+
+    let accounts = match AccountStore::open(&config.database) {
+        Ok(accounts) => accounts,
+        Err(()) => return Err(std::io::Error::other("Failed to open the account database")),
+    };
+    let capture = Capture::new(&config.capture.path.to_string_lossy());
+    if config.capture.enabled && capture.enable().is_err() {
+        return Err(std::io::Error::other("Failed to open the capture file"));
+    }
+    let keys = load_keys(&config)?;
+    let state = Arc::new(State::new(handlers(), accounts, capture, config, keys));
 
-    // Print help
-    print_help();
+    // Pick up a replaced private key without a restart
+    #[cfg(unix)]
+    {
+        let keys_state = state.clone();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading the private key");
+                if keys_state.keys.reload(&keys_state.config().keys).is_err() {
+                    error!("Keeping the private key we already had");
+                }
+            }
+        });
 
-    let login_listener = TcpListener::bind(("0.0.0.0", login_port)).await?;
-    debug!("Listening on port {}", login_port);
-    let persona_listener = TcpListener::bind(("0.0.0.0", persona_port)).await?;
-    debug!("Listening on port {}", persona_port);
-    let lobby_listener = TcpListener::bind(("0.0.0.0", lobby_port)).await?;
-    debug!("Listening on port {}", lobby_port);
+        // What the console's `capture` does, for when there is no console
+        let capture_state = state.clone();
+        let mut user1 =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while user1.recv().await.is_some() {
+                info!("SIGUSR1 received, toggling capture");
+                if !capture_state.capture.toggle() {
+                    info!("Not capturing traffic");
+                }
+            }
+        });
+    }
 
     let (tx, rx) = watch::channel(true);
+    let tx = Arc::new(tx);
+
+    // Signals shut down the same way the console does
+    let signal_tx = tx.clone();
+    tokio::spawn(async move {
+        match shutdown_signal().await {
+            Ok(signal) => info!("{} received, shutting down", signal),
+            Err(e) => {
+                error!("Failed to listen for shutdown signals: {}", e);
+                return;
+            }
+        }
+        signal_tx.send_replace(false);
+    });
 
     let login_rx = rx.clone();
     let persona_rx = rx.clone();
     let lobby_rx = rx.clone();
+    let transaction_rx = rx.clone();
 
-    // Spawn listeners
+    // Periodically forget sessions nobody is using any more
+    let sessions_state = state.clone();
     tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
         loop {
-            if !*login_rx.borrow() {
-                debug!("Login listener shutting down");
-                break;
-            }
-
-            let login_result = login_listener.accept().await;
-            if let Err(e) = login_result {
-                error!("Failed to accept login connection: {}", e);
-                continue;
-            }
-            if let Ok((socket, _)) = login_result {
-                debug!("Login connection");
-                tokio::spawn(handle_client(socket, "login"));
+            interval.tick().await;
+            let purged = sessions_state.sessions.purge_expired();
+            if purged > 0 {
+                debug!("Purged {} expired session(s)", purged);
             }
         }
     });
 
-    let persona_handle = tokio::spawn(async move {
-        loop {
-            if !*persona_rx.borrow() {
-                debug!("Persona listener shutting down");
-                break;
-            }
-
-            let persona_result = persona_listener.accept().await;
-            if let Err(e) = persona_result {
-                error!("Failed to accept persona connection: {}", e);
-                continue;
-            }
-            if let Ok((socket, _)) = persona_result {
-                debug!("Persona connection");
-                tokio::spawn(handle_client(socket, "persona"));
-            }
+    // The client fetches its ticket and the shard list over HTTP first
+    let web_state = state.clone();
+    let web_rx = rx.clone();
+    let web = tokio::spawn(async move {
+        if let Err(e) = web::serve(web_listener, web_state, web_rx).await {
+            error!("Web server failed: {}", e);
         }
     });
 
-    let lobby_handle = tokio::spawn(async move {
-        loop {
-            if !*lobby_rx.borrow() {
-                debug!("Lobby listener shutting down");
-                break;
+    let admin = admin_listener.map(|listener| {
+        let admin_state = state.clone();
+        let admin_rx = rx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, admin_state, admin_rx).await {
+                error!("Admin API failed: {}", e);
             }
+        })
+    });
 
-            let lobby_result = lobby_listener.accept().await;
-            if let Err(e) = lobby_result {
-                error!("Failed to accept lobby connection: {}", e);
-                continue;
-            }
-            if let Ok((socket, _)) = lobby_result {
-                debug!("Lobby connection");
-                tokio::spawn(handle_client(socket, "lobby"));
-            }
+    let login_state = state.clone();
+    let persona_state = state.clone();
+    let lobby_state = state.clone();
+    let transaction_state = state.clone();
+
+    // Spawn listeners
+    let listeners = [
+        tokio::spawn(serve(login_listener, Server::Login, login_state, login_rx)),
+        tokio::spawn(serve(
+            persona_listener,
+            Server::Persona,
+            persona_state,
+            persona_rx,
+        )),
+        tokio::spawn(serve(lobby_listener, Server::Lobby, lobby_state, lobby_rx)),
+        tokio::spawn(serve(
+            transaction_listener,
+            Server::Transaction,
+            transaction_state,
+            transaction_rx,
+        )),
+    ];
+
+    let mut terminal = None;
+    let mut dashboard = None;
+    if headless {
+        info!("Running headless, stop with SIGINT or SIGTERM");
+    } else if state.config().dashboard {
+        dashboard = dashboard::start(state.clone(), tx.clone());
+    } else {
+        terminal = Some(console::start(Console::new(
+            state.clone(),
+            tx.clone(),
+            cli.config,
+            cli.overrides,
+        )));
+    }
+    stopped(&mut rx.clone()).await;
+    // The dashboard gives the terminal back within a refresh
+    if let Some(dashboard) = dashboard {
+        if dashboard.join().is_err() {
+            error!("The dashboard panicked");
         }
-    });
+    }
+    drop(terminal);
 
-    // Main loop
-    loop {
-        // Check for input
-        match check_for_key() {
-            Keys::Quit => {
-                tx.send(false).unwrap();
-                break;
-            }
-            _ => {}
+    println!("Server shutting down");
+
+    // Everything still running gets the one grace period between them
+    let grace_period = state.config().shutdown.grace_period();
+    let deadline = tokio::time::Instant::now() + grace_period;
+
+    // Listeners stop as soon as they see the signal. Wait for them so no
+    // connection slips in after we start counting.
+    for listener in listeners {
+        let _ = listener.await;
+    }
+
+    // The HTTP servers finish the requests they are in the middle of
+    let http = async {
+        let _ = web.await;
+        if let Some(admin) = admin {
+            let _ = admin.await;
         }
+    };
+    if tokio::time::timeout_at(deadline, http).await.is_err() {
+        warn!(
+            "Dropping HTTP requests still running after {:?}",
+            grace_period
+        );
+    }
 
-        // Sleep for a bit
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // Connected clients have been told to go, give them a moment to finish
+    state.tasks.close();
+    if !state.tasks.is_empty() {
+        info!(
+            "Waiting up to {:?} for {} connection(s) to close",
+            deadline.saturating_duration_since(tokio::time::Instant::now()),
+            state.tasks.len()
+        );
+    }
+    if tokio::time::timeout_at(deadline, state.tasks.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Dropping {} connection(s) still open after {:?}",
+            state.tasks.len(),
+            grace_period
+        );
     }
 
-    println!("Server shutting down");
+    // Accounts are written as they change, only the capture and logs are buffered
+    state.capture.disable();
+    log_crate::logger().flush();
 
     Ok(())
 }
+
+// Resolves with the name of the first SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
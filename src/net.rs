@@ -1,111 +1,619 @@
 // Desc: Network code
 
-use crossterm::terminal::disable_raw_mode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use futures::{SinkExt, StreamExt};
+use tokio::sync::watch;
+use tokio::time::{timeout, Instant};
+use tokio_util::codec::Framed;
+
+use crate::capture::Direction;
+use crate::codec::{HeaderKind, McotsCodec, NpsCodec};
+use crate::crypto::Ciphers;
+use crate::dispatch::{encode, Connection, HandlerResult, Server};
+use crate::packet::error::{ErrorResponse, LOGOFF_KICKED, LOGOFF_SERVER_SHUTDOWN};
 use crate::packet::header::Header;
-use crate::parser::user_login::handle_user_login;
-use tokio::net::TcpStream;
+use crate::packet::mcots::{
+    message_id, McotsHeader, McotsStatus, MCOTS_FLAG_ENCRYPTED, MCOTS_HEADER_SIZE,
+};
+use crate::packet::Decode;
+use crate::state::State;
+use crate::store::connection::Control;
+use tokio::net::{TcpListener, TcpStream};
+
+// How long a connection may sit idle between packets before we hang up
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
 
-#[derive(Debug)]
-enum PacketId {
-    LoginRequest = 0x501,
+// How long we wait for the rest of a packet once its header has arrived
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Resolves once the server is shutting down, or whoever started it has gone away
+pub(crate) async fn stopped(running: &mut watch::Receiver<bool>) {
+    let _ = running.wait_for(|running| !running).await;
 }
 
-impl PacketId {
-    fn from_u16(value: u16) -> Option<PacketId> {
-        match value {
-            0x501 => Some(PacketId::LoginRequest),
-            _ => None,
+// Accept connections for one listener until told to stop.
+// The listener is closed as soon as the signal arrives.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    server: Server,
+    state: Arc<State>,
+    mut running: watch::Receiver<bool>,
+) {
+    state.connections.set_listening(server, true);
+    loop {
+        let accepted = tokio::select! {
+            _ = stopped(&mut running) => {
+                debug!("{} listener shutting down", server);
+                break;
+            }
+            accepted = listener.accept() => accepted,
+        };
+
+        state.connections.accepted(server, accepted.is_ok());
+        match accepted {
+            Ok((socket, _)) => {
+                debug!("{} connection", server);
+                let running = running.clone();
+                match server {
+                    Server::Transaction => {
+                        state.tasks.spawn(handle_transaction_client(
+                            socket,
+                            state.clone(),
+                            running,
+                        ));
+                    }
+                    _ => {
+                        state
+                            .tasks
+                            .spawn(handle_client(socket, server, state.clone(), running));
+                    }
+                }
+            }
+            Err(e) => error!("Failed to accept {} connection: {}", server, e),
         }
     }
+    state.connections.set_listening(server, false);
 }
 
-// Handle a client
-pub(crate) async fn handle_client(mut stream: TcpStream, server_name: &str) -> Result<(), ()> {
-    let mut buffer = [0; 4];
-    let header: Header;
-    disable_raw_mode().unwrap();
-
-    // Log the connection
-    info!(
-        "Connection from {} to {}",
-        stream.peer_addr().unwrap(),
-        server_name
-    );
+// When the last whole frame arrived, and when we first saw part of the next one
+struct ReadDeadlines {
+    last_frame: Instant,
+    partial_since: Option<Instant>,
+}
 
-    // Read the header
-    match stream.read_exact(&mut buffer).await {
-        Ok(_) => {
-            header = Header::from_bytes(&buffer);
-            debug!("Loading header: {:?}", header);
-        }
-        Err(_) => {
-            error!("Failed to read header");
-            return Err(());
+impl ReadDeadlines {
+    fn new() -> ReadDeadlines {
+        ReadDeadlines {
+            last_frame: Instant::now(),
+            partial_since: None,
         }
     }
 
-    // Check the header
-    if header.id != 0x501 {
-        error!("Invalid header id: {}", header.id);
-        return Err(());
+    fn received(&mut self) {
+        self.last_frame = Instant::now();
+        self.partial_since = None;
     }
 
-    // Read the rest of the packet
-    let mut packet_buffer = vec![0; header.length as usize - 4];
-    match stream.read_exact(&mut packet_buffer).await {
-        Ok(_) => {
-            debug!("Loading packet: {}", hex::encode(&packet_buffer));
+    // READ_TIMEOUT went by without a whole frame. Returns how to hang up, if
+    // it is time to: an idle client is closed cleanly, a stalled frame is an error.
+    // A part frame only gets so long to finish.
+    fn expired(&mut self, buffered: bool) -> Option<Result<(), ()>> {
+        if !buffered {
+            self.partial_since = None;
+            if self.last_frame.elapsed() >= IDLE_TIMEOUT {
+                info!("Connection idle for {:?}, closing", IDLE_TIMEOUT);
+                return Some(Ok(()));
+            }
+            return None;
         }
-        Err(_) => {
-            error!("Failed to read packet");
-            return Err(());
+        match self.partial_since {
+            Some(since) if since.elapsed() >= READ_TIMEOUT => {
+                error!("Timed out reading packet body");
+                Some(Err(()))
+            }
+            Some(_) => None,
+            None => {
+                self.partial_since = Some(Instant::now());
+                None
+            }
         }
     }
+}
+
+// Hands out a unique id to every connection
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Handle a client
+pub(crate) async fn handle_client(
+    stream: TcpStream,
+    server: Server,
+    state: Arc<State>,
+    mut running: watch::Receiver<bool>,
+) -> Result<(), ()> {
+    let mut connection = new_connection(&stream, server, state.clone());
+    let mut control = state.connections.register(&connection);
+    let _registered = Registered {
+        state: &state,
+        id: connection.id,
+    };
 
-    // Combine the header and packet
-    let mut packet = header.to_bytes();
-    packet.append(&mut packet_buffer);
+    let mut framed = Framed::new(stream, NpsCodec::new(HeaderKind::for_server(server)));
 
-    let packet_type: &str;
+    let mut deadlines = ReadDeadlines::new();
 
-    // Check if thos packet has a known id
-    match PacketId::from_u16(header.id) {
-        Some(PacketId::LoginRequest) => {
-            info!("Login request");
-            packet_type = "LoginRequest";
+    // Keep reading packets until the client hangs up
+    loop {
+        let next = tokio::select! {
+            _ = stopped(&mut running) => {
+                info!("Disconnecting connection {} from {}, shutting down", connection.id, connection.peer);
+                log_off(&mut framed, &connection, LOGOFF_SERVER_SHUTDOWN).await;
+                return Ok(());
+            }
+            Some(control) = control.recv() => match control {
+                Control::Kick => {
+                    info!("Kicking connection {} from {}", connection.id, connection.peer);
+                    log_off(&mut framed, &connection, LOGOFF_KICKED).await;
+                    return Ok(());
+                }
+                Control::Send(frame) => {
+                    record(&connection, Direction::Unsolicited, &frame);
+                    if let Err(e) = framed.send(frame).await {
+                        error!("Failed to send packet: {}", e);
+                        return Err(());
+                    }
+                    continue;
+                }
+            },
+            next = timeout(READ_TIMEOUT, framed.next()) => next,
+        };
+        let packet = match next {
+            Ok(Some(Ok(packet))) => packet,
+            Ok(Some(Err(e))) => {
+                error!("Failed to read packet: {}", e);
+                return Err(());
+            }
+            Ok(None) => {
+                info!(
+                    "Connection {} from {} to {} closed",
+                    connection.id, connection.peer, server
+                );
+                return Ok(());
+            }
+            Err(_) => match deadlines.expired(!framed.read_buffer().is_empty()) {
+                Some(result) => return result,
+                None => continue,
+            },
+        };
+        deadlines.received();
+        record(&connection, Direction::Inbound, &packet);
+
+        let response_packets = handle_packet(&mut connection, &packet).await?;
+        state.connections.update(&connection);
+
+        // Send response packets
+        for response_packet in response_packets {
+            debug!("Sending packet: {}", hex::encode(&response_packet));
+            record(&connection, Direction::Outbound, &response_packet);
+            if let Err(e) = framed.feed(response_packet).await {
+                error!("Failed to send packet: {}", e);
+                return Err(());
+            }
         }
-        None => {
-            error!("Unknown packet id: {}", header.id);
-            debug!("Packet: {}", hex::encode(packet));
+        if let Err(e) = framed.flush().await {
+            error!("Failed to send packet: {}", e);
             return Err(());
         }
+
+        // A handler finished the handshake, encrypt from here on.
+        // Frames the client already sent are decoded lazily, so they get decrypted too.
+        if !framed.codec().is_encrypted() {
+            if let Some(session_key) = &connection.session_key {
+                framed.codec_mut().enable(session_key)?;
+                debug!("Connection {} is now encrypted", connection.id);
+            }
+        }
     }
+}
 
-    // If known packet type, load into appropriate struct
-    match packet_type {
-        "LoginRequest" => {
-            let response_packets: Vec<Vec<u8>> = handle_user_login(packet.as_slice()).await?;
-            debug!("Loading packet type: {}", packet_type);
+// Frames go to the capture file and the dashboard, whichever is listening
+fn record(connection: &Connection, direction: Direction, frame: &[u8]) {
+    let state = &connection.state;
+    state.capture.record(connection, direction, frame);
+    state.feed.record(connection, direction, frame);
+}
 
-            // Send response packets
-            for response_packet in response_packets {
-                debug!("Sending packet: {}", hex::encode(&response_packet));
-                match stream.write_all(&response_packet).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        error!("Failed to send packet");
-                        return Err(());
+// Tell the client why we are hanging up. It may already be gone, which is fine.
+async fn log_off(framed: &mut Framed<TcpStream, NpsCodec>, connection: &Connection, reason: u32) {
+    let Ok(logoff) = encode(&ErrorResponse::logoff(reason)) else {
+        return;
+    };
+    record(connection, Direction::Unsolicited, &logoff);
+    if let Err(e) = framed.send(logoff).await {
+        debug!(
+            "Failed to say goodbye to connection {}: {}",
+            connection.id, e
+        );
+    }
+}
+
+// Everything `handle_client` does with a packet once it is off the wire.
+// Takes and returns plaintext frames, so captures can be replayed through it.
+pub(crate) async fn handle_packet(connection: &mut Connection, packet: &[u8]) -> HandlerResult {
+    let header = Header::from_bytes(packet).map_err(|e| error!("Failed to parse header: {}", e))?;
+    let state = connection.state.clone();
+    state.handlers.dispatch(connection, header.id, packet).await
+}
+
+// The MCOTS counterpart of `handle_packet`, takes and returns plaintext bodies
+pub(crate) async fn handle_transaction_packet(
+    connection: &mut Connection,
+    body: &[u8],
+) -> HandlerResult {
+    let id = message_id(body)
+        .map_err(|e| error!("Message too short for an id: {}: {}", e, hex::encode(body)))?;
+    let state = connection.state.clone();
+    state.handlers.dispatch(connection, id, body).await
+}
+
+// Handle a client of the transaction server, which frames messages with MCOTS
+pub(crate) async fn handle_transaction_client(
+    stream: TcpStream,
+    state: Arc<State>,
+    mut running: watch::Receiver<bool>,
+) -> Result<(), ()> {
+    let mut connection = new_connection(&stream, Server::Transaction, state.clone());
+    let mut control = state.connections.register(&connection);
+    let _registered = Registered {
+        state: &state,
+        id: connection.id,
+    };
+    let mut ciphers: Option<Ciphers> = None;
+    let mut sequence: u32 = 0;
+
+    // Frames are read through the codec's buffer, so a control message that
+    // arrives part way through one doesn't lose the bytes already read
+    let mut framed = Framed::new(stream, McotsCodec);
+    let mut deadlines = ReadDeadlines::new();
+
+    loop {
+        // A half read frame is thrown away on shutdown, the connection is closing anyway
+        let next = tokio::select! {
+            _ = stopped(&mut running) => {
+                info!("Disconnecting connection {} from {}, shutting down", connection.id, connection.peer);
+                let logout = encode(&McotsStatus::logout())?;
+                record(&connection, Direction::Unsolicited, &logout);
+                sequence = sequence.wrapping_add(1);
+                let frame = mcots_frame(logout, sequence, ciphers.as_mut())?;
+                if let Err(e) = framed.send(frame).await {
+                    debug!("Failed to say goodbye to connection {}: {}", connection.id, e);
+                }
+                return Ok(());
+            }
+            Some(control) = control.recv() => {
+                let (body, hang_up) = match control {
+                    Control::Kick => {
+                        info!("Kicking connection {} from {}", connection.id, connection.peer);
+                        (encode(&McotsStatus::logout())?, true)
                     }
+                    Control::Send(body) => (body, false),
+                };
+                record(&connection, Direction::Unsolicited, &body);
+                sequence = sequence.wrapping_add(1);
+                let frame = mcots_frame(body, sequence, ciphers.as_mut())?;
+                if let Err(e) = framed.send(frame).await {
+                    error!("Failed to send packet: {}", e);
+                    return Err(());
                 }
+                if hang_up {
+                    return Ok(());
+                }
+                continue;
+            }
+            next = timeout(READ_TIMEOUT, framed.next()) => next,
+        };
+        let (header, body) = match next {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(e))) => {
+                error!("Failed to read packet: {}", e);
+                return Err(());
+            }
+            Ok(None) => {
+                info!(
+                    "Connection {} from {} to {} closed",
+                    connection.id, connection.peer, connection.server
+                );
+                return Ok(());
+            }
+            Err(_) => match deadlines.expired(!framed.read_buffer().is_empty()) {
+                Some(result) => return result,
+                None => continue,
+            },
+        };
+        deadlines.received();
+
+        // Once the key is known every message has to be encrypted, or anyone
+        // on the path could slip plaintext commands into the session
+        let body = match (header.is_encrypted(), ciphers.as_mut()) {
+            (true, Some(ciphers)) => ciphers.decrypt_command(&body)?,
+            (false, None) => body,
+            (true, None) => {
+                error!("Encrypted message before the session key was known");
+                return Err(());
+            }
+            (false, Some(_)) => {
+                error!(
+                    "Plaintext message on encrypted connection {}, hanging up",
+                    connection.id
+                );
+                return Err(());
+            }
+        };
+        record(&connection, Direction::Inbound, &body);
+
+        let response_bodies = handle_transaction_packet(&mut connection, &body).await?;
+        state.connections.update(&connection);
+
+        for response_body in response_bodies {
+            record(&connection, Direction::Outbound, &response_body);
+            sequence = sequence.wrapping_add(1);
+            let frame = mcots_frame(response_body, sequence, ciphers.as_mut())?;
+            debug!("Sending packet: {}", hex::encode(&frame));
+            if let Err(e) = framed.feed(frame).await {
+                error!("Failed to send packet: {}", e);
+                return Err(());
             }
         }
-        _ => {
-            error!("Unknown packet type: {}", packet_type);
-            debug!("Packet: {}", hex::encode(packet));
+        if let Err(e) = framed.flush().await {
+            error!("Failed to send packet: {}", e);
+            return Err(());
+        }
+
+        // A handler finished the handshake, messages may be encrypted from here on
+        if ciphers.is_none() {
+            if let Some(session_key) = &connection.session_key {
+                ciphers = Some(Ciphers::from_session_key(session_key)?);
+                debug!("Connection {} is now encrypted", connection.id);
+            }
+        }
+    }
+}
+
+// Takes a connection out of the registry however its task ends
+struct Registered<'a> {
+    state: &'a State,
+    id: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.state.connections.remove(self.id);
+    }
+}
+
+fn new_connection(stream: &TcpStream, server: Server, state: Arc<State>) -> Connection {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer.to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+
+    let connection = Connection::new(
+        NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        server,
+        peer,
+        state,
+    );
+
+    // Log the connection
+    info!(
+        "Connection {} from {} to {}",
+        connection.id, connection.peer, server
+    );
+    connection
+}
+
+// Wrap a message body in an MCOTS header, encrypting it if we can
+fn mcots_frame(
+    mut body: Vec<u8>,
+    sequence: u32,
+    ciphers: Option<&mut Ciphers>,
+) -> Result<Vec<u8>, ()> {
+    let mut flags = 0;
+    if let Some(ciphers) = ciphers {
+        // DES works on whole blocks
+        body.resize(body.len().next_multiple_of(8), 0);
+        body = ciphers.encrypt_command(&body)?;
+        flags |= MCOTS_FLAG_ENCRYPTED;
+    }
+    // The length field is 16 bits and covers the header too
+    let length = match u16::try_from(MCOTS_HEADER_SIZE + body.len()) {
+        Ok(length) => length,
+        Err(_) => {
+            error!("MCOTS body too long for one frame: {} bytes", body.len());
             return Err(());
         }
+    };
+    let header = McotsHeader {
+        length,
+        sequence,
+        flags,
+    };
+    let mut frame = encode(&header)?;
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::read_capture;
+    use crate::packet::mcots::{
+        MCOTS_FLAG_ENCRYPTED, MCOTS_SIGNATURE, MC_CLIENT_CONNECT_MSG, MC_LOGOUT,
+    };
+    use crate::packet::Encode;
+    use crate::test_client::test_state;
+    use bytes::BytesMut;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Decoder;
+
+    const SESSION_KEY: [u8; 32] = [0x42; 32];
+
+    // Decode a whole capture of the stream, the way the listener reads it
+    fn read_mcots_frame(bytes: &[u8]) -> std::io::Result<Option<(McotsHeader, Vec<u8>)>> {
+        McotsCodec.decode_eof(&mut BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn mcots_frames_read_back() {
+        let frame = mcots_frame(vec![0xac, 0x00, 1, 0, 0, 0], 7, None).unwrap();
+        assert_eq!(&frame[..2], 17u16.to_le_bytes());
+        assert_eq!(&frame[2..6], MCOTS_SIGNATURE);
+        assert_eq!(&frame[6..10], 7u32.to_le_bytes());
+        assert_eq!(frame[10], 0);
+
+        let (header, body) = read_mcots_frame(&frame).unwrap().unwrap();
+        assert_eq!((header.length, header.sequence), (17, 7));
+        assert!(!header.is_encrypted());
+        assert_eq!(body, [0xac, 0x00, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encrypted_mcots_frames_are_padded() {
+        let mut server = Ciphers::from_session_key(&SESSION_KEY).unwrap();
+        let mut client = Ciphers::from_session_key(&SESSION_KEY).unwrap();
+        let frame = mcots_frame(vec![0x65, 0x00, 0xb6, 0x01], 1, Some(&mut server)).unwrap();
+        assert_eq!(frame[10], MCOTS_FLAG_ENCRYPTED);
+
+        let (header, body) = read_mcots_frame(&frame).unwrap().unwrap();
+        assert!(header.is_encrypted());
+        assert_eq!(
+            client.decrypt_command(&body).unwrap(),
+            [0x65, 0x00, 0xb6, 0x01, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn oversize_mcots_bodies_are_refused() {
+        let largest = u16::MAX as usize - MCOTS_HEADER_SIZE;
+        assert!(mcots_frame(vec![0; largest], 1, None).is_ok());
+        assert!(mcots_frame(vec![0; largest + 1], 1, None).is_err());
+    }
+
+    #[test]
+    fn bad_mcots_frames_are_refused() {
+        // A clean close between frames
+        assert!(read_mcots_frame(&[]).unwrap().is_none());
+
+        let frame = mcots_frame(vec![0xac, 0x00], 1, None).unwrap();
+        let mut wrong_signature = frame.clone();
+        wrong_signature[2] = b'X';
+        assert!(read_mcots_frame(&wrong_signature).is_err());
+
+        let mut too_short = frame.clone();
+        too_short[..2].copy_from_slice(&10u16.to_le_bytes());
+        assert!(read_mcots_frame(&too_short).is_err());
+
+        // The client hung up part way through the body
+        assert!(read_mcots_frame(&frame[..12]).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn transaction_frames_are_captured() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let state = test_state(Some(path));
+        state.capture.enable().unwrap();
+
+        let (_running, rx) = watch::channel(true);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Server::Transaction, state.clone(), rx));
+
+        let mut client = Framed::new(TcpStream::connect(address).await.unwrap(), McotsCodec);
+        let logout = MC_LOGOUT.to_le_bytes().to_vec();
+        client
+            .send(mcots_frame(logout.clone(), 1, None).unwrap())
+            .await
+            .unwrap();
+        let (_, reply) = client.next().await.unwrap().unwrap();
+        state.capture.disable();
+
+        let records = read_capture(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .all(|record| record.server == Server::Transaction));
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[0].frame, logout);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert_eq!(records[1].frame, reply);
+    }
+
+    #[tokio::test]
+    async fn plaintext_after_the_handshake_disconnects() {
+        let state = test_state(None);
+        let customer_id = state
+            .accounts
+            .create("racer", "password")
+            .unwrap()
+            .customer_id;
+        let persona = state.personas.create(customer_id, "Racer", 44).unwrap();
+        state
+            .sessions
+            .insert(customer_id, 1, "127.0.0.1:1", SESSION_KEY.to_vec());
+
+        let (_running, rx) = watch::channel(true);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Server::Transaction, state, rx));
+
+        let mut client = Framed::new(TcpStream::connect(address).await.unwrap(), McotsCodec);
+        let mut connect = MC_CLIENT_CONNECT_MSG.to_le_bytes().to_vec();
+        connect.extend_from_slice(&customer_id.to_le_bytes());
+        connect.extend_from_slice(&persona.id.to_le_bytes());
+        client
+            .send(mcots_frame(connect, 1, None).unwrap())
+            .await
+            .unwrap();
+        let (_, reply) = client.next().await.unwrap().unwrap();
+        assert_eq!(
+            reply,
+            McotsStatus::success(MC_CLIENT_CONNECT_MSG)
+                .to_bytes()
+                .unwrap()
+        );
+
+        let logout = MC_LOGOUT.to_le_bytes().to_vec();
+        client
+            .send(mcots_frame(logout, 2, None).unwrap())
+            .await
+            .unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn control_messages_keep_part_read_frames() {
+        let state = test_state(None);
+        let (_running, rx) = watch::channel(true);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Server::Transaction, state.clone(), rx));
+
+        let mut client = Framed::new(TcpStream::connect(address).await.unwrap(), McotsCodec);
+        let logout = mcots_frame(MC_LOGOUT.to_le_bytes().to_vec(), 1, None).unwrap();
+        client.get_mut().write_all(&logout[..5]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let notice = McotsStatus::logout().to_bytes().unwrap();
+        assert_eq!(state.connections.broadcast(Server::Transaction, &notice), 1);
+        let (_, body) = client.next().await.unwrap().unwrap();
+        assert_eq!(body, notice);
+
+        // The rest of the frame still lines up with what was read before
+        client.get_mut().write_all(&logout[5..]).await.unwrap();
+        let (_, reply) = client.next().await.unwrap().unwrap();
+        assert_eq!(reply, McotsStatus::success(MC_LOGOUT).to_bytes().unwrap());
     }
-    Ok(())
 }
@@ -0,0 +1,101 @@
+use super::header::Header;
+use super::{Decode, Encode};
+
+// Why a packet could not be decoded, or encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PacketError {
+    // Ran out of bytes part way through a field
+    Truncated { needed: usize, available: usize },
+    // A length field disagrees with the bytes we were given
+    BadLength { length: usize, available: usize },
+    // A string wasn't UTF-8, or a marker byte was wrong
+    InvalidEncoding,
+    // The message was longer than its fields
+    TrailingBytes { count: usize },
+    // A versioned header from a protocol version we don't speak
+    UnsupportedVersion { version: u16 },
+    // A value too long for its length field, or a string for its fixed width
+    TooLong { length: usize, limit: usize },
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::Truncated { needed, available } => write!(
+                f,
+                "packet truncated, needed {} bytes but only {} available",
+                needed, available
+            ),
+            PacketError::BadLength { length, available } => write!(
+                f,
+                "length field says {} bytes but {} available",
+                length, available
+            ),
+            PacketError::InvalidEncoding => write!(f, "invalid encoding"),
+            PacketError::TrailingBytes { count } => {
+                write!(f, "{} unexpected trailing bytes", count)
+            }
+            PacketError::UnsupportedVersion { version } => {
+                write!(f, "unsupported protocol version 0x{:04x}", version)
+            }
+            PacketError::TooLong { length, limit } => {
+                write!(f, "{} bytes is longer than the limit of {}", length, limit)
+            }
+        }
+    }
+}
+
+// Sent when the server can't make sense of a message
+pub(crate) const NPS_GENERIC_FAILURE: u16 = 0x0602;
+
+// Sent instead of a user status when a login is refused
+pub(crate) const NPS_LOGIN_FAILED: u16 = 0x0623;
+
+// Sent before we close a connection ourselves
+pub(crate) const NPS_FORCE_LOGOFF: u16 = 0x0218;
+
+// Why we closed the connection
+pub(crate) const LOGOFF_SERVER_SHUTDOWN: u32 = 0x0001;
+pub(crate) const LOGOFF_KICKED: u32 = 0x0002;
+
+// Reasons a login can be refused
+pub(crate) const LOGIN_INVALID_TICKET: u32 = 0x0001;
+pub(crate) const LOGIN_SERVER_ERROR: u32 = 0x0002;
+
+#[derive(Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct ErrorResponse {
+    #[nps(header)]
+    header: Header,
+    // What went wrong, for generic failures this is the id of the request
+    code: u32,
+}
+
+impl ErrorResponse {
+    fn new(id: u16, code: u32) -> ErrorResponse {
+        ErrorResponse {
+            header: Header { id, length: 8 },
+            code,
+        }
+    }
+
+    pub(crate) fn generic(request_id: u16) -> ErrorResponse {
+        ErrorResponse::new(NPS_GENERIC_FAILURE, request_id as u32)
+    }
+
+    pub(crate) fn login_failed(code: u32) -> ErrorResponse {
+        ErrorResponse::new(NPS_LOGIN_FAILED, code)
+    }
+
+    pub(crate) fn logoff(reason: u32) -> ErrorResponse {
+        ErrorResponse::new(NPS_FORCE_LOGOFF, reason)
+    }
+}
+
+impl std::fmt::Debug for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ErrorResponse")
+            .field("id", &format!("0x{:04x}", self.header.id))
+            .field("code", &format!("0x{:04x}", self.code))
+            .finish()
+    }
+}
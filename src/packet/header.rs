@@ -1,32 +1,63 @@
 use byte_struct::*;
 
-#[derive(ByteStruct, Debug)]
+use super::error::PacketError;
+use super::{read_u16, read_u32, u16_length, Decode, Encode};
+
+// What the derive needs to check and fill in a message's length
+pub(crate) trait MessageHeader: Sized {
+    fn length(&self) -> usize;
+
+    // Fails when the message is too long for the header to describe
+    fn with_length(&self, length: usize) -> Result<Self, PacketError>;
+}
+
+#[derive(ByteStruct, Debug, Clone, PartialEq, Eq)]
 #[byte_struct_be]
 pub struct Header {
     pub id: u16,
     pub length: u16,
 }
-impl Header {
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+
+impl Encode for Header {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.id.to_be_bytes());
         bytes.extend_from_slice(&self.length.to_be_bytes());
-        bytes
+        Ok(bytes)
     }
 
-    pub(crate) fn from_bytes(bytes: &[u8]) -> Header {
-        let mut id_bytes = [0; 2];
-        id_bytes.copy_from_slice(&bytes[0..2]);
-        let mut length_bytes = [0; 2];
-        length_bytes.copy_from_slice(&bytes[2..4]);
-        Header {
-            id: u16::from_be_bytes(id_bytes),
-            length: u16::from_be_bytes(length_bytes),
-        }
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn fields(&self) -> Vec<(String, usize)> {
+        vec![("id".to_string(), 2), ("length".to_string(), 2)]
+    }
+}
+
+impl Decode for Header {
+    fn from_bytes(bytes: &[u8]) -> Result<Header, PacketError> {
+        Ok(Header {
+            id: read_u16(bytes, 0)?,
+            length: read_u16(bytes, 2)?,
+        })
     }
 }
 
-#[derive(ByteStruct, Debug)]
+impl MessageHeader for Header {
+    fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    fn with_length(&self, length: usize) -> Result<Header, PacketError> {
+        Ok(Header {
+            id: self.id,
+            length: u16_length(length)?,
+        })
+    }
+}
+
+#[derive(ByteStruct, Debug, Clone, PartialEq, Eq)]
 #[byte_struct_be]
 pub struct VersionedHeader {
     pub id: u16,
@@ -36,26 +67,96 @@ pub struct VersionedHeader {
     checksum: u32,
 }
 
-impl VersionedHeader {
+// The version every login request we have seen carries
+pub(crate) const NPS_VERSION: u16 = 0x0101;
+
+// Versions we know how to parse, anything else is refused
+pub(crate) const SUPPORTED_VERSIONS: [u16; 1] = [NPS_VERSION];
 
+// The client fills the checksum in with the length of the whole message
+fn checksum(length: u16) -> u32 {
+    length as u32
+}
 
-    pub(crate) fn from_bytes(bytes: &[u8]) -> VersionedHeader {
-        let mut id_bytes = [0; 2];
-        id_bytes.copy_from_slice(&bytes[0..2]);
-        let mut length_bytes = [0; 2];
-        length_bytes.copy_from_slice(&bytes[2..4]);
-        let mut version_bytes = [0; 2];
-        version_bytes.copy_from_slice(&bytes[4..6]);
-        let mut reserved_bytes = [0; 2];
-        reserved_bytes.copy_from_slice(&bytes[6..8]);
-        let mut checksum_bytes = [0; 4];
-        checksum_bytes.copy_from_slice(&bytes[8..12]);
+impl VersionedHeader {
+    // An outgoing header, with the checksum computed from the length
+    pub(crate) fn new(id: u16, length: u16) -> VersionedHeader {
         VersionedHeader {
-            id: u16::from_be_bytes(id_bytes),
-            length: u16::from_be_bytes(length_bytes),
-            version: u16::from_be_bytes(version_bytes),
-            reserved: u16::from_be_bytes(reserved_bytes),
-            checksum: u32::from_be_bytes(checksum_bytes),
+            id,
+            length,
+            version: NPS_VERSION,
+            reserved: 0,
+            checksum: checksum(length),
+        }
+    }
+}
+
+impl MessageHeader for VersionedHeader {
+    fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    // A header that already has the right length keeps its checksum,
+    // so decoded messages encode back to the same bytes
+    fn with_length(&self, length: usize) -> Result<VersionedHeader, PacketError> {
+        if self.length as usize == length {
+            return Ok(self.clone());
         }
+        Ok(VersionedHeader {
+            version: self.version,
+            reserved: self.reserved,
+            ..VersionedHeader::new(self.id, u16_length(length)?)
+        })
+    }
+}
+
+impl Encode for VersionedHeader {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.reserved.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        Ok(bytes)
     }
-}
\ No newline at end of file
+
+    fn size(&self) -> usize {
+        12
+    }
+
+    fn fields(&self) -> Vec<(String, usize)> {
+        ["id", "length", "version", "reserved"]
+            .into_iter()
+            .map(|name| (name.to_string(), 2))
+            .chain([("checksum".to_string(), 4)])
+            .collect()
+    }
+}
+
+impl Decode for VersionedHeader {
+    fn from_bytes(bytes: &[u8]) -> Result<VersionedHeader, PacketError> {
+        let header = VersionedHeader {
+            id: read_u16(bytes, 0)?,
+            length: read_u16(bytes, 2)?,
+            version: read_u16(bytes, 4)?,
+            reserved: read_u16(bytes, 6)?,
+            checksum: read_u32(bytes, 8)?,
+        };
+        if !SUPPORTED_VERSIONS.contains(&header.version) {
+            return Err(PacketError::UnsupportedVersion {
+                version: header.version,
+            });
+        }
+        // Not every client is known to fill this in, so a mismatch isn't fatal
+        if header.checksum != checksum(header.length) {
+            warn!(
+                "Checksum mismatch on 0x{:04x}: got 0x{:08x}, expected 0x{:08x}",
+                header.id,
+                header.checksum,
+                checksum(header.length)
+            );
+        }
+        Ok(header)
+    }
+}
@@ -0,0 +1,64 @@
+use super::header::Header;
+use super::{Decode, Encode, PrefixedString};
+use crate::codec::MAX_FRAME_SIZE;
+
+pub(crate) const NPS_LOBBY_LOGIN: u16 = 0x0100;
+pub(crate) const NPS_LOBBY_WELCOME: u16 = 0x0120;
+pub(crate) const NPS_SYSTEM_MESSAGE: u16 = 0x0219;
+
+// 0x100 - first message on the lobby server, names the customer logging in
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct LobbyLogin {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+    pub(crate) persona_id: u32,
+}
+
+// 0x120 - tells the client the lobby accepted it and traffic is now encrypted
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct LobbyWelcome {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+    pub(crate) persona_id: u32,
+}
+
+impl LobbyWelcome {
+    pub(crate) fn new(customer_id: u32, persona_id: u32) -> LobbyWelcome {
+        LobbyWelcome {
+            header: Header {
+                id: NPS_LOBBY_WELCOME,
+                length: 12,
+            },
+            customer_id,
+            persona_id,
+        }
+    }
+}
+
+// The longest text a SystemMessage can carry in one frame, after the
+// header and the string's length prefix
+pub(crate) const MAX_SYSTEM_MESSAGE_LENGTH: usize = MAX_FRAME_SIZE - 4 - 2;
+
+// 0x219 - text from the server operator, shown to everyone in the lobby
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct SystemMessage {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) text: PrefixedString,
+}
+
+impl SystemMessage {
+    pub(crate) fn new(text: &str) -> SystemMessage {
+        SystemMessage {
+            header: Header {
+                id: NPS_SYSTEM_MESSAGE,
+                length: 0,
+            },
+            text: PrefixedString {
+                string: text.to_string(),
+            },
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// MCOTS, the transaction server protocol, is little endian unlike NPS, so
+// its messages implement `Encode` and `Decode` by hand
+
+use super::error::PacketError;
+use super::{read_bytes, u16_length, Decode, Encode};
+
+pub(crate) const MCOTS_SIGNATURE: &[u8; 4] = b"TOMC";
+
+// length (2) + signature (4) + sequence (4) + flags (1)
+pub(crate) const MCOTS_HEADER_SIZE: usize = 11;
+
+// Set when the body is encrypted with the session's command cipher
+pub(crate) const MCOTS_FLAG_ENCRYPTED: u8 = 0x08;
+
+pub(crate) const MC_SUCCESS: u16 = 101;
+pub(crate) const MC_FAILED: u16 = 102;
+pub(crate) const MC_LOGIN: u16 = 105;
+pub(crate) const MC_LOGOUT: u16 = 106;
+pub(crate) const MC_STOCK_CAR_INFO: u16 = 141;
+pub(crate) const MC_GET_OWNED_VEHICLES: u16 = 172;
+pub(crate) const MC_OWNED_VEHICLES_LIST: u16 = 173;
+pub(crate) const MC_CLIENT_CONNECT_MSG: u16 = 438;
+
+#[derive(Debug)]
+pub(crate) struct McotsHeader {
+    // Length of the whole frame, including this header
+    pub(crate) length: u16,
+    pub(crate) sequence: u32,
+    pub(crate) flags: u8,
+}
+
+impl McotsHeader {
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.flags & MCOTS_FLAG_ENCRYPTED != 0
+    }
+}
+
+impl Decode for McotsHeader {
+    fn from_bytes(bytes: &[u8]) -> Result<McotsHeader, PacketError> {
+        if read_bytes(bytes, 2, 4)? != MCOTS_SIGNATURE {
+            return Err(PacketError::InvalidEncoding);
+        }
+        Ok(McotsHeader {
+            length: read_u16(bytes, 0)?,
+            sequence: read_u32(bytes, 6)?,
+            flags: read_bytes(bytes, 10, 1)?[0],
+        })
+    }
+}
+
+impl Encode for McotsHeader {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes.extend_from_slice(MCOTS_SIGNATURE);
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.push(self.flags);
+        Ok(bytes)
+    }
+
+    fn size(&self) -> usize {
+        MCOTS_HEADER_SIZE
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, PacketError> {
+    let mut value_bytes = [0; 2];
+    value_bytes.copy_from_slice(read_bytes(bytes, offset, 2)?);
+    Ok(u16::from_le_bytes(value_bytes))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, PacketError> {
+    let mut value_bytes = [0; 4];
+    value_bytes.copy_from_slice(read_bytes(bytes, offset, 4)?);
+    Ok(u32::from_le_bytes(value_bytes))
+}
+
+// Every message body starts with its id
+pub(crate) fn message_id(body: &[u8]) -> Result<u16, PacketError> {
+    read_u16(body, 0)
+}
+
+// Read a message id and check it is the one we expected
+fn expect_id(bytes: &[u8], id: u16) -> Result<(), PacketError> {
+    if message_id(bytes)? != id {
+        return Err(PacketError::InvalidEncoding);
+    }
+    Ok(())
+}
+
+// Entries of a list, after its u16 count
+fn read_list<T>(
+    bytes: &[u8],
+    offset: usize,
+    entry_size: usize,
+    read: impl Fn(&[u8]) -> Result<T, PacketError>,
+) -> Result<Vec<T>, PacketError> {
+    let count = read_u16(bytes, offset)? as usize;
+    let start = offset + 2;
+    let entries = read_bytes(bytes, start, count * entry_size)?;
+    entries.chunks(entry_size).map(read).collect()
+}
+
+// 438 / 105 - who is connecting to the transaction server
+#[derive(Debug)]
+pub(crate) struct ClientConnect {
+    pub(crate) customer_id: u32,
+    pub(crate) persona_id: u32,
+}
+
+impl Decode for ClientConnect {
+    fn from_bytes(bytes: &[u8]) -> Result<ClientConnect, PacketError> {
+        Ok(ClientConnect {
+            customer_id: read_u32(bytes, 2)?,
+            persona_id: read_u32(bytes, 6)?,
+        })
+    }
+}
+
+// 141 - ask for the dealer catalogue of a brand
+#[derive(Debug)]
+pub(crate) struct StockCarInfoRequest {
+    pub(crate) brand_id: u32,
+}
+
+impl Decode for StockCarInfoRequest {
+    fn from_bytes(bytes: &[u8]) -> Result<StockCarInfoRequest, PacketError> {
+        Ok(StockCarInfoRequest {
+            brand_id: read_u32(bytes, 2)?,
+        })
+    }
+}
+
+// 172 - ask for the cars a persona owns
+#[derive(Debug)]
+pub(crate) struct OwnedVehiclesRequest {
+    pub(crate) persona_id: u32,
+}
+
+impl Decode for OwnedVehiclesRequest {
+    fn from_bytes(bytes: &[u8]) -> Result<OwnedVehiclesRequest, PacketError> {
+        Ok(OwnedVehiclesRequest {
+            persona_id: read_u32(bytes, 2)?,
+        })
+    }
+}
+
+// 101 / 102 - plain success or failure for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct McotsStatus {
+    pub(crate) id: u16,
+    pub(crate) request_id: u16,
+}
+
+impl McotsStatus {
+    pub(crate) fn success(request_id: u16) -> McotsStatus {
+        McotsStatus {
+            id: MC_SUCCESS,
+            request_id,
+        }
+    }
+
+    pub(crate) fn failed(request_id: u16) -> McotsStatus {
+        McotsStatus {
+            id: MC_FAILED,
+            request_id,
+        }
+    }
+
+    // Sent before we close a connection ourselves
+    pub(crate) fn logout() -> McotsStatus {
+        McotsStatus {
+            id: MC_LOGOUT,
+            request_id: 0,
+        }
+    }
+}
+
+impl Encode for McotsStatus {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        bytes.extend_from_slice(&self.request_id.to_le_bytes());
+        Ok(bytes)
+    }
+
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn fields(&self) -> Vec<(String, usize)> {
+        vec![("id".to_string(), 2), ("request_id".to_string(), 2)]
+    }
+}
+
+impl Decode for McotsStatus {
+    fn from_bytes(bytes: &[u8]) -> Result<McotsStatus, PacketError> {
+        Ok(McotsStatus {
+            id: read_u16(bytes, 0)?,
+            request_id: read_u16(bytes, 2)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StockCarEntry {
+    pub(crate) car_id: u32,
+    pub(crate) brand_id: u32,
+    pub(crate) retail_price: u32,
+}
+
+const STOCK_CAR_ENTRY_SIZE: usize = 12;
+
+// 141 - the dealer catalogue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StockCarInfo {
+    pub(crate) cars: Vec<StockCarEntry>,
+}
+
+impl Encode for StockCarInfo {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MC_STOCK_CAR_INFO.to_le_bytes());
+        bytes.extend_from_slice(&u16_length(self.cars.len())?.to_le_bytes());
+        for car in &self.cars {
+            bytes.extend_from_slice(&car.car_id.to_le_bytes());
+            bytes.extend_from_slice(&car.brand_id.to_le_bytes());
+            bytes.extend_from_slice(&car.retail_price.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    fn size(&self) -> usize {
+        4 + self.cars.len() * STOCK_CAR_ENTRY_SIZE
+    }
+
+    fn fields(&self) -> Vec<(String, usize)> {
+        let mut fields = vec![("id".to_string(), 2), ("cars.count".to_string(), 2)];
+        for index in 0..self.cars.len() {
+            for field in ["car_id", "brand_id", "retail_price"] {
+                fields.push((format!("cars[{}].{}", index, field), 4));
+            }
+        }
+        fields
+    }
+}
+
+impl Decode for StockCarInfo {
+    fn from_bytes(bytes: &[u8]) -> Result<StockCarInfo, PacketError> {
+        expect_id(bytes, MC_STOCK_CAR_INFO)?;
+        let cars = read_list(bytes, 2, STOCK_CAR_ENTRY_SIZE, |entry| {
+            Ok(StockCarEntry {
+                car_id: read_u32(entry, 0)?,
+                brand_id: read_u32(entry, 4)?,
+                retail_price: read_u32(entry, 8)?,
+            })
+        })?;
+        Ok(StockCarInfo { cars })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OwnedVehicleEntry {
+    pub(crate) vehicle_id: u32,
+    pub(crate) car_id: u32,
+}
+
+const OWNED_VEHICLE_ENTRY_SIZE: usize = 8;
+
+// 173 - the cars a persona owns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OwnedVehiclesList {
+    pub(crate) vehicles: Vec<OwnedVehicleEntry>,
+}
+
+impl Encode for OwnedVehiclesList {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MC_OWNED_VEHICLES_LIST.to_le_bytes());
+        bytes.extend_from_slice(&u16_length(self.vehicles.len())?.to_le_bytes());
+        for vehicle in &self.vehicles {
+            bytes.extend_from_slice(&vehicle.vehicle_id.to_le_bytes());
+            bytes.extend_from_slice(&vehicle.car_id.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    fn size(&self) -> usize {
+        4 + self.vehicles.len() * OWNED_VEHICLE_ENTRY_SIZE
+    }
+
+    fn fields(&self) -> Vec<(String, usize)> {
+        let mut fields = vec![("id".to_string(), 2), ("vehicles.count".to_string(), 2)];
+        for index in 0..self.vehicles.len() {
+            for field in ["vehicle_id", "car_id"] {
+                fields.push((format!("vehicles[{}].{}", index, field), 4));
+            }
+        }
+        fields
+    }
+}
+
+impl Decode for OwnedVehiclesList {
+    fn from_bytes(bytes: &[u8]) -> Result<OwnedVehiclesList, PacketError> {
+        expect_id(bytes, MC_OWNED_VEHICLES_LIST)?;
+        let vehicles = read_list(bytes, 2, OWNED_VEHICLE_ENTRY_SIZE, |entry| {
+            Ok(OwnedVehicleEntry {
+                vehicle_id: read_u32(entry, 0)?,
+                car_id: read_u32(entry, 4)?,
+            })
+        })?;
+        Ok(OwnedVehiclesList { vehicles })
+    }
+}
@@ -1,30 +1,205 @@
+pub(crate) mod error;
 pub(crate) mod header;
+pub(crate) mod lobby;
 pub(crate) mod login_request;
+pub(crate) mod mcots;
+pub(crate) mod persona;
+pub(crate) mod user_status;
 
+use error::PacketError;
+
+// Borrow `length` bytes starting at `offset`, or say how short we came up
+pub(crate) fn read_bytes(bytes: &[u8], offset: usize, length: usize) -> Result<&[u8], PacketError> {
+    match bytes.get(offset..offset + length) {
+        Some(slice) => Ok(slice),
+        None => Err(PacketError::Truncated {
+            needed: offset + length,
+            available: bytes.len(),
+        }),
+    }
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, PacketError> {
+    let mut value_bytes = [0; 2];
+    value_bytes.copy_from_slice(read_bytes(bytes, offset, 2)?);
+    Ok(u16::from_be_bytes(value_bytes))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, PacketError> {
+    let mut value_bytes = [0; 4];
+    value_bytes.copy_from_slice(read_bytes(bytes, offset, 4)?);
+    Ok(u32::from_be_bytes(value_bytes))
+}
+
+fn read_string(bytes: &[u8], offset: usize, length: usize) -> Result<String, PacketError> {
+    match String::from_utf8(read_bytes(bytes, offset, length)?.to_vec()) {
+        Ok(string) => Ok(string),
+        Err(_) => Err(PacketError::InvalidEncoding),
+    }
+}
+
+// Turn a message, or a piece of one, into the bytes sent on the wire
+// Fails when a length or count doesn't fit the field it is written to.
+pub(crate) trait Encode {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError>;
+
+    // How many bytes `to_bytes` produces
+    fn size(&self) -> usize;
+
+    // The name and size of each field `to_bytes` writes, in order.
+    // Plain values have no fields of their own.
+    fn fields(&self) -> Vec<(String, usize)> {
+        Vec::new()
+    }
+}
+
+// List a field's own fields under its name, or the field itself if it has none
+pub(crate) fn push_fields<T: Encode>(fields: &mut Vec<(String, usize)>, name: &str, value: &T) {
+    let inner = value.fields();
+    if inner.is_empty() {
+        fields.push((name.to_string(), value.size()));
+    } else {
+        fields.extend(
+            inner
+                .into_iter()
+                .map(|(field, size)| (format!("{}.{}", name, field), size)),
+        );
+    }
+}
+
+// Parse a message, or a piece of one, from the front of `bytes`.
+// Anything after the value is left for the caller, use `size` to skip it.
+pub(crate) trait Decode: Sized {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError>;
+}
+
+pub(crate) use nps_derive::{Decode, Encode};
+
+// Integers are big endian on the wire
+macro_rules! big_endian {
+    ($($int:ty),*) => {$(
+        impl Decode for $int {
+            fn from_bytes(bytes: &[u8]) -> Result<$int, PacketError> {
+                let mut value_bytes = [0; std::mem::size_of::<$int>()];
+                value_bytes.copy_from_slice(read_bytes(bytes, 0, std::mem::size_of::<$int>())?);
+                Ok(<$int>::from_be_bytes(value_bytes))
+            }
+        }
+
+        impl Encode for $int {
+            fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+                Ok(self.to_be_bytes().to_vec())
+            }
+
+            fn size(&self) -> usize {
+                std::mem::size_of::<$int>()
+            }
+        }
+    )*};
+}
+
+big_endian!(u8, u16, u32, u64, i16, i32);
+
+// Flags take a byte and are only ever 0 or 1
+impl Decode for bool {
+    fn from_bytes(bytes: &[u8]) -> Result<bool, PacketError> {
+        match read_bytes(bytes, 0, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(PacketError::InvalidEncoding),
+        }
+    }
+}
+
+impl Encode for bool {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        Ok(vec![*self as u8])
+    }
+
+    fn size(&self) -> usize {
+        1
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn from_bytes(bytes: &[u8]) -> Result<[u8; N], PacketError> {
+        let mut array = [0; N];
+        array.copy_from_slice(read_bytes(bytes, 0, N)?);
+        Ok(array)
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        Ok(self.to_vec())
+    }
+
+    fn size(&self) -> usize {
+        N
+    }
+}
+
+// A string null padded out to a fixed width, anything after the first null is ignored
+pub(crate) fn read_fixed_string(
+    bytes: &[u8],
+    offset: usize,
+    width: usize,
+) -> Result<String, PacketError> {
+    let field = read_bytes(bytes, offset, width)?;
+    let end = field.iter().position(|&b| b == 0).unwrap_or(width);
+    read_string(field, 0, end)
+}
+
+// Strings longer than the field are refused rather than cut short
+pub(crate) fn write_fixed_string(
+    bytes: &mut Vec<u8>,
+    string: &str,
+    width: usize,
+) -> Result<(), PacketError> {
+    if string.len() > width {
+        return Err(PacketError::TooLong {
+            length: string.len(),
+            limit: width,
+        });
+    }
+    let mut field = string.as_bytes().to_vec();
+    field.resize(width, 0);
+    bytes.extend_from_slice(&field);
+    Ok(())
+}
+
+// Lengths and counts go on the wire as a u16
+pub(crate) fn u16_length(length: usize) -> Result<u16, PacketError> {
+    u16::try_from(length).map_err(|_| PacketError::TooLong {
+        length,
+        limit: u16::MAX as usize,
+    })
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub(crate) struct PrefixedField {
     pub(crate) length: u16,
     pub(crate) data: Vec<u8>,
 }
 
-impl PrefixedField {
-    pub(crate) fn from_bytes(bytes: &[u8]) -> PrefixedField {
-        let mut length_bytes = [0; 2];
-        length_bytes.copy_from_slice(&bytes[0..2]);
-        let length = u16::from_be_bytes(length_bytes);
+impl Decode for PrefixedField {
+    fn from_bytes(bytes: &[u8]) -> Result<PrefixedField, PacketError> {
+        let length = read_u16(bytes, 0)?;
         debug!("Prefixed field length: {}", length);
-        let mut data = vec![0; length as usize];
-        data.copy_from_slice(&bytes[2..length as usize + 2]);
-        PrefixedField { length, data }
+        let data = read_bytes(bytes, 2, length as usize)?.to_vec();
+        Ok(PrefixedField { length, data })
     }
+}
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+impl Encode for PrefixedField {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.length.to_be_bytes());
         bytes.extend_from_slice(&self.data);
-        bytes
+        Ok(bytes)
     }
 
-    pub(crate) fn size(&self) -> usize {
+    fn size(&self) -> usize {
         self.length as usize + 2 // 2 bytes for length
     }
 }
@@ -38,21 +213,29 @@ impl std::fmt::Debug for PrefixedField {
     }
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub(crate) struct PrefixedString {
     pub(crate) string: String,
 }
 
-impl PrefixedString {
-    pub(crate) fn from_bytes(bytes: &[u8]) -> PrefixedString {
-        let mut length_bytes = [0; 2];
-        length_bytes.copy_from_slice(&bytes[0..2]);
-        let length = u16::from_be_bytes(length_bytes);
+impl Decode for PrefixedString {
+    fn from_bytes(bytes: &[u8]) -> Result<PrefixedString, PacketError> {
+        let length = read_u16(bytes, 0)?;
         debug!("Prefixed string length: {}", length);
-        let string = String::from_utf8(bytes[2..length as usize + 2].to_vec()).unwrap();
-        PrefixedString { string }
+        let string = read_string(bytes, 2, length as usize)?;
+        Ok(PrefixedString { string })
+    }
+}
+
+impl Encode for PrefixedString {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&u16_length(self.string.len())?.to_be_bytes());
+        bytes.extend_from_slice(self.string.as_bytes());
+        Ok(bytes)
     }
 
-    pub(crate) fn size(&self) -> usize {
+    fn size(&self) -> usize {
         self.string.len() + 2 // 2 bytes for length
     }
 }
@@ -65,21 +248,39 @@ impl std::fmt::Debug for PrefixedString {
     }
 }
 
+// A length-prefixed string followed by a null the length doesn't count
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(
+    not(test),
+    expect(dead_code, reason = "no message we handle carries one yet")
+)]
 pub(crate) struct PrefixedStringWithNull {
-    string: String,
+    pub(crate) string: String,
 }
 
-impl PrefixedStringWithNull {
-    pub(crate) fn from_bytes(bytes: &[u8]) -> PrefixedStringWithNull {
-        let mut length_bytes = [0; 2];
-        length_bytes.copy_from_slice(&bytes[0..2]);
-        let length = u16::from_be_bytes(length_bytes);
+impl Decode for PrefixedStringWithNull {
+    fn from_bytes(bytes: &[u8]) -> Result<PrefixedStringWithNull, PacketError> {
+        let length = read_u16(bytes, 0)?;
         debug!("Prefixed with null string length: {}", length);
-        let string = String::from_utf8(bytes[2..length as usize + 2].to_vec()).unwrap();
-        PrefixedStringWithNull { string }
+        let string = read_string(bytes, 2, length as usize)?;
+        // The terminator isn't counted in the length
+        if read_bytes(bytes, 2 + length as usize, 1)? != [0] {
+            return Err(PacketError::InvalidEncoding);
+        }
+        Ok(PrefixedStringWithNull { string })
+    }
+}
+
+impl Encode for PrefixedStringWithNull {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&u16_length(self.string.len())?.to_be_bytes());
+        bytes.extend_from_slice(self.string.as_bytes());
+        bytes.push(0);
+        Ok(bytes)
     }
 
-    pub(crate) fn size(&self) -> usize {
+    fn size(&self) -> usize {
         self.string.len() + 2 + 1 // 2 bytes for length, 1 byte for null terminator
     }
 }
@@ -92,14 +293,30 @@ impl std::fmt::Debug for PrefixedStringWithNull {
     }
 }
 
+// A string with no length or terminator, it runs to the end of the message
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(
+    not(test),
+    expect(dead_code, reason = "no message we handle ends in a bare string yet")
+)]
 pub(crate) struct PlainString {
-    string: String,
+    pub(crate) string: String,
+}
+
+impl Decode for PlainString {
+    fn from_bytes(bytes: &[u8]) -> Result<PlainString, PacketError> {
+        let string = read_string(bytes, 0, bytes.len())?;
+        Ok(PlainString { string })
+    }
 }
 
-impl PlainString {
-    pub(crate) fn from_bytes(bytes: &[u8], length: usize) -> PlainString {
-        let string = String::from_utf8(bytes[0..length].to_vec()).unwrap();
-        PlainString { string }
+impl Encode for PlainString {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        Ok(self.string.as_bytes().to_vec())
+    }
+
+    fn size(&self) -> usize {
+        self.string.len()
     }
 }
 
@@ -111,46 +328,42 @@ impl std::fmt::Debug for PlainString {
     }
 }
 
+// An id followed by an opaque payload that runs to the end of the message
+#[derive(Clone, PartialEq, Eq)]
 pub(crate) struct MessageContainer {
     id: u16,
     data: Vec<u8>,
 }
 
 impl MessageContainer {
-    pub(crate) fn from_bytes(bytes: &[u8]) -> MessageContainer {
-        let mut id_bytes = [0; 2];
-        id_bytes.copy_from_slice(&bytes[0..2]);
-        let id = u16::from_be_bytes(id_bytes);
-        debug!("Message container id: {}", id);
-        let data = bytes[2..].to_vec();
+    pub(crate) fn new(id: u16, data: Vec<u8>) -> MessageContainer {
         MessageContainer { id, data }
     }
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.id.to_be_bytes());
-        bytes.extend_from_slice(&self.data);
-        bytes
-    }
-
-    pub(crate) fn id(&self) -> u16 {
-        self.id
-    }
-
     pub(crate) fn data(&self) -> &[u8] {
         &self.data
     }
+}
 
-    pub(crate) fn data_mut(&mut self) -> &mut Vec<u8> {
-        &mut self.data
+impl Decode for MessageContainer {
+    fn from_bytes(bytes: &[u8]) -> Result<MessageContainer, PacketError> {
+        let id = read_u16(bytes, 0)?;
+        debug!("Message container id: {}", id);
+        let data = bytes[2..].to_vec();
+        Ok(MessageContainer { id, data })
     }
+}
 
-    pub(crate) fn size(&self) -> usize {
-        self.data.len() + 4 // 4 bytes for header
+impl Encode for MessageContainer {
+    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
     }
 
-    pub(crate) fn set_id(&mut self, id: u16) {
-        self.id = id;
+    fn size(&self) -> usize {
+        self.data.len() + 2 // 2 bytes for id
     }
 }
 
@@ -162,3 +375,348 @@ impl std::fmt::Debug for MessageContainer {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::error::ErrorResponse;
+    use super::header::{Header, VersionedHeader};
+    use super::login_request::LoginRequest;
+    use super::mcots::{
+        McotsStatus, OwnedVehicleEntry, OwnedVehiclesList, StockCarEntry, StockCarInfo,
+    };
+    use super::persona::{GetPersonaMaps, PersonaCreated, PersonaMaps, PersonaStatus, NPS_ACK};
+    use super::user_status::{UserStatus, USER_STATUS_LENGTH};
+    use super::*;
+    use crate::store::persona::Persona;
+    use crate::test_client::build_login_request;
+    use proptest::prelude::*;
+
+    // decode(encode(x)) == x, and `size` agrees with what was written
+    fn round_trip<T: Encode + Decode + PartialEq + std::fmt::Debug>(value: T) {
+        let bytes = value.to_bytes().unwrap();
+        assert_eq!(bytes.len(), value.size());
+        assert_eq!(T::from_bytes(&bytes).unwrap(), value);
+    }
+
+    // Exercises every field layout the derive supports
+    #[derive(Debug, PartialEq, Decode, Encode)]
+    struct Everything {
+        #[nps(header)]
+        header: Header,
+        flags: u8,
+        customer_id: u32,
+        token: [u8; 4],
+        #[nps(fixed = 8)]
+        name: String,
+        #[nps(prefixed)]
+        blob: Vec<u8>,
+        #[nps(container)]
+        inner: Inner,
+    }
+
+    #[derive(Debug, PartialEq, Decode, Encode)]
+    struct Inner {
+        game_id: PrefixedStringWithNull,
+    }
+
+    fn everything(name: String, blob: Vec<u8>, game_id: String) -> Everything {
+        let mut message = Everything {
+            header: Header {
+                id: 0x0123,
+                length: 0,
+            },
+            flags: 0x80,
+            customer_id: 0x0001_0001,
+            token: [1, 2, 3, 4],
+            name,
+            blob,
+            inner: Inner {
+                game_id: PrefixedStringWithNull { string: game_id },
+            },
+        };
+        message.header.length = message.size() as u16;
+        message
+    }
+
+    #[test]
+    fn versioned_headers_are_checked() {
+        let mut bytes = VersionedHeader::new(0x0501, 12).to_bytes().unwrap();
+        assert_eq!(&bytes[8..], [0x00, 0x00, 0x00, 0x0c]);
+
+        bytes[4..6].copy_from_slice(&[0x02, 0x02]);
+        assert_eq!(
+            VersionedHeader::from_bytes(&bytes).unwrap_err(),
+            PacketError::UnsupportedVersion { version: 0x0202 }
+        );
+    }
+
+    #[test]
+    fn derived_headers_get_the_message_length() {
+        let mut message = everything("racer".to_string(), vec![9], "mco".to_string());
+        let length = message.header.length;
+        message.header.length = 0;
+        assert_eq!(&message.to_bytes().unwrap()[2..4], length.to_be_bytes());
+
+        let request = build_login_request("ticket", "key", "game");
+        let bytes = request.to_bytes().unwrap();
+        assert_eq!(&bytes[2..4], (bytes.len() as u16).to_be_bytes());
+        assert_eq!(&bytes[8..12], (bytes.len() as u32).to_be_bytes());
+    }
+
+    #[test]
+    fn derived_messages_reject_leftovers() {
+        let mut bytes = everything("racer".to_string(), vec![9], "mco".to_string())
+            .to_bytes()
+            .unwrap();
+        bytes.push(0);
+        let length = bytes.len() as u16;
+        bytes[2..4].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(
+            Everything::from_bytes(&bytes).unwrap_err(),
+            PacketError::TrailingBytes { count: 1 }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn headers_round_trip(id: u16, length: u16) {
+            round_trip(Header { id, length });
+            round_trip(VersionedHeader::new(id, length));
+        }
+
+        #[test]
+        fn versioned_headers_keep_their_checksum(id: u16, checksum: u32) {
+            let request = build_login_request("ticket", "key", "game");
+            let mut bytes = request.to_bytes().unwrap();
+            bytes[..2].copy_from_slice(&id.to_be_bytes());
+            bytes[8..12].copy_from_slice(&checksum.to_be_bytes());
+            let decoded = LoginRequest::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(decoded.to_bytes().unwrap(), bytes);
+        }
+
+        #[test]
+        fn prefixed_fields_round_trip(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            round_trip(PrefixedField { length: data.len() as u16, data });
+        }
+
+        #[test]
+        fn strings_round_trip(string in "\\PC{0,64}") {
+            round_trip(PrefixedString { string: string.clone() });
+            round_trip(PrefixedStringWithNull { string: string.clone() });
+            round_trip(PlainString { string });
+        }
+
+        #[test]
+        fn message_containers_round_trip(id: u16, data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            round_trip(MessageContainer::new(id, data));
+        }
+
+        #[test]
+        fn login_requests_round_trip(
+            context_id in "[0-9a-f]{32}",
+            encrypted_session_key in "[0-9A-F]{0,256}",
+            game_id in "\\PC{0,16}",
+        ) {
+            round_trip(build_login_request(&context_id, &encrypted_session_key, &game_id));
+        }
+
+        #[test]
+        fn error_responses_round_trip(request_id: u16, code: u32) {
+            round_trip(ErrorResponse::generic(request_id));
+            round_trip(ErrorResponse::login_failed(code));
+            round_trip(ErrorResponse::logoff(code));
+        }
+
+        #[test]
+        fn user_statuses_round_trip(
+            customer_id: u32,
+            is_banned: bool,
+            is_gagged: bool,
+            context_id in "[0-9a-f]{32}",
+            session_key in proptest::array::uniform32(any::<u8>()),
+        ) {
+            let status = UserStatus::new(customer_id, is_banned, is_gagged, &context_id, &session_key);
+            prop_assert_eq!(status.size(), USER_STATUS_LENGTH);
+            round_trip(status);
+        }
+
+        #[test]
+        fn persona_replies_round_trip(
+            personas in proptest::collection::vec((any::<u32>(), any::<u32>(), "[a-zA-Z0-9_-]{3,30}"), 0..4),
+            id: u16,
+            request_id: u16,
+        ) {
+            let personas: Vec<Persona> = personas
+                .into_iter()
+                .map(|(id, shard_id, name)| Persona { id, customer_id: 1, name, shard_id })
+                .collect();
+            round_trip(PersonaMaps::new(&personas));
+            round_trip(PersonaCreated::new(id as u32));
+            round_trip(PersonaStatus::new(NPS_ACK, request_id));
+            round_trip(PersonaStatus::new(id, request_id));
+        }
+
+        #[test]
+        fn mcots_replies_round_trip(
+            request_id: u16,
+            cars in proptest::collection::vec(any::<(u32, u32, u32)>(), 0..8),
+            vehicles in proptest::collection::vec(any::<(u32, u32)>(), 0..8),
+        ) {
+            round_trip(McotsStatus::success(request_id));
+            round_trip(McotsStatus::failed(request_id));
+            round_trip(McotsStatus::logout());
+            round_trip(StockCarInfo {
+                cars: cars
+                    .into_iter()
+                    .map(|(car_id, brand_id, retail_price)| StockCarEntry { car_id, brand_id, retail_price })
+                    .collect(),
+            });
+            round_trip(OwnedVehiclesList {
+                vehicles: vehicles
+                    .into_iter()
+                    .map(|(vehicle_id, car_id)| OwnedVehicleEntry { vehicle_id, car_id })
+                    .collect(),
+            });
+        }
+
+        #[test]
+        fn derived_messages_round_trip(
+            name in "[a-zA-Z0-9_]{0,8}",
+            blob in proptest::collection::vec(any::<u8>(), 0..64),
+            game_id in "\\PC{0,16}",
+        ) {
+            round_trip(everything(name, blob, game_id));
+        }
+    }
+
+    #[test]
+    fn short_input_is_truncated() {
+        assert_eq!(
+            Header::from_bytes(&[0x05, 0x01, 0x00]).unwrap_err(),
+            PacketError::Truncated {
+                needed: 4,
+                available: 3
+            }
+        );
+        assert_eq!(
+            PrefixedString::from_bytes(&[0x00, 0x05, b'a', b'b']).unwrap_err(),
+            PacketError::Truncated {
+                needed: 7,
+                available: 4
+            }
+        );
+        assert!(MessageContainer::from_bytes(&[0x00]).is_err());
+    }
+
+    #[test]
+    fn requests_check_their_length() {
+        let mut request = vec![0x05, 0x32, 0x00, 0x08];
+        request.extend_from_slice(&42u32.to_be_bytes());
+        assert_eq!(
+            GetPersonaMaps::from_bytes(&request).unwrap().customer_id,
+            42
+        );
+
+        request.push(0);
+        assert_eq!(
+            GetPersonaMaps::from_bytes(&request).unwrap_err(),
+            PacketError::BadLength {
+                length: 8,
+                available: 9
+            }
+        );
+        request[3] = 9;
+        assert_eq!(
+            GetPersonaMaps::from_bytes(&request).unwrap_err(),
+            PacketError::TrailingBytes { count: 1 }
+        );
+    }
+
+    #[test]
+    fn flags_are_zero_or_one() {
+        assert_eq!(bool::from_bytes(&[1]), Ok(true));
+        assert_eq!(bool::from_bytes(&[2]), Err(PacketError::InvalidEncoding));
+    }
+
+    #[test]
+    fn derived_fields_cover_the_message() {
+        let message = everything("racer".to_string(), vec![9], "mco".to_string());
+        let fields = message.fields();
+        assert_eq!(
+            fields.iter().map(|(_, size)| size).sum::<usize>(),
+            message.size()
+        );
+        let names: Vec<&str> = fields.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "header.id",
+                "header.length",
+                "flags",
+                "customer_id",
+                "token",
+                "name",
+                "blob",
+                "inner.id",
+                "inner.game_id",
+            ]
+        );
+    }
+
+    #[test]
+    fn encoding_refuses_values_too_long_for_their_field() {
+        let long = u16::MAX as usize + 1;
+        let too_long = |length| PacketError::TooLong {
+            length,
+            limit: u16::MAX as usize,
+        };
+        assert_eq!(
+            PrefixedString {
+                string: "a".repeat(long)
+            }
+            .to_bytes()
+            .unwrap_err(),
+            too_long(long)
+        );
+        // The header is filled in first, so a long blob fails on the message length
+        let message = everything("racer".to_string(), vec![0; long], "mco".to_string());
+        assert_eq!(message.to_bytes().unwrap_err(), too_long(message.size()));
+        assert_eq!(
+            everything("racer-x-1".to_string(), vec![9], "mco".to_string())
+                .to_bytes()
+                .unwrap_err(),
+            PacketError::TooLong {
+                length: 9,
+                limit: 8
+            }
+        );
+    }
+
+    #[test]
+    fn strings_must_be_utf8() {
+        assert_eq!(
+            PrefixedString::from_bytes(&[0x00, 0x02, 0xff, 0xfe]).unwrap_err(),
+            PacketError::InvalidEncoding
+        );
+        assert_eq!(
+            PrefixedStringWithNull::from_bytes(&[0x00, 0x01, b'a', b'b']).unwrap_err(),
+            PacketError::InvalidEncoding
+        );
+    }
+
+    #[test]
+    fn login_request_checks_lengths() {
+        assert!(LoginRequest::from_bytes(&[0x05, 0x01]).is_err());
+
+        // A header claiming more bytes than were sent
+        let mut packet = vec![0x05, 0x01, 0x00, 0x40, 0x01, 0x01, 0x00, 0x00];
+        packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x40]);
+        assert_eq!(
+            LoginRequest::from_bytes(&packet).unwrap_err(),
+            PacketError::BadLength {
+                length: 0x40,
+                available: 12
+            }
+        );
+    }
+}
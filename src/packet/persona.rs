@@ -0,0 +1,142 @@
+use super::{header::Header, Decode, Encode, PrefixedString};
+use crate::store::persona::{Persona, MAX_NAME_LENGTH};
+
+pub(crate) const NPS_SELECT_GAME_PERSONA: u16 = 0x0503;
+pub(crate) const NPS_CREATE_PERSONA: u16 = 0x0511;
+pub(crate) const NPS_DELETE_PERSONA: u16 = 0x0512;
+pub(crate) const NPS_GET_PERSONA_MAPS: u16 = 0x0532;
+pub(crate) const NPS_VALIDATE_PERSONA_NAME: u16 = 0x0533;
+
+pub(crate) const NPS_ACK: u16 = 0x0207;
+pub(crate) const NPS_PERSONA_MAPS: u16 = 0x0607;
+pub(crate) const NPS_PERSONA_CREATED: u16 = 0x0611;
+pub(crate) const NPS_PERSONA_NAME_TAKEN: u16 = 0x0612;
+pub(crate) const NPS_PERSONA_NAME_INVALID: u16 = 0x0613;
+pub(crate) const NPS_PERSONA_NOT_FOUND: u16 = 0x0614;
+pub(crate) const NPS_TOO_MANY_PERSONAS: u16 = 0x0615;
+
+// Persona names are sent as null padded fixed width strings
+const PERSONA_NAME_FIELD: usize = MAX_NAME_LENGTH + 2;
+
+// 0x532 - list the personas owned by a customer
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct GetPersonaMaps {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+}
+
+// 0x503 - pick the persona to play as
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct SelectGamePersona {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+    pub(crate) persona_id: u32,
+}
+
+// 0x533 - ask whether a name is free, 0x511 - claim it
+#[derive(Decode, Encode)]
+pub(crate) struct PersonaNameRequest {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+    pub(crate) name: PrefixedString,
+}
+
+impl std::fmt::Debug for PersonaNameRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PersonaNameRequest")
+            .field("customer_id", &self.customer_id)
+            .field("name", &self.name.string)
+            .finish()
+    }
+}
+
+// 0x512 - remove a persona
+#[derive(Debug, Decode, Encode)]
+pub(crate) struct DeletePersona {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) customer_id: u32,
+    pub(crate) persona_id: u32,
+}
+
+// One entry in the persona list
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct PersonaEntry {
+    pub(crate) id: u32,
+    pub(crate) shard_id: u32,
+    #[nps(fixed = PERSONA_NAME_FIELD)]
+    pub(crate) name: String,
+}
+
+impl From<&Persona> for PersonaEntry {
+    fn from(persona: &Persona) -> PersonaEntry {
+        PersonaEntry {
+            id: persona.id,
+            shard_id: persona.shard_id,
+            name: persona.name.clone(),
+        }
+    }
+}
+
+// 0x607 - every persona the customer owns
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct PersonaMaps {
+    #[nps(header)]
+    pub(crate) header: Header,
+    #[nps(counted)]
+    pub(crate) personas: Vec<PersonaEntry>,
+}
+
+impl PersonaMaps {
+    pub(crate) fn new(personas: &[Persona]) -> PersonaMaps {
+        let mut maps = PersonaMaps {
+            header: Header {
+                id: NPS_PERSONA_MAPS,
+                length: 0,
+            },
+            personas: personas.iter().map(PersonaEntry::from).collect(),
+        };
+        maps.header.length = maps.size() as u16;
+        maps
+    }
+}
+
+// 0x611 - the persona that was just created
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct PersonaCreated {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) persona_id: u32,
+}
+
+impl PersonaCreated {
+    pub(crate) fn new(persona_id: u32) -> PersonaCreated {
+        PersonaCreated {
+            header: Header {
+                id: NPS_PERSONA_CREATED,
+                length: 8,
+            },
+            persona_id,
+        }
+    }
+}
+
+// A bare status reply, carrying the id of the request it answers
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct PersonaStatus {
+    #[nps(header)]
+    pub(crate) header: Header,
+    pub(crate) request_id: u32,
+}
+
+impl PersonaStatus {
+    pub(crate) fn new(id: u16, request_id: u16) -> PersonaStatus {
+        PersonaStatus {
+            header: Header { id, length: 8 },
+            request_id: request_id as u32,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use super::{header::Header, Decode, Encode, PrefixedString};
+
+pub(crate) const NPS_USER_STATUS: u16 = 0x0601;
+
+// The client checks that the user status is exactly this long, including the header
+pub(crate) const USER_STATUS_LENGTH: usize = 0x0257;
+
+// Sent in reply to a LoginRequest (0x501) once the session key has been decrypted
+#[derive(Clone, PartialEq, Eq, Decode, Encode)]
+pub(crate) struct UserStatus {
+    #[nps(header)]
+    pub(crate) header: Header,
+    // The auth ticket the client logged in with
+    pub(crate) context_id: PrefixedString,
+    pub(crate) customer_id: u32,
+    pub(crate) user_id: u32,
+    pub(crate) is_banned: bool,
+    pub(crate) is_gagged: bool,
+    // Hex SHA-256 of the decrypted session key, so the client can check we
+    // recovered the same key without the key itself crossing the wire
+    pub(crate) session_key_hash: PrefixedString,
+    // Zeros out to the length the client expects
+    #[nps(rest)]
+    pub(crate) padding: Vec<u8>,
+}
+
+impl UserStatus {
+    pub(crate) fn new(
+        customer_id: u32,
+        is_banned: bool,
+        is_gagged: bool,
+        context_id: &str,
+        session_key: &[u8],
+    ) -> UserStatus {
+        let mut status = UserStatus {
+            header: Header {
+                id: NPS_USER_STATUS,
+                length: 0,
+            },
+            context_id: PrefixedString {
+                string: context_id.to_string(),
+            },
+            customer_id,
+            user_id: customer_id,
+            is_banned,
+            is_gagged,
+            session_key_hash: PrefixedString {
+                string: hex::encode(openssl::sha::sha256(session_key)),
+            },
+            padding: Vec::new(),
+        };
+        status.padding = vec![0; USER_STATUS_LENGTH.saturating_sub(status.size())];
+        status.header.length = status.size() as u16;
+        status
+    }
+}
+
+impl std::fmt::Debug for UserStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserStatus")
+            .field("customer_id", &self.customer_id)
+            .field("user_id", &self.user_id)
+            .field("is_banned", &self.is_banned)
+            .field("is_gagged", &self.is_gagged)
+            .field("context_id", &self.context_id.string)
+            .finish()
+    }
+}
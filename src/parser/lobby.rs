@@ -0,0 +1,60 @@
+use crate::dispatch::{encode, Connection, HandlerResult};
+use crate::packet::error::ErrorResponse;
+use crate::packet::lobby::{LobbyLogin, LobbyWelcome, NPS_LOBBY_LOGIN};
+use crate::packet::Decode;
+
+pub(crate) async fn handle_lobby_login(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request =
+        LobbyLogin::from_bytes(packet).map_err(|e| error!("Failed to parse LobbyLogin: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    // The session key was negotiated on the login server
+    let refused = Ok(vec![encode(&ErrorResponse::generic(NPS_LOBBY_LOGIN))?]);
+    let session = match connection.session(request.customer_id) {
+        Some(session) => session,
+        None => return refused,
+    };
+    let customer_id = request.customer_id;
+    let account = connection
+        .with_accounts(move |accounts| accounts.get(customer_id))
+        .await?;
+    if account.is_none_or(|account| account.is_banned) {
+        error!(
+            "Customer {} is banned or gone, refusing the lobby",
+            request.customer_id
+        );
+        return refused;
+    }
+    // Only the persona picked on the persona server may join
+    if session.persona_id != Some(request.persona_id)
+        || connection
+            .state
+            .personas
+            .get(request.customer_id, request.persona_id)
+            .is_none()
+    {
+        error!(
+            "Customer {} asked to join as persona {} but selected {:?}",
+            request.customer_id, request.persona_id, session.persona_id
+        );
+        return refused;
+    }
+
+    info!(
+        "Customer {} joined the lobby as persona {} (logged in on connection {})",
+        session.customer_id, request.persona_id, session.connection_id
+    );
+    connection.customer_id = Some(request.customer_id);
+    connection.persona_id = Some(request.persona_id);
+    connection.state.sessions.touch(request.customer_id);
+
+    let welcome = LobbyWelcome::new(request.customer_id, request.persona_id);
+
+    // Everything after the welcome is encrypted with the session key
+    connection.session_key = Some(session.session_key);
+
+    Ok(vec![encode(&welcome)?])
+}
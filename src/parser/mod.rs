@@ -1 +1,90 @@
+pub(crate) mod lobby;
+pub(crate) mod persona;
+pub(crate) mod transaction;
 pub(crate) mod user_login;
+
+use crate::dispatch::{handler, Registry, Server};
+use crate::packet::lobby::NPS_LOBBY_LOGIN;
+use crate::packet::login_request::NPS_USER_LOGIN;
+use crate::packet::mcots::{
+    MC_CLIENT_CONNECT_MSG, MC_GET_OWNED_VEHICLES, MC_LOGIN, MC_LOGOUT, MC_STOCK_CAR_INFO,
+};
+use crate::packet::persona::{
+    NPS_CREATE_PERSONA, NPS_DELETE_PERSONA, NPS_GET_PERSONA_MAPS, NPS_SELECT_GAME_PERSONA,
+    NPS_VALIDATE_PERSONA_NAME,
+};
+
+// Build the dispatch table for every listener
+pub(crate) fn handlers() -> Registry {
+    let mut registry = Registry::new();
+
+    // Login server
+    registry.register(
+        Server::Login,
+        NPS_USER_LOGIN,
+        handler!(user_login::handle_user_login),
+    );
+
+    // Persona server
+    registry.register(
+        Server::Persona,
+        NPS_GET_PERSONA_MAPS,
+        handler!(persona::handle_get_persona_maps),
+    );
+    registry.register(
+        Server::Persona,
+        NPS_SELECT_GAME_PERSONA,
+        handler!(persona::handle_select_game_persona),
+    );
+    registry.register(
+        Server::Persona,
+        NPS_VALIDATE_PERSONA_NAME,
+        handler!(persona::handle_validate_persona_name),
+    );
+    registry.register(
+        Server::Persona,
+        NPS_CREATE_PERSONA,
+        handler!(persona::handle_create_persona),
+    );
+    registry.register(
+        Server::Persona,
+        NPS_DELETE_PERSONA,
+        handler!(persona::handle_delete_persona),
+    );
+
+    // Lobby server
+    registry.register(
+        Server::Lobby,
+        NPS_LOBBY_LOGIN,
+        handler!(lobby::handle_lobby_login),
+    );
+
+    // Transaction server
+    registry.register(
+        Server::Transaction,
+        MC_CLIENT_CONNECT_MSG,
+        handler!(transaction::handle_client_connect),
+    );
+    registry.register(
+        Server::Transaction,
+        MC_LOGIN,
+        handler!(transaction::handle_login),
+    );
+    registry.register(
+        Server::Transaction,
+        MC_LOGOUT,
+        handler!(transaction::handle_logout),
+    );
+    registry.register(
+        Server::Transaction,
+        MC_STOCK_CAR_INFO,
+        handler!(transaction::handle_stock_car_info),
+    );
+    registry.register(
+        Server::Transaction,
+        MC_GET_OWNED_VEHICLES,
+        handler!(transaction::handle_get_owned_vehicles),
+    );
+
+    registry
+}
@@ -0,0 +1,334 @@
+use crate::dispatch::{encode, Connection, HandlerResult};
+use crate::packet::error::ErrorResponse;
+use crate::packet::persona::{
+    DeletePersona, GetPersonaMaps, PersonaCreated, PersonaMaps, PersonaNameRequest, PersonaStatus,
+    SelectGamePersona, NPS_ACK, NPS_CREATE_PERSONA, NPS_DELETE_PERSONA, NPS_GET_PERSONA_MAPS,
+    NPS_PERSONA_NAME_INVALID, NPS_PERSONA_NAME_TAKEN, NPS_PERSONA_NOT_FOUND,
+    NPS_SELECT_GAME_PERSONA, NPS_TOO_MANY_PERSONAS, NPS_VALIDATE_PERSONA_NAME,
+};
+use crate::packet::Decode;
+use crate::store::persona::PersonaError;
+use crate::store::vehicle::STARTER_CAR_ID;
+
+pub(crate) async fn handle_get_persona_maps(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = GetPersonaMaps::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse GetPersonaMaps: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if connection.bind_session(request.customer_id).is_none() {
+        return Ok(vec![encode(&ErrorResponse::generic(NPS_GET_PERSONA_MAPS))?]);
+    }
+
+    let personas = connection.state.personas.for_customer(request.customer_id);
+    info!(
+        "Customer {} has {} persona(s)",
+        request.customer_id,
+        personas.len()
+    );
+
+    Ok(vec![encode(&PersonaMaps::new(&personas))?])
+}
+
+pub(crate) async fn handle_select_game_persona(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = SelectGamePersona::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse SelectGamePersona: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if connection.bind_session(request.customer_id).is_none() {
+        return Ok(vec![encode(&ErrorResponse::generic(
+            NPS_SELECT_GAME_PERSONA,
+        ))?]);
+    }
+
+    let status = match connection
+        .state
+        .personas
+        .get(request.customer_id, request.persona_id)
+    {
+        Some(persona) => {
+            info!(
+                "Customer {} selected persona {} ({})",
+                persona.customer_id, persona.id, persona.name
+            );
+            connection.persona_id = Some(persona.id);
+            connection
+                .state
+                .sessions
+                .set_persona(persona.customer_id, Some(persona.id));
+            NPS_ACK
+        }
+        None => {
+            error!(
+                "Customer {} does not own persona {}",
+                request.customer_id, request.persona_id
+            );
+            NPS_PERSONA_NOT_FOUND
+        }
+    };
+
+    Ok(vec![reply(status, NPS_SELECT_GAME_PERSONA)?])
+}
+
+pub(crate) async fn handle_validate_persona_name(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = PersonaNameRequest::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse PersonaNameRequest: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if connection.bind_session(request.customer_id).is_none() {
+        return Ok(vec![encode(&ErrorResponse::generic(
+            NPS_VALIDATE_PERSONA_NAME,
+        ))?]);
+    }
+
+    let status = match connection.state.personas.check_name(&request.name.string) {
+        Ok(()) => NPS_ACK,
+        Err(e) => {
+            info!("Persona name {:?} rejected: {:?}", request.name.string, e);
+            error_status(e)
+        }
+    };
+
+    Ok(vec![reply(status, NPS_VALIDATE_PERSONA_NAME)?])
+}
+
+pub(crate) async fn handle_create_persona(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = PersonaNameRequest::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse PersonaNameRequest: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if connection.bind_session(request.customer_id).is_none() {
+        return Ok(vec![encode(&ErrorResponse::generic(NPS_CREATE_PERSONA))?]);
+    }
+
+    match connection.state.personas.create(
+        request.customer_id,
+        &request.name.string,
+        connection.state.config().home_shard(),
+    ) {
+        Ok(persona) => {
+            info!(
+                "Customer {} created persona {} ({})",
+                persona.customer_id, persona.id, persona.name
+            );
+            connection.state.vehicles.grant(persona.id, STARTER_CAR_ID);
+            Ok(vec![encode(&PersonaCreated::new(persona.id))?])
+        }
+        Err(e) => {
+            error!(
+                "Customer {} could not create persona {:?}: {:?}",
+                request.customer_id, request.name.string, e
+            );
+            Ok(vec![reply(error_status(e), NPS_CREATE_PERSONA)?])
+        }
+    }
+}
+
+pub(crate) async fn handle_delete_persona(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = DeletePersona::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse DeletePersona: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if connection.bind_session(request.customer_id).is_none() {
+        return Ok(vec![encode(&ErrorResponse::generic(NPS_DELETE_PERSONA))?]);
+    }
+
+    let status = match connection
+        .state
+        .personas
+        .delete(request.customer_id, request.persona_id)
+    {
+        Ok(()) => {
+            info!(
+                "Customer {} deleted persona {}",
+                request.customer_id, request.persona_id
+            );
+            connection.state.vehicles.remove_persona(request.persona_id);
+            if connection.persona_id == Some(request.persona_id) {
+                connection.persona_id = None;
+            }
+            connection
+                .state
+                .sessions
+                .clear_persona(request.customer_id, request.persona_id);
+            NPS_ACK
+        }
+        Err(e) => {
+            error!(
+                "Customer {} could not delete persona {}: {:?}",
+                request.customer_id, request.persona_id, e
+            );
+            error_status(e)
+        }
+    };
+
+    Ok(vec![reply(status, NPS_DELETE_PERSONA)?])
+}
+
+fn error_status(error: PersonaError) -> u16 {
+    match error {
+        PersonaError::InvalidName => NPS_PERSONA_NAME_INVALID,
+        PersonaError::NameTaken => NPS_PERSONA_NAME_TAKEN,
+        PersonaError::TooManyPersonas => NPS_TOO_MANY_PERSONAS,
+        PersonaError::NotFound => NPS_PERSONA_NOT_FOUND,
+    }
+}
+
+fn reply(id: u16, request_id: u16) -> Result<Vec<u8>, ()> {
+    encode(&PersonaStatus::new(id, request_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::dispatch::Server;
+    use crate::packet::error::NPS_GENERIC_FAILURE;
+    use crate::packet::persona::{NPS_PERSONA_CREATED, NPS_PERSONA_MAPS};
+    use crate::state::State;
+    use crate::test_client::{
+        delete_persona, get_persona_maps, persona_name_request, select_game_persona, test_state,
+    };
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn persona_connection(state: &Arc<State>, peer: &str) -> Connection {
+        Connection::new(9, Server::Persona, peer.to_string(), state.clone())
+    }
+
+    fn response_id(responses: &[Vec<u8>]) -> u16 {
+        u16::from_be_bytes([responses[0][0], responses[0][1]])
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_session_are_refused() {
+        let state = test_state(None);
+        let mut connection = persona_connection(&state, "10.0.0.1:4000");
+
+        let responses = handle_get_persona_maps(&mut connection, &get_persona_maps(1))
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_GENERIC_FAILURE);
+
+        let request = persona_name_request(NPS_CREATE_PERSONA, 1, "Racer");
+        let responses = handle_create_persona(&mut connection, &request)
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_GENERIC_FAILURE);
+        assert!(state.personas.for_customer(1).is_empty());
+
+        let request = persona_name_request(NPS_VALIDATE_PERSONA_NAME, 1, "Racer");
+        let responses = handle_validate_persona_name(&mut connection, &request)
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_GENERIC_FAILURE);
+        assert_eq!(connection.customer_id, None);
+    }
+
+    #[tokio::test]
+    async fn sessions_from_another_host_are_refused() {
+        let state = test_state(None);
+        state.sessions.insert(1, 1, "10.0.0.1:4000", KEY.to_vec());
+        let persona = state.personas.create(1, "Racer", 1).unwrap();
+        let mut connection = persona_connection(&state, "10.0.0.2:4000");
+
+        let responses =
+            handle_select_game_persona(&mut connection, &select_game_persona(1, persona.id))
+                .await
+                .unwrap();
+        assert_eq!(response_id(&responses), NPS_GENERIC_FAILURE);
+        assert_eq!(state.sessions.get(1).unwrap().persona_id, None);
+        assert_eq!(connection.persona_id, None);
+    }
+
+    #[tokio::test]
+    async fn another_customers_personas_are_refused() {
+        let state = test_state(None);
+        state.sessions.insert(1, 1, "10.0.0.1:4000", KEY.to_vec());
+        state.sessions.insert(2, 2, "10.0.0.1:4001", KEY.to_vec());
+        let victim = state.personas.create(2, "Victim", 1).unwrap();
+        let mut connection = persona_connection(&state, "10.0.0.1:4002");
+
+        let responses = handle_get_persona_maps(&mut connection, &get_persona_maps(1))
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_PERSONA_MAPS);
+
+        // Having spoken as customer 1, the connection can't switch to customer 2
+        let responses = handle_delete_persona(&mut connection, &delete_persona(2, victim.id))
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_GENERIC_FAILURE);
+        assert!(state.personas.get(2, victim.id).is_some());
+    }
+
+    #[tokio::test]
+    async fn logged_in_customers_manage_their_personas() {
+        let state = test_state(None);
+        state.sessions.insert(1, 1, "10.0.0.1:4000", KEY.to_vec());
+        let mut connection = persona_connection(&state, "10.0.0.1:4001");
+
+        let request = persona_name_request(NPS_CREATE_PERSONA, 1, "Racer");
+        let responses = handle_create_persona(&mut connection, &request)
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_PERSONA_CREATED);
+        let persona = state.personas.for_customer(1).remove(0);
+        let starter: Vec<u32> = state
+            .vehicles
+            .owned_by(persona.id)
+            .iter()
+            .map(|vehicle| vehicle.car_id)
+            .collect();
+        assert_eq!(starter, [STARTER_CAR_ID]);
+
+        let responses =
+            handle_select_game_persona(&mut connection, &select_game_persona(1, persona.id))
+                .await
+                .unwrap();
+        assert_eq!(response_id(&responses), NPS_ACK);
+        assert_eq!(state.sessions.get(1).unwrap().persona_id, Some(persona.id));
+
+        let responses = handle_delete_persona(&mut connection, &delete_persona(1, persona.id))
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_ACK);
+        assert!(state.personas.for_customer(1).is_empty());
+        assert!(state.vehicles.owned_by(persona.id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleting_a_persona_selected_elsewhere_unselects_it() {
+        let state = test_state(None);
+        state.sessions.insert(1, 1, "10.0.0.1:4000", KEY.to_vec());
+        let persona = state.personas.create(1, "Racer", 1).unwrap();
+        let mut selecting = persona_connection(&state, "10.0.0.1:4001");
+        let mut deleting = persona_connection(&state, "10.0.0.1:4002");
+
+        handle_select_game_persona(&mut selecting, &select_game_persona(1, persona.id))
+            .await
+            .unwrap();
+        assert_eq!(state.sessions.get(1).unwrap().persona_id, Some(persona.id));
+
+        let responses = handle_delete_persona(&mut deleting, &delete_persona(1, persona.id))
+            .await
+            .unwrap();
+        assert_eq!(response_id(&responses), NPS_ACK);
+        assert_eq!(state.sessions.get(1).unwrap().persona_id, None);
+    }
+}
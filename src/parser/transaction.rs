@@ -0,0 +1,325 @@
+use crate::dispatch::{encode, Connection, HandlerResult};
+use crate::packet::mcots::{
+    ClientConnect, McotsStatus, OwnedVehiclesList, OwnedVehiclesRequest, StockCarInfo,
+    StockCarInfoRequest, MC_CLIENT_CONNECT_MSG, MC_GET_OWNED_VEHICLES, MC_LOGIN, MC_LOGOUT,
+    MC_STOCK_CAR_INFO,
+};
+use crate::packet::Decode;
+
+pub(crate) async fn handle_client_connect(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = ClientConnect::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse ClientConnect: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    // The session key was negotiated on the login server
+    let refused = Ok(vec![encode(&McotsStatus::failed(MC_CLIENT_CONNECT_MSG))?]);
+    let session = match connection.session(request.customer_id) {
+        Some(session) => session,
+        None => return refused,
+    };
+    if !may_play(connection, request.customer_id, request.persona_id).await? {
+        return refused;
+    }
+
+    info!(
+        "Customer {} connected to the transaction server as persona {}",
+        session.customer_id, request.persona_id
+    );
+    connection.customer_id = Some(request.customer_id);
+    connection.persona_id = Some(request.persona_id);
+    connection.state.sessions.touch(request.customer_id);
+
+    // Everything after this reply may be encrypted with the session key
+    connection.session_key = Some(session.session_key);
+
+    Ok(vec![encode(&McotsStatus::success(MC_CLIENT_CONNECT_MSG))?])
+}
+
+// Everything but ClientConnect waits for it to have succeeded, which is
+// also when the connection switches to encrypted traffic
+fn client_connected(connection: &Connection, request: &str) -> bool {
+    if connection.session_key.is_none() {
+        error!(
+            "{} asked for before ClientConnect on connection {}",
+            request, connection.id
+        );
+        return false;
+    }
+    true
+}
+
+// The customer must still be allowed in, and the persona must be theirs
+async fn may_play(connection: &Connection, customer_id: u32, persona_id: u32) -> Result<bool, ()> {
+    let account = connection
+        .with_accounts(move |accounts| accounts.get(customer_id))
+        .await?;
+    if account.is_none_or(|account| account.is_banned) {
+        error!(
+            "Customer {} is banned or gone, refusing the transaction server",
+            customer_id
+        );
+        return Ok(false);
+    }
+    if connection
+        .state
+        .personas
+        .get(customer_id, persona_id)
+        .is_none()
+    {
+        error!(
+            "Customer {} asked to play as persona {}, which isn't theirs",
+            customer_id, persona_id
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+pub(crate) async fn handle_login(connection: &mut Connection, packet: &[u8]) -> HandlerResult {
+    let request = ClientConnect::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse ClientConnect: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if !client_connected(connection, "Transaction login") {
+        return Ok(vec![encode(&McotsStatus::failed(MC_LOGIN))?]);
+    }
+    if connection.customer_id != Some(request.customer_id) {
+        error!(
+            "Transaction login for customer {} on a connection for {:?}",
+            request.customer_id, connection.customer_id
+        );
+        return Ok(vec![encode(&McotsStatus::failed(MC_LOGIN))?]);
+    }
+    if !may_play(connection, request.customer_id, request.persona_id).await? {
+        return Ok(vec![encode(&McotsStatus::failed(MC_LOGIN))?]);
+    }
+
+    connection.persona_id = Some(request.persona_id);
+    Ok(vec![encode(&McotsStatus::success(MC_LOGIN))?])
+}
+
+pub(crate) async fn handle_logout(connection: &mut Connection, _packet: &[u8]) -> HandlerResult {
+    info!(
+        "Customer {:?} logged out of the transaction server",
+        connection.customer_id
+    );
+    connection.persona_id = None;
+    Ok(vec![encode(&McotsStatus::success(MC_LOGOUT))?])
+}
+
+pub(crate) async fn handle_stock_car_info(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = StockCarInfoRequest::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse StockCarInfoRequest: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if !client_connected(connection, "Stock car info") {
+        return Ok(vec![encode(&McotsStatus::failed(MC_STOCK_CAR_INFO))?]);
+    }
+
+    let cars = connection.state.vehicles.stock_cars(request.brand_id);
+    Ok(vec![encode(&StockCarInfo { cars })?])
+}
+
+pub(crate) async fn handle_get_owned_vehicles(
+    connection: &mut Connection,
+    packet: &[u8],
+) -> HandlerResult {
+    let request = OwnedVehiclesRequest::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse OwnedVehiclesRequest: {}", e))?;
+    debug!("Parsed packet: {:?}", request);
+
+    if !client_connected(connection, "Owned vehicles") {
+        return Ok(vec![encode(&McotsStatus::failed(MC_GET_OWNED_VEHICLES))?]);
+    }
+    // Only the persona this connection plays as, whatever the request says
+    let persona_id = match connection.persona_id {
+        Some(persona_id) if persona_id == request.persona_id => persona_id,
+        persona_id => {
+            error!(
+                "Connection {} playing as {:?} asked for the cars of persona {}",
+                connection.id, persona_id, request.persona_id
+            );
+            return Ok(vec![encode(&McotsStatus::failed(MC_GET_OWNED_VEHICLES))?]);
+        }
+    };
+
+    let vehicles = connection.state.vehicles.owned_by(persona_id);
+    Ok(vec![encode(&OwnedVehiclesList { vehicles })?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::Server;
+    use crate::packet::mcots::{message_id, MC_FAILED, MC_OWNED_VEHICLES_LIST, MC_SUCCESS};
+    use crate::test_client::test_state;
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn request(id: u16, values: &[u32]) -> Vec<u8> {
+        let mut bytes = id.to_le_bytes().to_vec();
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn status(responses: &[Vec<u8>]) -> McotsStatus {
+        McotsStatus::from_bytes(&responses[0]).unwrap()
+    }
+
+    // A customer logged in from 10.0.0.1 with one persona, and a transaction
+    // connection from the same host
+    fn connected() -> (Connection, u32, u32) {
+        let state = test_state(None);
+        let account = state.accounts.create("racer", "password").unwrap();
+        let customer_id = account.customer_id;
+        let persona = state.personas.create(customer_id, "Racer", 44).unwrap();
+        state
+            .sessions
+            .insert(customer_id, 1, "10.0.0.1:4000", KEY.to_vec());
+        let connection =
+            Connection::new(9, Server::Transaction, "10.0.0.1:5000".to_string(), state);
+        (connection, customer_id, persona.id)
+    }
+
+    #[tokio::test]
+    async fn client_connect_checks_the_session() {
+        let (mut connection, customer_id, persona_id) = connected();
+        let other = connection
+            .state
+            .accounts
+            .create("rival", "password")
+            .unwrap();
+        let theirs = connection
+            .state
+            .personas
+            .create(other.customer_id, "Rival", 44)
+            .unwrap();
+
+        // Someone else's persona
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, theirs.id]);
+        let responses = handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        assert_eq!(connection.session_key, None);
+
+        // From another host
+        let mut elsewhere = Connection::new(
+            10,
+            Server::Transaction,
+            "10.0.0.2:5000".to_string(),
+            connection.state.clone(),
+        );
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, persona_id]);
+        let responses = handle_client_connect(&mut elsewhere, &packet)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+
+        let responses = handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_SUCCESS);
+        assert_eq!(connection.persona_id, Some(persona_id));
+        assert_eq!(connection.session_key, Some(KEY.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn client_connect_refuses_banned_customers() {
+        let (mut connection, customer_id, persona_id) = connected();
+        connection
+            .state
+            .accounts
+            .set_banned(customer_id, true)
+            .unwrap();
+
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, persona_id]);
+        let responses = handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        assert_eq!(connection.session_key, None);
+        assert_eq!(connection.customer_id, None);
+    }
+
+    #[tokio::test]
+    async fn login_needs_a_client_connect() {
+        let (mut connection, customer_id, persona_id) = connected();
+        let other = connection
+            .state
+            .personas
+            .create(customer_id, "Spare", 44)
+            .unwrap();
+
+        // Refused for asking to play as a persona that doesn't exist
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, 0]);
+        let responses = handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        assert_eq!(connection.customer_id, None);
+
+        let login = request(MC_LOGIN, &[customer_id, other.id]);
+        let responses = handle_login(&mut connection, &login).await.unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        assert_eq!(connection.persona_id, None);
+
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, persona_id]);
+        handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        let responses = handle_login(&mut connection, &login).await.unwrap();
+        assert_eq!(status(&responses).id, MC_SUCCESS);
+        assert_eq!(connection.persona_id, Some(other.id));
+    }
+
+    #[tokio::test]
+    async fn vehicles_need_a_connected_persona() {
+        let (mut connection, customer_id, persona_id) = connected();
+        connection.state.vehicles.grant(persona_id, 101);
+
+        let stock = request(MC_STOCK_CAR_INFO, &[1]);
+        let responses = handle_stock_car_info(&mut connection, &stock)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        let owned = request(MC_GET_OWNED_VEHICLES, &[persona_id]);
+        let responses = handle_get_owned_vehicles(&mut connection, &owned)
+            .await
+            .unwrap();
+        assert_eq!(
+            status(&responses),
+            McotsStatus::failed(MC_GET_OWNED_VEHICLES)
+        );
+
+        let packet = request(MC_CLIENT_CONNECT_MSG, &[customer_id, persona_id]);
+        handle_client_connect(&mut connection, &packet)
+            .await
+            .unwrap();
+        let responses = handle_stock_car_info(&mut connection, &stock)
+            .await
+            .unwrap();
+        assert_ne!(status(&responses).id, MC_FAILED);
+
+        // Only our own cars
+        let theirs = request(MC_GET_OWNED_VEHICLES, &[persona_id + 1]);
+        let responses = handle_get_owned_vehicles(&mut connection, &theirs)
+            .await
+            .unwrap();
+        assert_eq!(status(&responses).id, MC_FAILED);
+        let responses = handle_get_owned_vehicles(&mut connection, &owned)
+            .await
+            .unwrap();
+        let list = OwnedVehiclesList::from_bytes(&responses[0]).unwrap();
+        assert_eq!(message_id(&responses[0]), Ok(MC_OWNED_VEHICLES_LIST));
+        assert_eq!(list.vehicles.len(), 1);
+    }
+}
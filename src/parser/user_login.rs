@@ -1,38 +1,88 @@
-use tokio::{fs::File, io::AsyncReadExt};
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
 
-use crate::packet::PrefixedField;
+use crate::dispatch::{encode, Connection, HandlerResult};
+use crate::packet::error::{ErrorResponse, LOGIN_INVALID_TICKET, LOGIN_SERVER_ERROR};
+use crate::packet::user_status::UserStatus;
+use crate::packet::{Decode, PrefixedField};
 
-pub(crate) async fn handle_user_login(packet: &[u8]) -> Result<Vec<Vec<u8>>, ()> {
-    let parsed_packet = crate::packet::login_request::LoginRequest::from_bytes(&packet);
+pub(crate) async fn handle_user_login(connection: &mut Connection, packet: &[u8]) -> HandlerResult {
+    info!("Login request from {}", connection.peer);
+    let parsed_packet = crate::packet::login_request::LoginRequest::from_bytes(packet)
+        .map_err(|e| error!("Failed to parse login request: {}", e))?;
 
     debug!("Parsed packet: {:?}", parsed_packet);
 
+    // The context id is the ticket the web AuthLogin endpoint handed out
+    let ticket = parsed_packet.get_context_id().to_string();
+    let redeemed = connection
+        .with_accounts(move |accounts| accounts.redeem_ticket(&ticket))
+        .await?;
+    let account = match redeemed {
+        Some(account) => account,
+        None => {
+            error!(
+                "Rejecting login with unknown or expired ticket {}",
+                parsed_packet.get_context_id()
+            );
+            return Ok(vec![encode(&ErrorResponse::login_failed(
+                LOGIN_INVALID_TICKET,
+            ))?]);
+        }
+    };
+    info!(
+        "Ticket belongs to {} (customer {})",
+        account.username, account.customer_id
+    );
+
     // There are a few steps needed to decrypt the session key and make it usable
 
     // 1. Start by reading the encrypted session key and displaying it as an ascii string
     let session_key = parsed_packet.get_encrypted_session_key();
     debug!("Encrypted session key: {}", session_key);
 
-    let decrypted_session_key = match decrypt_session_key(session_key).await {
-        Ok(value) => value,
-        Err(value) => {
-            error!("Failed to decrypt session key: {:?}", value);
-            return Err(());
-        }
-    };
-    
-    // Let's print the decrypted session key as a hex string
+    let decrypted_session_key =
+        match decrypt_session_key(&connection.state.keys.private_key(), session_key) {
+            Ok(value) => value,
+            Err(value) => {
+                error!("Failed to decrypt session key: {:?}", value);
+                return Ok(vec![encode(&ErrorResponse::login_failed(
+                    LOGIN_SERVER_ERROR,
+                ))?]);
+            }
+        };
+
+    // The key itself stays out of the logs, it encrypts everything after login
     debug!(
-        "Decrypted session key: {}",
-        hex::encode(&decrypted_session_key)
+        "Decrypted a {} byte session key",
+        decrypted_session_key.len()
+    );
+
+    // Remember the key so the persona and lobby servers can find it
+    connection.state.sessions.insert(
+        account.customer_id,
+        connection.id,
+        &connection.peer,
+        decrypted_session_key.clone(),
     );
+    connection.customer_id = Some(account.customer_id);
 
-    let response_message = vec![0x06, 0x01, 0x00, 0x04];
+    let user_status = UserStatus::new(
+        account.customer_id,
+        account.is_banned,
+        account.is_gagged,
+        parsed_packet.get_context_id(),
+        &decrypted_session_key,
+    );
+    debug!("User status: {:?}", user_status);
 
-    Ok(vec![response_message])
+    Ok(vec![encode(&user_status)?])
 }
 
-async fn decrypt_session_key(session_key: &str) -> Result<Vec<u8>, ()> {
+pub(crate) fn decrypt_session_key(
+    private_key: &Rsa<Private>,
+    session_key: &str,
+) -> Result<Vec<u8>, ()> {
     let session_key_decode_result = hex::decode(session_key);
     let session_key_bytes = match session_key_decode_result {
         Ok(bytes) => {
@@ -44,35 +94,11 @@ async fn decrypt_session_key(session_key: &str) -> Result<Vec<u8>, ()> {
             return Err(());
         }
     };
-    if session_key_bytes.len() != 128 {
+    // The client encrypts with the public half of our key, so the sizes match
+    if session_key_bytes.len() != private_key.size() as usize {
         error!("Invalid session key length: {}", session_key_bytes.len());
         return Err(());
     }
-    let private_key_open_result = File::open("data/private_key.pem").await;
-    let mut file = match private_key_open_result {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Failed to open private key file: {}", e);
-            return Err(());
-        }
-    };
-    let mut private_key_bytes = Vec::new();
-    let read_result = file.read_to_end(&mut private_key_bytes).await;
-    match read_result {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Failed to read private key file: {}", e);
-            return Err(());
-        }
-    };
-    let private_key_create_result = openssl::rsa::Rsa::private_key_from_pem(&private_key_bytes);
-    let private_key = match private_key_create_result {
-        Ok(key) => key,
-        Err(e) => {
-            error!("Failed to create private key: {}", e);
-            return Err(());
-        }
-    };
     let mut decrypted_session_key_bytes = vec![0; private_key.size() as usize];
     let key_decrypt_result = private_key.private_decrypt(
         &session_key_bytes,
@@ -89,7 +115,9 @@ async fn decrypt_session_key(session_key: &str) -> Result<Vec<u8>, ()> {
             return Err(());
         }
     };
-    let decrypted_session_key_prefixed_field = PrefixedField::from_bytes(&decrypted_session_key_bytes);
+    let decrypted_session_key_prefixed_field =
+        PrefixedField::from_bytes(&decrypted_session_key_bytes)
+            .map_err(|e| error!("Failed to parse decrypted session key: {}", e))?;
     let decrypted_session_key = decrypted_session_key_prefixed_field.data;
     if decrypted_session_key.len() != 32 {
         error!(
@@ -0,0 +1,496 @@
+// Desc: Replay captured sessions through the dispatcher
+//
+// Client frames from a capture are fed through `handle_packet`, or
+// `handle_transaction_packet` for MCOTS bodies, in the order they were
+// recorded, and whatever the handlers send back is compared with the server
+// frames that were recorded after them. The first difference is reported
+// with the name of the field it falls in. Frames the server sent without
+// being asked are skipped.
+//
+// Replays run against a throwaway account database. The tickets the
+// captured logins redeemed are put back into it first, each with the
+// account its UserStatus reply describes, so a capture can start from a
+// login. Personas live in memory, so any made before the capture started
+// are not there on replay.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::capture::{read_capture, CaptureRecord, Direction};
+use crate::dispatch::{Connection, HandlerResult, Server};
+use crate::inspect::field_at;
+use crate::net::{handle_packet, handle_transaction_packet};
+use crate::packet::login_request::LoginRequest;
+use crate::packet::user_status::UserStatus;
+use crate::packet::Decode;
+use crate::state::State;
+use crate::store::account::{Account, AccountStore};
+
+// Where a replay first stopped matching the capture
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Divergence {
+    // Both sides sent a frame but the bytes differ
+    Mismatch {
+        connection_id: u64,
+        server: Server,
+        response: usize,
+        offset: usize,
+        field: String,
+        expected: Option<u8>,
+        actual: Option<u8>,
+    },
+    // The capture has a server frame the handlers didn't produce
+    Missing {
+        connection_id: u64,
+        server: Server,
+        response: usize,
+    },
+    // The handlers produced a frame the capture doesn't have
+    Extra {
+        connection_id: u64,
+        server: Server,
+        response: usize,
+    },
+}
+
+fn show_byte(byte: Option<u8>) -> String {
+    match byte {
+        Some(byte) => format!("0x{:02x}", byte),
+        None => "end of frame".to_string(),
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Mismatch {
+                connection_id,
+                server,
+                response,
+                offset,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "connection {} ({}) response {} differs at byte {} ({}): expected {}, got {}",
+                connection_id,
+                server,
+                response,
+                offset,
+                field,
+                show_byte(*expected),
+                show_byte(*actual)
+            ),
+            Divergence::Missing {
+                connection_id,
+                server,
+                response,
+            } => write!(
+                f,
+                "connection {} ({}) response {} was recorded but never sent",
+                connection_id, server, response
+            ),
+            Divergence::Extra {
+                connection_id,
+                server,
+                response,
+            } => write!(
+                f,
+                "connection {} ({}) response {} was sent but never recorded",
+                connection_id, server, response
+            ),
+        }
+    }
+}
+
+fn compare(
+    connection: &Connection,
+    response: usize,
+    expected: &[u8],
+    actual: &[u8],
+) -> Option<Divergence> {
+    let offset =
+        (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))?;
+    Some(Divergence::Mismatch {
+        connection_id: connection.id,
+        server: connection.server,
+        response,
+        offset,
+        field: field_at(connection.server, expected, offset),
+        expected: expected.get(offset).copied(),
+        actual: actual.get(offset).copied(),
+    })
+}
+
+// A replayed connection and the responses it still owes the capture
+struct Replayed {
+    connection: Connection,
+    pending: Vec<Vec<u8>>,
+    matched: usize,
+}
+
+impl Replayed {
+    fn leftover(&self) -> Option<Divergence> {
+        if self.pending.len() > self.matched {
+            return Some(Divergence::Extra {
+                connection_id: self.connection.id,
+                server: self.connection.server,
+                response: self.matched,
+            });
+        }
+        None
+    }
+}
+
+// Put back the ticket each captured login redeemed. Logins the server
+// refused are left out, so they are refused again.
+fn seed_logins(records: &[CaptureRecord], accounts: &AccountStore) {
+    let mut tickets: HashMap<u64, String> = HashMap::new();
+    for record in records
+        .iter()
+        .filter(|record| record.server == Server::Login)
+    {
+        match record.direction {
+            Direction::Inbound => {
+                if let Ok(request) = LoginRequest::from_bytes(&record.frame) {
+                    tickets.insert(record.connection_id, request.get_context_id().to_string());
+                }
+            }
+            Direction::Outbound => {
+                let Some(ticket) = tickets.remove(&record.connection_id) else {
+                    continue;
+                };
+                let Ok(status) = UserStatus::from_bytes(&record.frame) else {
+                    continue;
+                };
+                let account = Account {
+                    customer_id: status.customer_id,
+                    username: format!("customer-{}", status.customer_id),
+                    is_banned: status.is_banned,
+                    is_gagged: status.is_gagged,
+                };
+                // A failure shows up as a refused login in the replay
+                let _ = accounts.insert_login(&account, &ticket);
+            }
+            Direction::Unsolicited => {}
+        }
+    }
+}
+
+// NPS frames carry their header, MCOTS frames were recorded as bare bodies
+async fn handle_frame(connection: &mut Connection, frame: &[u8]) -> HandlerResult {
+    match connection.server {
+        Server::Transaction => handle_transaction_packet(connection, frame).await,
+        _ => handle_packet(connection, frame).await,
+    }
+}
+
+// Run every client frame through the handlers, stopping at the first divergence.
+// The captured logins are seeded into `state`, which should be thrown away after.
+pub(crate) async fn replay(records: &[CaptureRecord], state: Arc<State>) -> Result<(), Divergence> {
+    seed_logins(records, &state.accounts);
+    let mut connections: HashMap<u64, Replayed> = HashMap::new();
+
+    for record in records {
+        let replayed = connections
+            .entry(record.connection_id)
+            .or_insert_with(|| Replayed {
+                connection: Connection::new(
+                    record.connection_id,
+                    record.server,
+                    record.peer.clone(),
+                    state.clone(),
+                ),
+                pending: Vec::new(),
+                matched: 0,
+            });
+
+        match record.direction {
+            Direction::Inbound => {
+                if let Some(divergence) = replayed.leftover() {
+                    return Err(divergence);
+                }
+                // A handler error closes the connection, so nothing is sent
+                replayed.pending = handle_frame(&mut replayed.connection, &record.frame)
+                    .await
+                    .unwrap_or_default();
+                replayed.matched = 0;
+            }
+            Direction::Outbound => {
+                let response = replayed.matched;
+                let actual = match replayed.pending.get(response) {
+                    Some(actual) => actual,
+                    None => {
+                        return Err(Divergence::Missing {
+                            connection_id: record.connection_id,
+                            server: record.server,
+                            response,
+                        })
+                    }
+                };
+                if let Some(divergence) =
+                    compare(&replayed.connection, response, &record.frame, actual)
+                {
+                    return Err(divergence);
+                }
+                replayed.matched += 1;
+            }
+            // Kicks, operator messages and shutdown log offs depend on what
+            // was happening on the server, not on the client's frames
+            Direction::Unsolicited => {}
+        }
+    }
+
+    for replayed in connections.values() {
+        if let Some(divergence) = replayed.leftover() {
+            return Err(divergence);
+        }
+    }
+    Ok(())
+}
+
+// The `replay` subcommand, returns whether the capture replayed cleanly
+pub(crate) async fn run(path: &str, state: Arc<State>) -> Result<bool, ()> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Failed to read {}: {}", path, e);
+            return Err(());
+        }
+    };
+    let records = match read_capture(&bytes) {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Failed to parse {}: {}", path, e);
+            return Err(());
+        }
+    };
+
+    let inbound = records
+        .iter()
+        .filter(|record| record.direction == Direction::Inbound)
+        .count();
+    println!(
+        "Replaying {} frames ({} from clients) from {}",
+        records.len(),
+        inbound,
+        path
+    );
+    match replay(&records, state).await {
+        Ok(()) => {
+            println!("Every response matched the capture");
+            Ok(true)
+        }
+        Err(divergence) => {
+            println!("{}", divergence);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::error::{ErrorResponse, LOGIN_INVALID_TICKET};
+    use crate::packet::lobby::SystemMessage;
+    use crate::packet::mcots::{McotsStatus, MC_CLIENT_CONNECT_MSG, MC_LOGIN, MC_LOGOUT};
+    use crate::packet::persona::{PersonaCreated, NPS_CREATE_PERSONA};
+    use crate::packet::Encode;
+    use crate::test_client::{get_persona_maps, login_request, persona_name_request, test_state};
+
+    const CUSTOMER_ID: u32 = 0x0001_0001;
+    const TICKET: &str = "0123456789abcdef0123456789abcdef";
+    const SESSION_KEY: [u8; 32] = [0x42; 32];
+
+    // Every connection comes from the same host, as one client's would
+    fn record(
+        connection_id: u64,
+        server: Server,
+        direction: Direction,
+        frame: Vec<u8>,
+    ) -> CaptureRecord {
+        CaptureRecord {
+            timestamp: 0,
+            connection_id,
+            server,
+            direction,
+            peer: format!("127.0.0.1:{}", 50000 + connection_id),
+            frame,
+        }
+    }
+
+    // The customer logs in with a ticket the server issued before the capture
+    fn login() -> Vec<CaptureRecord> {
+        let status = UserStatus::new(CUSTOMER_ID, false, false, TICKET, &SESSION_KEY);
+        vec![
+            record(
+                1,
+                Server::Login,
+                Direction::Inbound,
+                login_request(TICKET, &SESSION_KEY),
+            ),
+            record(
+                1,
+                Server::Login,
+                Direction::Outbound,
+                status.to_bytes().unwrap(),
+            ),
+        ]
+    }
+
+    fn persona_session(response: Vec<u8>) -> Vec<CaptureRecord> {
+        let mut records = login();
+        records.push(record(
+            2,
+            Server::Persona,
+            Direction::Inbound,
+            get_persona_maps(CUSTOMER_ID),
+        ));
+        records.push(record(2, Server::Persona, Direction::Outbound, response));
+        records
+    }
+
+    // A little endian MCOTS body
+    fn mcots(id: u16, values: &[u32]) -> Vec<u8> {
+        let mut body = id.to_le_bytes().to_vec();
+        for value in values {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn matching_capture_replays_cleanly() {
+        // A customer with no personas gets an empty list
+        let records = persona_session(vec![0x06, 0x07, 0x00, 0x06, 0x00, 0x00]);
+        assert_eq!(replay(&records, test_state(None)).await, Ok(()));
+        // The ticket is seeded again, so the same capture replays twice
+        assert_eq!(replay(&records, test_state(None)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn refused_logins_are_refused_again() {
+        let mut records = login();
+        records[1].frame = ErrorResponse::login_failed(LOGIN_INVALID_TICKET)
+            .to_bytes()
+            .unwrap();
+        let state = test_state(None);
+        assert_eq!(replay(&records, state.clone()).await, Ok(()));
+        assert!(state.accounts.get(CUSTOMER_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn divergence_names_the_field() {
+        // The capture says the customer had a persona, the replay has none
+        let mut recorded = vec![0x06, 0x07, 0x00, 0x2e, 0x00, 0x01];
+        recorded.extend_from_slice(&[0; 40]);
+        let records = persona_session(recorded);
+        assert_eq!(
+            replay(&records, test_state(None)).await,
+            Err(Divergence::Mismatch {
+                connection_id: 2,
+                server: Server::Persona,
+                response: 0,
+                offset: 3,
+                field: "PersonaMaps.header.length".to_string(),
+                expected: Some(0x2e),
+                actual: Some(0x06),
+            })
+        );
+
+        let mut records = persona_session(vec![0x06, 0x07, 0x00, 0x06, 0x00, 0x00]);
+        records.push(record(
+            2,
+            Server::Persona,
+            Direction::Outbound,
+            vec![0x02, 0x07],
+        ));
+        assert!(matches!(
+            replay(&records, test_state(None)).await,
+            Err(Divergence::Missing { response: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn transaction_frames_are_replayed() {
+        // Log in, make a persona and take it to the transaction server
+        let mut records = login();
+        for (direction, frame) in [
+            (
+                Direction::Inbound,
+                persona_name_request(NPS_CREATE_PERSONA, CUSTOMER_ID, "Racer"),
+            ),
+            (
+                Direction::Outbound,
+                PersonaCreated::new(1).to_bytes().unwrap(),
+            ),
+        ] {
+            records.push(record(2, Server::Persona, direction, frame));
+        }
+        for (direction, frame) in [
+            (
+                Direction::Inbound,
+                mcots(MC_CLIENT_CONNECT_MSG, &[CUSTOMER_ID, 1]),
+            ),
+            (
+                Direction::Outbound,
+                McotsStatus::success(MC_CLIENT_CONNECT_MSG)
+                    .to_bytes()
+                    .unwrap(),
+            ),
+            (Direction::Inbound, mcots(MC_LOGOUT, &[])),
+            (
+                Direction::Outbound,
+                McotsStatus::success(MC_LOGOUT).to_bytes().unwrap(),
+            ),
+        ] {
+            records.push(record(3, Server::Transaction, direction, frame));
+        }
+        assert_eq!(replay(&records, test_state(None)).await, Ok(()));
+
+        // A capture where the server refused the connect no longer matches
+        records[5].frame = McotsStatus::failed(MC_CLIENT_CONNECT_MSG)
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            replay(&records, test_state(None)).await,
+            Err(Divergence::Mismatch {
+                connection_id: 3,
+                server: Server::Transaction,
+                response: 0,
+                offset: 0,
+                field: "McotsStatus.id".to_string(),
+                expected: Some(102),
+                actual: Some(101),
+            })
+        );
+
+        // Or one that answered a different request
+        records[5].frame = McotsStatus::success(MC_LOGIN).to_bytes().unwrap();
+        assert_eq!(
+            replay(&records, test_state(None)).await,
+            Err(Divergence::Mismatch {
+                connection_id: 3,
+                server: Server::Transaction,
+                response: 0,
+                offset: 2,
+                field: "McotsStatus.request_id".to_string(),
+                expected: Some(MC_LOGIN as u8),
+                actual: Some(MC_CLIENT_CONNECT_MSG as u8),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unsolicited_frames_are_skipped() {
+        // An operator message arrives between the request and its response
+        let mut records = persona_session(vec![0x06, 0x07, 0x00, 0x06, 0x00, 0x00]);
+        let message = SystemMessage::new("Restarting soon").to_bytes().unwrap();
+        records.insert(
+            3,
+            record(2, Server::Persona, Direction::Unsolicited, message),
+        );
+        assert_eq!(replay(&records, test_state(None)).await, Ok(()));
+    }
+}
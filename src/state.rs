@@ -0,0 +1,75 @@
+// Desc: State shared by every listener
+
+use std::sync::{Arc, RwLock};
+
+use tokio_util::task::TaskTracker;
+
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::dispatch::Registry;
+use crate::keys::KeyManager;
+use crate::store::account::AccountStore;
+use crate::store::connection::ConnectionStore;
+use crate::store::feed::PacketFeed;
+use crate::store::persona::PersonaStore;
+use crate::store::session::{SessionStore, SESSION_TTL};
+use crate::store::vehicle::VehicleStore;
+
+pub(crate) struct State {
+    pub(crate) handlers: Registry,
+    pub(crate) accounts: AccountStore,
+    pub(crate) personas: PersonaStore,
+    pub(crate) sessions: SessionStore,
+    pub(crate) vehicles: VehicleStore,
+    pub(crate) capture: Capture,
+    // Swapped whole when the console reloads the config
+    config: RwLock<Arc<Config>>,
+    pub(crate) keys: KeyManager,
+    pub(crate) connections: ConnectionStore,
+    pub(crate) feed: PacketFeed,
+    // Every task serving a client, so shutdown can wait for them
+    pub(crate) tasks: TaskTracker,
+}
+
+impl State {
+    pub(crate) fn new(
+        handlers: Registry,
+        accounts: AccountStore,
+        capture: Capture,
+        config: Config,
+        keys: KeyManager,
+    ) -> State {
+        State {
+            handlers,
+            accounts,
+            personas: PersonaStore::new(),
+            sessions: SessionStore::new(SESSION_TTL),
+            vehicles: VehicleStore::new(),
+            capture,
+            config: RwLock::new(Arc::new(config)),
+            keys,
+            connections: ConnectionStore::new(),
+            feed: PacketFeed::new(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    // The settings as they are now, a later reload doesn't change what this returns
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    // The account database blocks, so calls into it run off the runtime.
+    // Callers decide what a failed task means for them.
+    pub(crate) async fn with_accounts<T: Send + 'static>(
+        self: &Arc<Self>,
+        call: impl FnOnce(&AccountStore) -> T + Send + 'static,
+    ) -> Result<T, tokio::task::JoinError> {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || call(&state.accounts)).await
+    }
+}
@@ -0,0 +1,440 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use rusqlite::{params, OptionalExtension};
+
+// The client logs in to the NPS server straight after fetching a ticket
+pub(crate) const TICKET_TTL: Duration = Duration::from_secs(5 * 60);
+
+const PASSWORD_ITERATIONS: usize = 100_000;
+const PASSWORD_HASH_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+
+// Customer ids start here so they never collide with anything the client treats as special
+const FIRST_CUSTOMER_ID: u32 = 0x0001_0001;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Account {
+    pub(crate) customer_id: u32,
+    pub(crate) username: String,
+    pub(crate) is_banned: bool,
+    pub(crate) is_gagged: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AccountError {
+    UsernameTaken,
+    InvalidUsername,
+    EmptyPassword,
+    Database,
+}
+
+// Customers, their passwords and the login tickets issued to them
+pub(crate) struct AccountStore {
+    db: Mutex<rusqlite::Connection>,
+}
+
+impl AccountStore {
+    pub(crate) fn open(path: &str) -> Result<AccountStore, ()> {
+        match rusqlite::Connection::open(path) {
+            Ok(db) => AccountStore::from_connection(db),
+            Err(e) => {
+                error!("Failed to open account database {}: {}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    pub(crate) fn open_in_memory() -> Result<AccountStore, ()> {
+        match rusqlite::Connection::open_in_memory() {
+            Ok(db) => AccountStore::from_connection(db),
+            Err(e) => {
+                error!("Failed to open in-memory account database: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn from_connection(db: rusqlite::Connection) -> Result<AccountStore, ()> {
+        let schema = "CREATE TABLE IF NOT EXISTS accounts (
+                customer_id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash BLOB NOT NULL,
+                salt BLOB NOT NULL,
+                is_banned INTEGER NOT NULL DEFAULT 0,
+                is_gagged INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS tickets (
+                ticket TEXT PRIMARY KEY,
+                customer_id INTEGER NOT NULL REFERENCES accounts(customer_id) ON DELETE CASCADE,
+                expires_at INTEGER NOT NULL
+            );";
+        if let Err(e) = db.execute_batch(schema) {
+            error!("Failed to create account tables: {}", e);
+            return Err(());
+        }
+        Ok(AccountStore { db: Mutex::new(db) })
+    }
+
+    pub(crate) fn create(&self, username: &str, password: &str) -> Result<Account, AccountError> {
+        if username.is_empty() || username.len() > 64 {
+            return Err(AccountError::InvalidUsername);
+        }
+        if password.is_empty() {
+            return Err(AccountError::EmptyPassword);
+        }
+        let mut salt = [0; SALT_LENGTH];
+        if let Err(e) = openssl::rand::rand_bytes(&mut salt) {
+            error!("Failed to generate salt: {}", e);
+            return Err(AccountError::Database);
+        }
+        let password_hash = hash_password(password, &salt).ok_or(AccountError::Database)?;
+
+        let db = self.db.lock().unwrap();
+        let next_id: u32 = db
+            .query_row(
+                "SELECT COALESCE(MAX(customer_id) + 1, ?1) FROM accounts",
+                params![FIRST_CUSTOMER_ID],
+                |row| row.get(0),
+            )
+            .map_err(database_error)?;
+        match db.execute(
+            "INSERT INTO accounts (customer_id, username, password_hash, salt) VALUES (?1, ?2, ?3, ?4)",
+            params![next_id, username, password_hash, salt.to_vec()],
+        ) {
+            Ok(_) => Ok(Account {
+                customer_id: next_id,
+                username: username.to_string(),
+                is_banned: false,
+                is_gagged: false,
+            }),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(AccountError::UsernameTaken)
+            }
+            Err(e) => Err(database_error(e)),
+        }
+    }
+
+    pub(crate) fn exists(&self, username: &str) -> bool {
+        let db = self.db.lock().unwrap();
+        db.query_row(
+            "SELECT 1 FROM accounts WHERE username = ?1",
+            params![username],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|found| found.is_some())
+        .unwrap_or(false)
+    }
+
+    // Check a username and password, returning the account if they match
+    pub(crate) fn authenticate(&self, username: &str, password: &str) -> Option<Account> {
+        let db = self.db.lock().unwrap();
+        let row = db
+            .query_row(
+                "SELECT customer_id, username, is_banned, is_gagged, password_hash, salt
+                 FROM accounts WHERE username = ?1",
+                params![username],
+                |row| {
+                    Ok((
+                        account_from_row(row)?,
+                        row.get::<_, Vec<u8>>(4)?,
+                        row.get::<_, Vec<u8>>(5)?,
+                    ))
+                },
+            )
+            .optional();
+        let (account, password_hash, salt) = match row {
+            Ok(Some((account, password_hash, salt))) => (Some(account), password_hash, salt),
+            // Hash the attempt anyway, so how long this takes doesn't tell
+            // whether the username exists
+            Ok(None) => (None, vec![0; PASSWORD_HASH_LENGTH], vec![0; SALT_LENGTH]),
+            Err(e) => {
+                database_error(e);
+                return None;
+            }
+        };
+
+        let attempt = hash_password(password, &salt)?;
+        if attempt.len() == password_hash.len() && openssl::memcmp::eq(&attempt, &password_hash) {
+            account
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn get(&self, customer_id: u32) -> Option<Account> {
+        let db = self.db.lock().unwrap();
+        db.query_row(
+            "SELECT customer_id, username, is_banned, is_gagged FROM accounts WHERE customer_id = ?1",
+            params![customer_id],
+            account_from_row,
+        )
+        .optional()
+        .unwrap_or_else(|e| {
+            database_error(e);
+            None
+        })
+    }
+
+    // Every account, by customer id
+    pub(crate) fn list(&self) -> Vec<Account> {
+        let db = self.db.lock().unwrap();
+        let accounts = db
+            .prepare(
+                "SELECT customer_id, username, is_banned, is_gagged FROM accounts
+                 ORDER BY customer_id",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], account_from_row)?
+                    .collect::<rusqlite::Result<Vec<Account>>>()
+            });
+        accounts.unwrap_or_else(|e| {
+            database_error(e);
+            Vec::new()
+        })
+    }
+
+    // Returns false if there is no such account
+    pub(crate) fn set_banned(&self, customer_id: u32, banned: bool) -> Result<bool, AccountError> {
+        let db = self.db.lock().unwrap();
+        let changed = db
+            .execute(
+                "UPDATE accounts SET is_banned = ?2 WHERE customer_id = ?1",
+                params![customer_id, banned],
+            )
+            .map_err(database_error)?;
+        if banned {
+            // Tickets already handed out shouldn't get a banned customer in
+            db.execute(
+                "DELETE FROM tickets WHERE customer_id = ?1",
+                params![customer_id],
+            )
+            .map_err(database_error)?;
+        }
+        Ok(changed > 0)
+    }
+
+    // Returns false if there is no such account
+    pub(crate) fn set_gagged(&self, customer_id: u32, gagged: bool) -> Result<bool, AccountError> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "UPDATE accounts SET is_gagged = ?2 WHERE customer_id = ?1",
+            params![customer_id, gagged],
+        )
+        .map(|changed| changed > 0)
+        .map_err(database_error)
+    }
+
+    // Returns false if there is no such account
+    pub(crate) fn set_password(
+        &self,
+        customer_id: u32,
+        password: &str,
+    ) -> Result<bool, AccountError> {
+        if password.is_empty() {
+            return Err(AccountError::EmptyPassword);
+        }
+        let mut salt = [0; SALT_LENGTH];
+        if let Err(e) = openssl::rand::rand_bytes(&mut salt) {
+            error!("Failed to generate salt: {}", e);
+            return Err(AccountError::Database);
+        }
+        let password_hash = hash_password(password, &salt).ok_or(AccountError::Database)?;
+
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "UPDATE accounts SET password_hash = ?2, salt = ?3 WHERE customer_id = ?1",
+            params![customer_id, password_hash, salt.to_vec()],
+        )
+        .map(|changed| changed > 0)
+        .map_err(database_error)
+    }
+
+    // Mint a single use login ticket for an account
+    pub(crate) fn issue_ticket(&self, customer_id: u32) -> Result<String, ()> {
+        let mut ticket_bytes = [0; 16];
+        if let Err(e) = openssl::rand::rand_bytes(&mut ticket_bytes) {
+            error!("Failed to generate ticket: {}", e);
+            return Err(());
+        }
+        let ticket = hex::encode(ticket_bytes);
+        let now = unix_time();
+
+        let db = self.db.lock().unwrap();
+        let result = db
+            .execute("DELETE FROM tickets WHERE expires_at <= ?1", params![now])
+            .and_then(|_| {
+                db.execute(
+                    "INSERT INTO tickets (ticket, customer_id, expires_at) VALUES (?1, ?2, ?3)",
+                    params![ticket, customer_id, now + TICKET_TTL.as_secs() as i64],
+                )
+            });
+        match result {
+            Ok(_) => Ok(ticket),
+            Err(e) => {
+                database_error(e);
+                Err(())
+            }
+        }
+    }
+
+    // Tickets are single use, redeeming one removes it.
+    // Returns `None` for unknown or expired tickets.
+    pub(crate) fn redeem_ticket(&self, ticket: &str) -> Option<Account> {
+        let db = self.db.lock().unwrap();
+        let customer_id: Option<(u32, i64)> = db
+            .query_row(
+                "DELETE FROM tickets WHERE ticket = ?1 RETURNING customer_id, expires_at",
+                params![ticket],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                database_error(e);
+                None
+            });
+        let (customer_id, expires_at) = customer_id?;
+        if expires_at <= unix_time() {
+            return None;
+        }
+        drop(db);
+        self.get(customer_id)
+    }
+
+    // Put back an account and a ticket it logged in with, so a captured login
+    // can be replayed against a throwaway database. The account has no
+    // password and the ticket never expires.
+    pub(crate) fn insert_login(&self, account: &Account, ticket: &str) -> Result<(), AccountError> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR IGNORE INTO accounts (customer_id, username, password_hash, salt, is_banned, is_gagged)
+             VALUES (?1, ?2, x'', x'', ?3, ?4)",
+            params![
+                account.customer_id,
+                account.username,
+                account.is_banned,
+                account.is_gagged
+            ],
+        )
+        .and_then(|_| {
+            db.execute(
+                "INSERT OR REPLACE INTO tickets (ticket, customer_id, expires_at) VALUES (?1, ?2, ?3)",
+                params![ticket, account.customer_id, i64::MAX],
+            )
+        })
+        .map_err(database_error)?;
+        Ok(())
+    }
+}
+
+fn account_from_row(row: &rusqlite::Row) -> rusqlite::Result<Account> {
+    Ok(Account {
+        customer_id: row.get(0)?,
+        username: row.get(1)?,
+        is_banned: row.get(2)?,
+        is_gagged: row.get(3)?,
+    })
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Option<Vec<u8>> {
+    let mut hash = vec![0; PASSWORD_HASH_LENGTH];
+    match openssl::pkcs5::pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        PASSWORD_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut hash,
+    ) {
+        Ok(()) => Some(hash),
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            None
+        }
+    }
+}
+
+fn database_error(e: rusqlite::Error) -> AccountError {
+    error!("Account database error: {}", e);
+    AccountError::Database
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_checked() {
+        let accounts = AccountStore::open_in_memory().unwrap();
+        let account = accounts.create("racer", "hunter2").unwrap();
+        assert_eq!(account.customer_id, FIRST_CUSTOMER_ID);
+
+        assert_eq!(
+            accounts.authenticate("racer", "hunter2"),
+            Some(account.clone())
+        );
+        assert_eq!(accounts.authenticate("racer", "hunter3"), None);
+        assert_eq!(accounts.authenticate("nobody", "hunter2"), None);
+        assert_eq!(
+            accounts.create("RACER", "other"),
+            Err(AccountError::UsernameTaken)
+        );
+        assert_eq!(
+            accounts.create("rival", ""),
+            Err(AccountError::EmptyPassword)
+        );
+        assert_eq!(
+            accounts.set_password(account.customer_id, ""),
+            Err(AccountError::EmptyPassword)
+        );
+        assert!(!accounts.exists("rival"));
+    }
+
+    #[test]
+    fn tickets_are_single_use() {
+        let accounts = AccountStore::open_in_memory().unwrap();
+        let account = accounts.create("racer", "hunter2").unwrap();
+        let ticket = accounts.issue_ticket(account.customer_id).unwrap();
+
+        assert_eq!(accounts.redeem_ticket(&ticket), Some(account));
+        assert_eq!(accounts.redeem_ticket(&ticket), None);
+        assert_eq!(accounts.redeem_ticket("not a ticket"), None);
+    }
+
+    #[test]
+    fn operators_can_moderate_accounts() {
+        let accounts = AccountStore::open_in_memory().unwrap();
+        let account = accounts.create("racer", "hunter2").unwrap();
+        let other = accounts.create("rival", "hunter2").unwrap();
+        let ticket = accounts.issue_ticket(account.customer_id).unwrap();
+
+        assert_eq!(accounts.set_banned(account.customer_id, true), Ok(true));
+        assert_eq!(accounts.set_gagged(other.customer_id, true), Ok(true));
+        assert_eq!(accounts.set_banned(1, true), Ok(false));
+        assert_eq!(accounts.redeem_ticket(&ticket), None);
+        let flags: Vec<(bool, bool)> = accounts
+            .list()
+            .iter()
+            .map(|account| (account.is_banned, account.is_gagged))
+            .collect();
+        assert_eq!(flags, [(true, false), (false, true)]);
+
+        assert_eq!(
+            accounts.set_password(account.customer_id, "hunter3"),
+            Ok(true)
+        );
+        assert_eq!(accounts.authenticate("racer", "hunter2"), None);
+        assert!(accounts.authenticate("racer", "hunter3").is_some());
+    }
+}
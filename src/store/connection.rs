@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Instant;
+
+use tokio::sync::mpsc;
+
+use crate::dispatch::{Connection, Server};
+
+// Asks a connection's task to do something on the operator's behalf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Control {
+    // Log the client off and hang up
+    Kick,
+    // Send a plaintext frame, the connection encrypts it if it has to
+    Send(Vec<u8>),
+}
+
+// What the console gets to see of a connection
+#[derive(Debug, Clone)]
+pub(crate) struct LiveConnection {
+    pub(crate) id: u64,
+    pub(crate) server: Server,
+    pub(crate) peer: String,
+    pub(crate) customer_id: Option<u32>,
+    pub(crate) persona_id: Option<u32>,
+    pub(crate) connected: Instant,
+}
+
+// How a listener has been getting on since startup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ListenerHealth {
+    pub(crate) listening: bool,
+    pub(crate) accepted: u64,
+    pub(crate) failed: u64,
+}
+
+struct Entry {
+    connection: LiveConnection,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+// Every connection currently being served, across all listeners
+#[derive(Default)]
+pub(crate) struct ConnectionStore {
+    connections: RwLock<HashMap<u64, Entry>>,
+    listeners: RwLock<HashMap<Server, ListenerHealth>>,
+}
+
+impl ConnectionStore {
+    pub(crate) fn new() -> ConnectionStore {
+        ConnectionStore::default()
+    }
+
+    // Returns where the connection's task receives control messages
+    pub(crate) fn register(&self, connection: &Connection) -> mpsc::UnboundedReceiver<Control> {
+        let (control, receiver) = mpsc::unbounded_channel();
+        let mut connections = self.connections.write().unwrap();
+        connections.insert(
+            connection.id,
+            Entry {
+                connection: LiveConnection {
+                    id: connection.id,
+                    server: connection.server,
+                    peer: connection.peer.clone(),
+                    customer_id: connection.customer_id,
+                    persona_id: connection.persona_id,
+                    connected: Instant::now(),
+                },
+                control,
+            },
+        );
+        receiver
+    }
+
+    // Handlers fill in who is connected as the login goes along
+    pub(crate) fn update(&self, connection: &Connection) {
+        let mut connections = self.connections.write().unwrap();
+        if let Some(entry) = connections.get_mut(&connection.id) {
+            entry.connection.customer_id = connection.customer_id;
+            entry.connection.persona_id = connection.persona_id;
+        }
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.connections.write().unwrap().remove(&id);
+    }
+
+    // Oldest first
+    pub(crate) fn list(&self) -> Vec<LiveConnection> {
+        let connections = self.connections.read().unwrap();
+        let mut list: Vec<LiveConnection> = connections
+            .values()
+            .map(|entry| entry.connection.clone())
+            .collect();
+        list.sort_by_key(|connection| connection.id);
+        list
+    }
+
+    // Returns false if there is no such connection
+    pub(crate) fn kick(&self, id: u64) -> bool {
+        let connections = self.connections.read().unwrap();
+        match connections.get(&id) {
+            Some(entry) => entry.control.send(Control::Kick).is_ok(),
+            None => false,
+        }
+    }
+
+    // Kick every connection a customer has, returns how many there were
+    pub(crate) fn kick_customer(&self, customer_id: u32) -> usize {
+        let connections = self.connections.read().unwrap();
+        connections
+            .values()
+            .filter(|entry| entry.connection.customer_id == Some(customer_id))
+            .filter(|entry| entry.control.send(Control::Kick).is_ok())
+            .count()
+    }
+
+    // Queue a frame on every connection to `server`, returns how many took it
+    pub(crate) fn broadcast(&self, server: Server, frame: &[u8]) -> usize {
+        let connections = self.connections.read().unwrap();
+        connections
+            .values()
+            .filter(|entry| entry.connection.server == server)
+            .filter(|entry| entry.control.send(Control::Send(frame.to_vec())).is_ok())
+            .count()
+    }
+
+    pub(crate) fn set_listening(&self, server: Server, listening: bool) {
+        let mut listeners = self.listeners.write().unwrap();
+        listeners.entry(server).or_default().listening = listening;
+    }
+
+    pub(crate) fn accepted(&self, server: Server, ok: bool) {
+        let mut listeners = self.listeners.write().unwrap();
+        let health = listeners.entry(server).or_default();
+        if ok {
+            health.accepted += 1;
+        } else {
+            health.failed += 1;
+        }
+    }
+
+    // A listener that never started reads as not listening
+    pub(crate) fn listener_health(&self, server: Server) -> ListenerHealth {
+        let listeners = self.listeners.read().unwrap();
+        listeners.get(&server).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcasts_reach_one_listener() {
+        let store = ConnectionStore::new();
+        let state = crate::test_client::test_state(None);
+        let lobby = Connection::new(1, Server::Lobby, "a".to_string(), state.clone());
+        let login = Connection::new(2, Server::Login, "b".to_string(), state);
+        let mut lobby_control = store.register(&lobby);
+        let mut login_control = store.register(&login);
+
+        assert_eq!(store.broadcast(Server::Lobby, b"hello"), 1);
+        assert_eq!(
+            lobby_control.try_recv(),
+            Ok(Control::Send(b"hello".to_vec()))
+        );
+        assert!(login_control.try_recv().is_err());
+
+        assert!(store.kick(2));
+        assert_eq!(login_control.try_recv(), Ok(Control::Kick));
+        store.remove(2);
+        assert!(!store.kick(2));
+        assert_eq!(store.list().len(), 1);
+    }
+}
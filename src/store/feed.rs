@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::capture::Direction;
+use crate::dispatch::{Connection, Server};
+
+// How many packets the dashboard can scroll back through
+pub(crate) const FEED_LENGTH: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FeedEntry {
+    // Counts up from 1, so an entry can be found again after the feed moves on
+    pub(crate) sequence: u64,
+    pub(crate) time: SystemTime,
+    pub(crate) connection_id: u64,
+    pub(crate) server: Server,
+    pub(crate) direction: Direction,
+    // Plaintext, like a capture
+    pub(crate) frame: Vec<u8>,
+}
+
+// The most recent packets on every listener, kept only while someone watches
+#[derive(Default)]
+pub(crate) struct PacketFeed {
+    enabled: AtomicBool,
+    next_sequence: AtomicU64,
+    entries: Mutex<VecDeque<FeedEntry>>,
+}
+
+impl PacketFeed {
+    pub(crate) fn new() -> PacketFeed {
+        PacketFeed::default()
+    }
+
+    // Forgets everything when switched off
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.entries.lock().unwrap().clear();
+        }
+    }
+
+    pub(crate) fn record(&self, connection: &Connection, direction: Direction, frame: &[u8]) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == FEED_LENGTH {
+            entries.pop_front();
+        }
+        entries.push_back(FeedEntry {
+            sequence: self.next_sequence.fetch_add(1, Ordering::Relaxed) + 1,
+            time: SystemTime::now(),
+            connection_id: connection.id,
+            server: connection.server,
+            direction,
+            frame: frame.to_vec(),
+        });
+    }
+
+    // Oldest first
+    pub(crate) fn entries(&self) -> Vec<FeedEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,6 @@
+pub(crate) mod account;
+pub(crate) mod connection;
+pub(crate) mod feed;
+pub(crate) mod persona;
+pub(crate) mod session;
+pub(crate) mod vehicle;
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// How many personas a single customer may own
+pub(crate) const MAX_PERSONAS: usize = 4;
+
+// Names are stored in fixed-width fields on the wire
+pub(crate) const MIN_NAME_LENGTH: usize = 3;
+pub(crate) const MAX_NAME_LENGTH: usize = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Persona {
+    pub(crate) id: u32,
+    pub(crate) customer_id: u32,
+    pub(crate) name: String,
+    pub(crate) shard_id: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PersonaError {
+    InvalidName,
+    NameTaken,
+    TooManyPersonas,
+    NotFound,
+}
+
+#[derive(Debug, Default)]
+struct Personas {
+    next_id: u32,
+    by_id: HashMap<u32, Persona>,
+}
+
+// In-memory store of every customer's personas
+#[derive(Debug, Default)]
+pub(crate) struct PersonaStore {
+    personas: Mutex<Personas>,
+}
+
+impl PersonaStore {
+    pub(crate) fn new() -> PersonaStore {
+        PersonaStore::default()
+    }
+
+    pub(crate) fn for_customer(&self, customer_id: u32) -> Vec<Persona> {
+        let personas = self.personas.lock().unwrap();
+        let mut found: Vec<Persona> = personas
+            .by_id
+            .values()
+            .filter(|persona| persona.customer_id == customer_id)
+            .cloned()
+            .collect();
+        found.sort_by_key(|persona| persona.id);
+        found
+    }
+
+    // Every customer's personas, by id
+    pub(crate) fn list(&self) -> Vec<Persona> {
+        let personas = self.personas.lock().unwrap();
+        let mut list: Vec<Persona> = personas.by_id.values().cloned().collect();
+        list.sort_by_key(|persona| persona.id);
+        list
+    }
+
+    pub(crate) fn get(&self, customer_id: u32, persona_id: u32) -> Option<Persona> {
+        let personas = self.personas.lock().unwrap();
+        personas
+            .by_id
+            .get(&persona_id)
+            .filter(|persona| persona.customer_id == customer_id)
+            .cloned()
+    }
+
+    // Check a name is well formed and nobody else has it
+    pub(crate) fn check_name(&self, name: &str) -> Result<(), PersonaError> {
+        if !is_valid_name(name) {
+            return Err(PersonaError::InvalidName);
+        }
+        let personas = self.personas.lock().unwrap();
+        if name_in_use(&personas, name) {
+            return Err(PersonaError::NameTaken);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn create(
+        &self,
+        customer_id: u32,
+        name: &str,
+        shard_id: u32,
+    ) -> Result<Persona, PersonaError> {
+        if !is_valid_name(name) {
+            return Err(PersonaError::InvalidName);
+        }
+        let mut personas = self.personas.lock().unwrap();
+        if name_in_use(&personas, name) {
+            return Err(PersonaError::NameTaken);
+        }
+        let owned = personas
+            .by_id
+            .values()
+            .filter(|persona| persona.customer_id == customer_id)
+            .count();
+        if owned >= MAX_PERSONAS {
+            return Err(PersonaError::TooManyPersonas);
+        }
+
+        personas.next_id += 1;
+        let persona = Persona {
+            id: personas.next_id,
+            customer_id,
+            name: name.to_string(),
+            shard_id,
+        };
+        personas.by_id.insert(persona.id, persona.clone());
+        Ok(persona)
+    }
+
+    pub(crate) fn delete(&self, customer_id: u32, persona_id: u32) -> Result<(), PersonaError> {
+        let mut personas = self.personas.lock().unwrap();
+        match personas.by_id.get(&persona_id) {
+            Some(persona) if persona.customer_id == customer_id => {
+                personas.by_id.remove(&persona_id);
+                Ok(())
+            }
+            _ => Err(PersonaError::NotFound),
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Names are unique regardless of case
+fn name_in_use(personas: &Personas, name: &str) -> bool {
+    personas
+        .by_id
+        .values()
+        .any(|persona| persona.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn personas_belong_to_their_customer() {
+        let store = PersonaStore::new();
+        let persona = store.create(1, "Racer", 1).unwrap();
+
+        assert_eq!(store.get(1, persona.id), Some(persona.clone()));
+        assert_eq!(store.get(2, persona.id), None);
+        assert_eq!(store.delete(2, persona.id), Err(PersonaError::NotFound));
+        assert_eq!(store.for_customer(1), vec![persona.clone()]);
+        assert!(store.for_customer(2).is_empty());
+
+        assert_eq!(store.delete(1, persona.id), Ok(()));
+        assert!(store.for_customer(1).is_empty());
+    }
+
+    #[test]
+    fn names_are_checked() {
+        let store = PersonaStore::new();
+        store.create(1, "Racer", 1).unwrap();
+
+        assert_eq!(store.check_name("RACER"), Err(PersonaError::NameTaken));
+        assert_eq!(store.create(2, "racer", 1), Err(PersonaError::NameTaken));
+        assert_eq!(store.check_name("ab"), Err(PersonaError::InvalidName));
+        assert_eq!(store.check_name("bad name"), Err(PersonaError::InvalidName));
+        assert_eq!(store.check_name("Fast_Car-2"), Ok(()));
+
+        for name in ["Two", "Three", "Four"] {
+            store.create(1, name, 1).unwrap();
+        }
+        assert_eq!(
+            store.create(1, "Five", 1),
+            Err(PersonaError::TooManyPersonas)
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// How long a session survives without being used
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub(crate) customer_id: u32,
+    // The login connection that created the session
+    pub(crate) connection_id: u64,
+    // Where the login came from, later connections must come from the same host
+    pub(crate) peer: String,
+    pub(crate) session_key: Vec<u8>,
+    pub(crate) persona_id: Option<u32>,
+    pub(crate) expires: Instant,
+}
+
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires <= now
+    }
+}
+
+// Session keys negotiated on the login server, shared with the other listeners
+#[derive(Debug)]
+pub(crate) struct SessionStore {
+    sessions: RwLock<HashMap<u32, Session>>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub(crate) fn new(ttl: Duration) -> SessionStore {
+        SessionStore {
+            sessions: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    // Start a session, replacing any earlier one for the same customer
+    pub(crate) fn insert(
+        &self,
+        customer_id: u32,
+        connection_id: u64,
+        peer: &str,
+        session_key: Vec<u8>,
+    ) {
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.insert(
+            customer_id,
+            Session {
+                customer_id,
+                connection_id,
+                peer: peer.to_string(),
+                session_key,
+                persona_id: None,
+                expires: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    pub(crate) fn get(&self, customer_id: u32) -> Option<Session> {
+        let sessions = self.sessions.read().unwrap();
+        sessions
+            .get(&customer_id)
+            .filter(|session| !session.is_expired(Instant::now()))
+            .cloned()
+    }
+
+    // Push the expiry back, returns false if there is no live session
+    pub(crate) fn touch(&self, customer_id: u32) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let now = Instant::now();
+        match sessions.get_mut(&customer_id) {
+            Some(session) if !session.is_expired(now) => {
+                session.expires = now + self.ttl;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn set_persona(&self, customer_id: u32, persona_id: Option<u32>) {
+        let mut sessions = self.sessions.write().unwrap();
+        if let Some(session) = sessions.get_mut(&customer_id) {
+            session.persona_id = persona_id;
+        }
+    }
+
+    // Unselect a persona that has gone, whichever connection selected it
+    pub(crate) fn clear_persona(&self, customer_id: u32, persona_id: u32) {
+        let mut sessions = self.sessions.write().unwrap();
+        if let Some(session) = sessions.get_mut(&customer_id) {
+            if session.persona_id == Some(persona_id) {
+                session.persona_id = None;
+            }
+        }
+    }
+
+    // Forget a customer's login, returns whether there was one
+    pub(crate) fn remove(&self, customer_id: u32) -> bool {
+        self.sessions
+            .write()
+            .unwrap()
+            .remove(&customer_id)
+            .is_some()
+    }
+
+    // Live sessions, by customer id
+    pub(crate) fn list(&self) -> Vec<Session> {
+        let sessions = self.sessions.read().unwrap();
+        let now = Instant::now();
+        let mut list: Vec<Session> = sessions
+            .values()
+            .filter(|session| !session.is_expired(now))
+            .cloned()
+            .collect();
+        list.sort_by_key(|session| session.customer_id);
+        list
+    }
+
+    // Drop expired sessions, returns how many were removed
+    pub(crate) fn purge_expired(&self) -> usize {
+        let mut sessions = self.sessions.write().unwrap();
+        let now = Instant::now();
+        let before = sessions.len();
+        sessions.retain(|_, session| !session.is_expired(now));
+        before - sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_remember_the_login() {
+        let store = SessionStore::new(SESSION_TTL);
+        store.insert(1, 5, "10.0.0.1:4000", vec![1; 32]);
+
+        let session = store.get(1).unwrap();
+        assert_eq!(session.connection_id, 5);
+        assert_eq!(session.peer, "10.0.0.1:4000");
+        assert_eq!(session.persona_id, None);
+        assert!(store.get(2).is_none());
+
+        store.set_persona(1, Some(3));
+        assert_eq!(store.get(1).unwrap().persona_id, Some(3));
+
+        assert!(store.remove(1));
+        assert!(store.get(1).is_none());
+        assert!(!store.remove(1));
+    }
+
+    #[test]
+    fn expired_sessions_are_gone() {
+        let store = SessionStore::new(Duration::ZERO);
+        store.insert(1, 5, "10.0.0.1:4000", vec![1; 32]);
+
+        assert!(store.get(1).is_none());
+        assert!(!store.touch(1));
+        assert_eq!(store.purge_expired(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::packet::mcots::{OwnedVehicleEntry, StockCarEntry};
+
+// What the dealers sell until there is a real catalogue
+const STOCK_CARS: &[StockCarEntry] = &[
+    StockCarEntry {
+        car_id: 101,
+        brand_id: 1,
+        retail_price: 12_000,
+    },
+    StockCarEntry {
+        car_id: 102,
+        brand_id: 1,
+        retail_price: 18_500,
+    },
+    StockCarEntry {
+        car_id: 201,
+        brand_id: 2,
+        retail_price: 24_000,
+    },
+];
+
+// Every new persona gets the cheapest car so it has something to drive
+pub(crate) const STARTER_CAR_ID: u32 = 101;
+
+// Cars owned by each persona
+#[derive(Debug, Default)]
+pub(crate) struct VehicleStore {
+    owned: Mutex<HashMap<u32, Vec<OwnedVehicleEntry>>>,
+    last_vehicle_id: AtomicU32,
+}
+
+impl VehicleStore {
+    pub(crate) fn new() -> VehicleStore {
+        VehicleStore::default()
+    }
+
+    pub(crate) fn stock_cars(&self, brand_id: u32) -> Vec<StockCarEntry> {
+        STOCK_CARS
+            .iter()
+            .filter(|car| car.brand_id == brand_id)
+            .copied()
+            .collect()
+    }
+
+    pub(crate) fn owned_by(&self, persona_id: u32) -> Vec<OwnedVehicleEntry> {
+        self.owned
+            .lock()
+            .unwrap()
+            .get(&persona_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Hand a persona a new car of the given model
+    pub(crate) fn grant(&self, persona_id: u32, car_id: u32) -> OwnedVehicleEntry {
+        let vehicle = OwnedVehicleEntry {
+            vehicle_id: self.last_vehicle_id.fetch_add(1, Ordering::Relaxed) + 1,
+            car_id,
+        };
+        self.owned
+            .lock()
+            .unwrap()
+            .entry(persona_id)
+            .or_default()
+            .push(vehicle);
+        vehicle
+    }
+
+    // A deleted persona's cars go with it
+    pub(crate) fn remove_persona(&self, persona_id: u32) {
+        self.owned.lock().unwrap().remove(&persona_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn granted_cars_are_owned() {
+        let store = VehicleStore::new();
+        assert!(store.owned_by(1).is_empty());
+
+        let first = store.grant(1, STARTER_CAR_ID);
+        let second = store.grant(1, 201);
+        store.grant(2, STARTER_CAR_ID);
+        assert_ne!(first.vehicle_id, second.vehicle_id);
+
+        let owned: Vec<(u32, u32)> = store
+            .owned_by(1)
+            .iter()
+            .map(|vehicle| (vehicle.vehicle_id, vehicle.car_id))
+            .collect();
+        assert_eq!(
+            owned,
+            [(first.vehicle_id, STARTER_CAR_ID), (second.vehicle_id, 201)]
+        );
+
+        store.remove_persona(1);
+        assert!(store.owned_by(1).is_empty());
+        assert_eq!(store.owned_by(2).len(), 1);
+    }
+}
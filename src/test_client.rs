@@ -0,0 +1,518 @@
+// Desc: A scripted stand-in for the game client, for tests
+//
+// `TestServer` runs the login, persona and lobby listeners on loopback ports
+// with an in-memory account database, and `FakeClient` talks to them the way
+// the game does: a LoginRequest carrying an RSA-OAEP encrypted session key,
+// then plain NPS messages, encrypted once the lobby says so.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use openssl::rsa::{Padding, Rsa};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_util::codec::Framed;
+
+use crate::capture::Capture;
+use crate::codec::{HeaderKind, NpsCodec};
+use crate::config::{Config, Keys};
+use crate::dispatch::Server;
+use crate::keys::KeyManager;
+use crate::net::serve;
+use crate::packet::header::{Header, MessageHeader, VersionedHeader};
+use crate::packet::lobby::{LobbyLogin, NPS_LOBBY_LOGIN};
+use crate::packet::login_request::{LoginRequest, LoginSession, NPS_USER_LOGIN};
+use crate::packet::persona::{
+    DeletePersona, GetPersonaMaps, PersonaNameRequest, SelectGamePersona, NPS_CREATE_PERSONA,
+    NPS_DELETE_PERSONA, NPS_GET_PERSONA_MAPS, NPS_SELECT_GAME_PERSONA, NPS_VALIDATE_PERSONA_NAME,
+};
+use crate::packet::{Encode, PrefixedField, PrefixedString};
+use crate::parser::handlers;
+use crate::state::State;
+use crate::store::account::AccountStore;
+
+// How long a test waits for the server before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The game ships with the public half of the server's login key
+const PUBLIC_KEY_PATH: &str = "data/pub.key";
+
+// Default settings, an empty in-memory database and a capture file at
+// `capture`, which stays off until the test enables it
+pub(crate) fn test_state(capture: Option<&str>) -> Arc<State> {
+    Arc::new(State::new(
+        handlers(),
+        AccountStore::open_in_memory().unwrap(),
+        Capture::new(capture.unwrap_or("")),
+        Config::default(),
+        KeyManager::load(&Keys::default()).unwrap(),
+    ))
+}
+
+pub(crate) struct TestServer {
+    pub(crate) state: Arc<State>,
+    pub(crate) login: SocketAddr,
+    pub(crate) persona: SocketAddr,
+    pub(crate) lobby: SocketAddr,
+    // Dropping the server stops the listeners
+    running: watch::Sender<bool>,
+}
+
+impl TestServer {
+    pub(crate) async fn start() -> TestServer {
+        let state = test_state(None);
+        let (running, rx) = watch::channel(true);
+
+        let mut addresses = Vec::new();
+        for server in [Server::Login, Server::Persona, Server::Lobby] {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            addresses.push(listener.local_addr().unwrap());
+            tokio::spawn(serve(listener, server, state.clone(), rx.clone()));
+        }
+
+        TestServer {
+            state,
+            login: addresses[0],
+            persona: addresses[1],
+            lobby: addresses[2],
+            running,
+        }
+    }
+
+    // What the console does on quit
+    pub(crate) fn shutdown(&self) {
+        self.running.send_replace(false);
+    }
+
+    // What the web AuthLogin endpoint would do: make sure the account exists and hand out a ticket
+    pub(crate) fn ticket_for(&self, username: &str) -> (u32, String) {
+        let account = match self.state.accounts.authenticate(username, "password") {
+            Some(account) => account,
+            None => self.state.accounts.create(username, "password").unwrap(),
+        };
+        let ticket = self
+            .state
+            .accounts
+            .issue_ticket(account.customer_id)
+            .unwrap();
+        (account.customer_id, ticket)
+    }
+}
+
+// Encrypt a session key the way the client does before sending it to the login server
+pub(crate) fn encrypt_session_key(session_key: &[u8]) -> String {
+    let der = hex::decode(std::fs::read_to_string(PUBLIC_KEY_PATH).unwrap().trim()).unwrap();
+    let public_key = Rsa::public_key_from_der(&der).unwrap();
+    let plaintext = PrefixedField {
+        length: session_key.len() as u16,
+        data: session_key.to_vec(),
+    }
+    .to_bytes()
+    .unwrap();
+    let mut encrypted = vec![0; public_key.size() as usize];
+    let length = public_key
+        .public_encrypt(&plaintext, &mut encrypted, Padding::PKCS1_OAEP)
+        .unwrap();
+    encrypted.truncate(length);
+    hex::encode_upper(encrypted)
+}
+
+// Requests carry a plain header, encoding fills in the length
+fn with_header<T: Encode>(id: u16, build: impl FnOnce(Header) -> T) -> Vec<u8> {
+    build(Header { id, length: 0 }).to_bytes().unwrap()
+}
+
+// Build a login request the way the client would, with the header length filled in
+pub(crate) fn build_login_request(
+    context_id: &str,
+    encrypted_session_key: &str,
+    game_id: &str,
+) -> LoginRequest {
+    let mut request = LoginRequest {
+        header: VersionedHeader::new(NPS_USER_LOGIN, 0),
+        context_id: PrefixedString {
+            string: context_id.to_string(),
+        },
+        session: LoginSession {
+            encrypted_session_key: PrefixedString {
+                string: encrypted_session_key.to_string(),
+            },
+            game_id: PrefixedString {
+                string: game_id.to_string(),
+            },
+        },
+    };
+    request.header = request.header.with_length(request.size()).unwrap();
+    request
+}
+
+pub(crate) fn login_request(ticket: &str, session_key: &[u8]) -> Vec<u8> {
+    build_login_request(ticket, &encrypt_session_key(session_key), "")
+        .to_bytes()
+        .unwrap()
+}
+
+pub(crate) fn get_persona_maps(customer_id: u32) -> Vec<u8> {
+    with_header(NPS_GET_PERSONA_MAPS, |header| GetPersonaMaps {
+        header,
+        customer_id,
+    })
+}
+
+pub(crate) fn persona_name_request(id: u16, customer_id: u32, name: &str) -> Vec<u8> {
+    with_header(id, |header| PersonaNameRequest {
+        header,
+        customer_id,
+        name: PrefixedString {
+            string: name.to_string(),
+        },
+    })
+}
+
+pub(crate) fn select_game_persona(customer_id: u32, persona_id: u32) -> Vec<u8> {
+    with_header(NPS_SELECT_GAME_PERSONA, |header| SelectGamePersona {
+        header,
+        customer_id,
+        persona_id,
+    })
+}
+
+pub(crate) fn delete_persona(customer_id: u32, persona_id: u32) -> Vec<u8> {
+    with_header(NPS_DELETE_PERSONA, |header| DeletePersona {
+        header,
+        customer_id,
+        persona_id,
+    })
+}
+
+pub(crate) fn lobby_login(customer_id: u32, persona_id: u32) -> Vec<u8> {
+    with_header(NPS_LOBBY_LOGIN, |header| LobbyLogin {
+        header,
+        customer_id,
+        persona_id,
+    })
+}
+
+pub(crate) struct FakeClient {
+    framed: Framed<TcpStream, NpsCodec>,
+}
+
+impl FakeClient {
+    pub(crate) async fn connect(address: SocketAddr) -> FakeClient {
+        let stream = TcpStream::connect(address).await.unwrap();
+        FakeClient {
+            framed: Framed::new(stream, NpsCodec::new(HeaderKind::Plain)),
+        }
+    }
+
+    pub(crate) async fn send(&mut self, frame: Vec<u8>) {
+        self.framed.send(frame).await.unwrap();
+    }
+
+    // `None` once the server has hung up
+    pub(crate) async fn receive(&mut self) -> Option<Vec<u8>> {
+        match tokio::time::timeout(RESPONSE_TIMEOUT, self.framed.next()).await {
+            Ok(Some(Ok(frame))) => Some(frame),
+            Ok(Some(Err(_))) | Ok(None) => None,
+            Err(_) => panic!("No response from the server within {:?}", RESPONSE_TIMEOUT),
+        }
+    }
+
+    pub(crate) async fn request(&mut self, frame: Vec<u8>) -> Vec<u8> {
+        self.send(frame).await;
+        self.receive().await.expect("server hung up")
+    }
+
+    // Bytes straight onto the socket, whether or not they make a frame
+    pub(crate) async fn send_raw(&mut self, bytes: &[u8]) {
+        self.framed.get_mut().write_all(bytes).await.unwrap();
+    }
+
+    pub(crate) fn encrypt(&mut self, session_key: &[u8]) {
+        self.framed.codec_mut().enable(session_key).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::error::{
+        LOGOFF_KICKED, LOGOFF_SERVER_SHUTDOWN, NPS_FORCE_LOGOFF, NPS_GENERIC_FAILURE,
+        NPS_LOGIN_FAILED,
+    };
+    use crate::packet::lobby::{SystemMessage, NPS_LOBBY_WELCOME, NPS_SYSTEM_MESSAGE};
+    use crate::packet::persona::{NPS_ACK, NPS_PERSONA_CREATED, NPS_PERSONA_MAPS};
+    use crate::packet::user_status::{NPS_USER_STATUS, USER_STATUS_LENGTH};
+    use crate::packet::Encode;
+
+    const SESSION_KEY: [u8; 32] = [0x42; 32];
+
+    fn id(frame: &[u8]) -> u16 {
+        u16::from_be_bytes([frame[0], frame[1]])
+    }
+
+    fn u32_at(frame: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(frame[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Who the live lobby connections speak for
+    fn lobby_customers(server: &TestServer) -> Vec<Option<u32>> {
+        server
+            .state
+            .connections
+            .list()
+            .iter()
+            .filter(|connection| connection.server == Server::Lobby)
+            .map(|connection| connection.customer_id)
+            .collect()
+    }
+
+    // Log in and return the customer id
+    async fn log_in(server: &TestServer, username: &str) -> u32 {
+        let (customer_id, ticket) = server.ticket_for(username);
+        let mut client = FakeClient::connect(server.login).await;
+        let status = client.request(login_request(&ticket, &SESSION_KEY)).await;
+        assert_eq!(id(&status), NPS_USER_STATUS);
+        customer_id
+    }
+
+    // Create and select a persona, returning its id
+    async fn pick_persona(server: &TestServer, customer_id: u32) -> u32 {
+        let mut client = FakeClient::connect(server.persona).await;
+        let created = client
+            .request(persona_name_request(
+                NPS_CREATE_PERSONA,
+                customer_id,
+                "Racer",
+            ))
+            .await;
+        assert_eq!(id(&created), NPS_PERSONA_CREATED);
+        let persona_id = u32_at(&created, 4);
+        let selected = client
+            .request(select_game_persona(customer_id, persona_id))
+            .await;
+        assert_eq!(id(&selected), NPS_ACK);
+        persona_id
+    }
+
+    #[tokio::test]
+    async fn login_returns_user_status() {
+        let server = TestServer::start().await;
+        let (customer_id, ticket) = server.ticket_for("racer");
+
+        let mut client = FakeClient::connect(server.login).await;
+        let status = client.request(login_request(&ticket, &SESSION_KEY)).await;
+        assert_eq!(id(&status), NPS_USER_STATUS);
+        assert_eq!(status.len(), USER_STATUS_LENGTH);
+
+        // The context id comes first, then the customer
+        let offset = 4 + 2 + ticket.len();
+        assert_eq!(u32_at(&status, offset), customer_id);
+
+        // Then the flags and a hash of the session key, never the key itself
+        let hash = hex::encode(openssl::sha::sha256(&SESSION_KEY));
+        assert_eq!(
+            &status[offset + 10..offset + 12],
+            (hash.len() as u16).to_be_bytes()
+        );
+        assert_eq!(
+            &status[offset + 12..offset + 12 + hash.len()],
+            hash.as_bytes()
+        );
+        for key in [SESSION_KEY.to_vec(), hex::encode(SESSION_KEY).into_bytes()] {
+            assert!(!status.windows(key.len()).any(|window| window == key));
+        }
+        assert_eq!(
+            server.state.sessions.get(customer_id).unwrap().session_key,
+            SESSION_KEY
+        );
+
+        // Tickets only work once
+        let mut client = FakeClient::connect(server.login).await;
+        let refused = client.request(login_request(&ticket, &SESSION_KEY)).await;
+        assert_eq!(id(&refused), NPS_LOGIN_FAILED);
+    }
+
+    #[tokio::test]
+    async fn personas_can_be_created_and_selected() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+
+        let mut client = FakeClient::connect(server.persona).await;
+        let maps = client.request(get_persona_maps(customer_id)).await;
+        assert_eq!(id(&maps), NPS_PERSONA_MAPS);
+        assert_eq!(&maps[4..6], [0, 0]);
+
+        let valid = client
+            .request(persona_name_request(
+                NPS_VALIDATE_PERSONA_NAME,
+                customer_id,
+                "Rusty",
+            ))
+            .await;
+        assert_eq!(id(&valid), NPS_ACK);
+
+        let created = client
+            .request(persona_name_request(
+                NPS_CREATE_PERSONA,
+                customer_id,
+                "Rusty",
+            ))
+            .await;
+        assert_eq!(id(&created), NPS_PERSONA_CREATED);
+        let persona_id = u32_at(&created, 4);
+
+        let maps = client.request(get_persona_maps(customer_id)).await;
+        assert_eq!(&maps[4..6], [0, 1]);
+        assert_eq!(u32_at(&maps, 6), persona_id);
+
+        let selected = client
+            .request(select_game_persona(customer_id, persona_id))
+            .await;
+        assert_eq!(id(&selected), NPS_ACK);
+    }
+
+    #[tokio::test]
+    async fn lobby_switches_to_encryption() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+        let persona_id = pick_persona(&server, customer_id).await;
+
+        let mut client = FakeClient::connect(server.lobby).await;
+        let welcome = client.request(lobby_login(customer_id, persona_id)).await;
+        assert_eq!(id(&welcome), NPS_LOBBY_WELCOME);
+        assert_eq!(u32_at(&welcome, 4), customer_id);
+        assert_eq!(u32_at(&welcome, 8), persona_id);
+
+        // Everything after the welcome is encrypted, in both directions
+        client.encrypt(&SESSION_KEY);
+        let reply = client.request(vec![0x0f, 0x0f, 0x00, 0x06, 1, 2]).await;
+        assert_eq!(id(&reply), NPS_GENERIC_FAILURE);
+        assert_eq!(u32_at(&reply, 4), 0x0f0f);
+    }
+
+    #[tokio::test]
+    async fn lobby_requires_a_login() {
+        let server = TestServer::start().await;
+        let mut client = FakeClient::connect(server.lobby).await;
+        let reply = client.request(lobby_login(0x0001_0001, 1)).await;
+        assert_eq!(id(&reply), NPS_GENERIC_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn lobby_requires_the_selected_persona() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+        let victim = log_in(&server, "victim").await;
+        let victim_persona = pick_persona(&server, victim).await;
+
+        // Nothing selected yet, and someone else's persona is never ours
+        let mut client = FakeClient::connect(server.lobby).await;
+        let reply = client.request(lobby_login(customer_id, 7)).await;
+        assert_eq!(id(&reply), NPS_GENERIC_FAILURE);
+        let reply = client
+            .request(lobby_login(customer_id, victim_persona))
+            .await;
+        assert_eq!(id(&reply), NPS_GENERIC_FAILURE);
+        // Refused, so the connection isn't theirs either
+        assert_eq!(lobby_customers(&server), [None]);
+    }
+
+    #[tokio::test]
+    async fn lobby_refuses_banned_customers() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+        let persona_id = pick_persona(&server, customer_id).await;
+        server.state.accounts.set_banned(customer_id, true).unwrap();
+
+        let mut client = FakeClient::connect(server.lobby).await;
+        let reply = client.request(lobby_login(customer_id, persona_id)).await;
+        assert_eq!(id(&reply), NPS_GENERIC_FAILURE);
+        assert_eq!(lobby_customers(&server), [None]);
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_disconnect() {
+        let server = TestServer::start().await;
+        let mut client = FakeClient::connect(server.login).await;
+        client.send(vec![0x05, 0x01, 0x00, 0x02]).await;
+        assert_eq!(client.receive().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_frames_disconnect() {
+        let server = TestServer::start().await;
+        let mut quiet = FakeClient::connect(server.login).await;
+        let mut stalled = FakeClient::connect(server.login).await;
+        // A header promising more than ever arrives
+        stalled.send_raw(&[0x05, 0x01, 0x00, 0x40]).await;
+
+        // Well past the body deadline but well short of the idle one
+        let wait = Duration::from_secs(90);
+        let closed = tokio::time::timeout(wait, stalled.framed.next()).await;
+        assert!(matches!(closed, Ok(None) | Ok(Some(Err(_)))));
+        assert!(tokio::time::timeout(Duration::ZERO, quiet.framed.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn operators_reach_lobby_clients() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+        let persona_id = pick_persona(&server, customer_id).await;
+        let mut client = FakeClient::connect(server.lobby).await;
+        client.request(lobby_login(customer_id, persona_id)).await;
+        client.encrypt(&SESSION_KEY);
+
+        let lobby: Vec<_> = server
+            .state
+            .connections
+            .list()
+            .into_iter()
+            .filter(|connection| connection.server == Server::Lobby)
+            .collect();
+        assert_eq!(lobby.len(), 1);
+        assert_eq!(lobby[0].customer_id, Some(customer_id));
+        assert_eq!(lobby[0].persona_id, Some(persona_id));
+
+        let frame = SystemMessage::new("Welcome to the track")
+            .to_bytes()
+            .unwrap();
+        assert_eq!(server.state.connections.broadcast(Server::Lobby, &frame), 1);
+        let message = client.receive().await.unwrap();
+        assert_eq!(id(&message), NPS_SYSTEM_MESSAGE);
+        assert_eq!(message, frame);
+
+        assert!(server.state.connections.kick(lobby[0].id));
+        let logoff = client.receive().await.unwrap();
+        assert_eq!(id(&logoff), NPS_FORCE_LOGOFF);
+        assert_eq!(u32_at(&logoff, 4), LOGOFF_KICKED);
+        assert_eq!(client.receive().await, None);
+    }
+
+    #[tokio::test]
+    async fn shutdown_logs_clients_off() {
+        let server = TestServer::start().await;
+        let customer_id = log_in(&server, "racer").await;
+        let persona_id = pick_persona(&server, customer_id).await;
+        let mut client = FakeClient::connect(server.lobby).await;
+        client.request(lobby_login(customer_id, persona_id)).await;
+        client.encrypt(&SESSION_KEY);
+
+        server.shutdown();
+        let logoff = client.receive().await.unwrap();
+        assert_eq!(id(&logoff), NPS_FORCE_LOGOFF);
+        assert_eq!(u32_at(&logoff, 4), LOGOFF_SERVER_SHUTDOWN);
+        assert_eq!(client.receive().await, None);
+
+        server.state.tasks.close();
+        tokio::time::timeout(RESPONSE_TIMEOUT, server.state.tasks.wait())
+            .await
+            .unwrap();
+        assert!(TcpStream::connect(server.lobby).await.is_err());
+    }
+}
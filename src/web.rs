@@ -0,0 +1,197 @@
+// Desc: HTTP auth and shard service the client talks to before connecting
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::extract::{Query, State as AxumState};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::config::{Listeners, ShardConfig};
+use crate::net::stopped;
+use crate::state::State;
+
+// A game world the client can pick from the shard list
+#[derive(Debug, Clone)]
+pub(crate) struct Shard {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) description: String,
+    pub(crate) host: String,
+    pub(crate) login_port: u16,
+    pub(crate) lobby_port: u16,
+    pub(crate) population: u32,
+}
+
+impl Shard {
+    // Clients reach every shard through our own login and lobby listeners
+    pub(crate) fn new(config: &ShardConfig, listeners: &Listeners) -> Shard {
+        Shard {
+            id: config.id,
+            name: config.name.clone(),
+            description: config.description.clone(),
+            host: config.host.clone(),
+            login_port: listeners.login.port(),
+            lobby_port: listeners.lobby.port(),
+            population: 0,
+        }
+    }
+
+    // The client parses the shard list as an ini file
+    fn render(&self) -> String {
+        format!(
+            "[{name}]\n\
+             Description={description}\n\
+             ShardId={id}\n\
+             LoginServerIP={host}\n\
+             LoginServerPort={login_port}\n\
+             LobbyServerIP={host}\n\
+             LobbyServerPort={lobby_port}\n\
+             MCOTSServerIP={host}\n\
+             StatusId=0\n\
+             Status_Reason=\n\
+             ServerGroupName=Group - 1\n\
+             Population={population}\n\
+             MaxPersonasPerUser={max_personas}\n\
+             DiagnosticServerHost={host}\n\
+             DiagnosticServerPort=80\n",
+            name = self.name,
+            description = self.description,
+            id = self.id,
+            host = self.host,
+            login_port = self.login_port,
+            lobby_port = self.lobby_port,
+            population = self.population,
+            max_personas = crate::store::persona::MAX_PERSONAS,
+        )
+    }
+}
+
+// Serve the web endpoints until the server shuts down. Settings are read
+// per request, so a reload takes effect straight away.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    state: Arc<State>,
+    mut running: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/AuthLogin", get(auth_login))
+        .route("/ShardList/", get(shard_list))
+        .route("/cert", get(certificate))
+        .route("/key", get(public_key))
+        .with_state(WebState { state });
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { stopped(&mut running).await })
+        .await
+}
+
+#[derive(Clone)]
+struct WebState {
+    state: Arc<State>,
+}
+
+async fn auth_login(
+    AxumState(web): AxumState<WebState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let username = params.get("username").map(String::as_str).unwrap_or("");
+    let password = params.get("password").map(String::as_str).unwrap_or("");
+    if username.is_empty() {
+        info!("AuthLogin without a username");
+        return auth_failure("INV-200", "Unable to login. Please check your username");
+    }
+
+    // Password hashing and the database both block, keep them off the runtime
+    let state = web.state.clone();
+    let (username, password) = (username.to_string(), password.to_string());
+    match tokio::task::spawn_blocking(move || log_in(&state, &username, &password)).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("AuthLogin task failed: {}", e);
+            auth_failure("INV-100", "Unable to login. Please try again later")
+        }
+    }
+}
+
+// Check the credentials and hand out a login ticket
+fn log_in(state: &State, username: &str, password: &str) -> Response {
+    let accounts = &state.accounts;
+    if state.config().auto_register && !accounts.exists(username) {
+        match accounts.create(username, password) {
+            Ok(account) => info!(
+                "Registered {} as customer {}",
+                username, account.customer_id
+            ),
+            Err(e) => error!("Failed to register {}: {:?}", username, e),
+        }
+    }
+
+    let account = match accounts.authenticate(username, password) {
+        Some(account) => account,
+        None => {
+            info!("Failed AuthLogin for {}", username);
+            return auth_failure(
+                "INV-200",
+                "Unable to login. Please check your username and password",
+            );
+        }
+    };
+
+    if account.is_banned {
+        info!("Refusing AuthLogin for banned {}", username);
+        return auth_failure("INV-200", "This account has been suspended");
+    }
+
+    match accounts.issue_ticket(account.customer_id) {
+        Ok(ticket) => {
+            info!("Issued login ticket for {}", username);
+            text(format!("Valid=TRUE\nTicket={}\n", ticket))
+        }
+        Err(()) => auth_failure("INV-100", "Unable to login. Please try again later"),
+    }
+}
+
+async fn shard_list(AxumState(web): AxumState<WebState>) -> Response {
+    let config = web.state.config();
+    let body: Vec<String> = config
+        .shards
+        .iter()
+        .map(|shard| Shard::new(shard, &config.listeners).render())
+        .collect();
+    text(body.join("\n"))
+}
+
+async fn certificate(AxumState(web): AxumState<WebState>) -> Response {
+    file(&web.state.config().keys.certificate).await
+}
+
+async fn public_key(AxumState(web): AxumState<WebState>) -> Response {
+    file(&web.state.config().keys.public_key).await
+}
+
+fn auth_failure(code: &str, reason: &str) -> Response {
+    text(format!(
+        "reasoncode={}\nreasontext={}\nreasonurl=https://rusty-motors.com\n",
+        code, reason
+    ))
+}
+
+fn text(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/plain")], body).into_response()
+}
+
+async fn file(path: &Path) -> Response {
+    match tokio::fs::read(path).await {
+        Ok(contents) => ([(header::CONTENT_TYPE, "text/plain")], contents).into_response(),
+        Err(e) => {
+            error!("Failed to read {}: {}", path.display(), e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}